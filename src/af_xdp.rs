@@ -0,0 +1,772 @@
+use super::link;
+use super::packet;
+
+use once_cell::unsync::Lazy;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// AF_XDP SOCKETS
+//
+// This module implements AF_XDP sockets bound to a network interface queue.
+// The UMEM (the memory shared with the kernel) of a socket is a packet pool
+// (see packet::new_pool_at) with one packet per UMEM frame, i.e. packets are
+// received into and transmitted from packet buffers without copying.
+//
+// Packets are steered to the socket by a minimal XDP program that redirects
+// all packets received on the queue (and passes packets received on other
+// queues on to the kernel stack). The sockets on the queues of an interface
+// share a single program (an interface has at most one XDP program).
+//
+//   XdpSocket - AF_XDP socket with UMEM, fill, completion, RX, and TX rings
+//   XdpStats - socket statistics (drops, errors)
+//   XdpMode - how the XDP program is attached (native, generic, or auto)
+//   open(ifname:&str, queue, XdpMode) -> io::Result<XdpSocket> - open and
+//     bind socket
+//   XdpSocket.pool() -> Pool - the packet pool backing the UMEM
+//   XdpSocket.receive(&mut Vec<PacketBox>, npackets) - receive packets
+//   XdpSocket.transmit(&mut Link) -> u64 - transmit packets from link, return
+//     number of packets dropped
+//   XdpSocket.held_packets() -> usize - packets currently owned by the kernel
+//   XdpSocket.stats() -> XdpStats - read socket statistics
+
+// UMEM geometry. Packets have FRAME_SIZE - XDP_PACKET_HEADROOM bytes of
+// payload, their headers are placed in the headroom of each frame.
+const FRAME_SIZE: usize = 4096;
+const NFRAMES: usize = 8192;
+const XDP_PACKET_HEADROOM: usize = 256;
+const BUFFER_SIZE: usize = FRAME_SIZE - XDP_PACKET_HEADROOM;
+const RING_SIZE: u32 = 2048;
+
+// Number of entries of the XSKMAP of an XDP program, i.e. sockets can be bound
+// to queues 0 to XSKMAP_SIZE - 1.
+const XSKMAP_SIZE: u32 = 256;
+
+// Definitions from <linux/if_xdp.h> and <linux/bpf.h> (not provided by the
+// libc crate)
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_STATISTICS: libc::c_int = 7;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+// RX/TX descriptor (struct xdp_desc)
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpStatistics {
+    rx_dropped: u64,
+    rx_invalid_descs: u64,
+    tx_invalid_descs: u64,
+    rx_ring_full: u64,
+    rx_fill_ring_empty_descs: u64,
+    tx_ring_empty_descs: u64,
+}
+
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    regs: u8, // src_reg << 4 | dst_reg
+    off: i16,
+    imm: i32,
+}
+
+#[repr(C)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdateAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+// File descriptor, closed on Drop.
+struct Fd(libc::c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+// Memory mapped ring shared with the kernel. We produce entries on the fill
+// and TX rings, and consume entries on the completion and RX rings.
+struct Ring {
+    map: *mut u8,
+    map_size: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut u8,
+    mask: u32,
+}
+
+impl Ring {
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    // Number of entries we can produce.
+    fn nfree(&self) -> u32 {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let consumer = self.consumer().load(Ordering::Acquire);
+        self.mask + 1 - producer.wrapping_sub(consumer)
+    }
+
+    // Number of entries we can consume.
+    fn navailable(&self) -> u32 {
+        let producer = self.producer().load(Ordering::Acquire);
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        producer.wrapping_sub(consumer)
+    }
+
+    // Return a pointer to the nth entry following the producer (or
+    // consumer) index of entries of type T.
+    fn entry<T>(&self, index: &AtomicU32, n: u32) -> *mut T {
+        let i = index.load(Ordering::Relaxed).wrapping_add(n) & self.mask;
+        unsafe { (self.desc as *mut T).add(i as usize) }
+    }
+
+    fn produce(&self, n: u32) {
+        let producer = self.producer().load(Ordering::Relaxed);
+        self.producer()
+            .store(producer.wrapping_add(n), Ordering::Release);
+    }
+
+    fn consume(&self, n: u32) {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        self.consumer()
+            .store(consumer.wrapping_add(n), Ordering::Release);
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.map_size) };
+    }
+}
+
+// How to attach XDP programs:
+//
+//   Native: in driver mode (the interface driver must support XDP)
+//   Generic: in SKB mode (works with any driver, but slower)
+//   Auto: in driver mode if supported by the driver, in SKB mode otherwise
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XdpMode {
+    Auto,
+    Native,
+    Generic,
+}
+
+// XDP program attached to an interface (in mode), detached on Drop.
+// NB: fields are dropped in order, i.e. the link is closed first.
+struct Program {
+    _link: Fd,
+    _prog: Fd,
+    map: Fd,
+    mode: XdpMode,
+}
+
+pub struct XdpSocket {
+    program: Option<Rc<Program>>,
+    fd: Fd,
+    fill: Ring,
+    comp: Ring,
+    rx: Ring,
+    tx: Ring,
+    umem: *mut u8,
+    pool: packet::Pool,
+    frames: Vec<Option<packet::PacketBox>>, // packets owned by the kernel
+    nheld: usize,                           // number of packets in frames
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XdpStats {
+    pub rxdrops: u64,  // packets dropped (RX ring full, invalid descriptors)
+    pub txerrors: u64, // invalid TX descriptors
+}
+
+// UMEMS: UMEM memory and packet pools by interface queue. The UMEM of a queue
+// is reused when a socket is re-opened (packet pools live forever).
+// NB: like packet pools, UMEMs are per thread.
+thread_local! {
+    static UMEMS: RefCell<HashMap<String, (usize, packet::Pool)>> = RefCell::new(HashMap::new());
+}
+
+fn umem(ifname: &str, queue: u32) -> io::Result<(*mut u8, packet::Pool)> {
+    let name = format!("af_xdp {}/{}", ifname, queue);
+    if let Some((umem, pool)) = UMEMS.with(|umems| umems.borrow().get(&name).copied()) {
+        return Ok((umem as *mut u8, pool));
+    }
+    let umem = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            FRAME_SIZE * NFRAMES,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if umem == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let umem = umem as *mut u8;
+    let pool = unsafe {
+        let data = umem.add(XDP_PACKET_HEADROOM);
+        packet::new_pool_at(&name, BUFFER_SIZE, data, FRAME_SIZE, NFRAMES)
+    };
+    UMEMS.with(|umems| umems.borrow_mut().insert(name, (umem as usize, pool)));
+    Ok((umem, pool))
+}
+
+// PROGRAMS: XDP programs by interface index. Sockets on the same interface
+// share its program, which is detached once the last of them is closed.
+// NB: using PROGRAMS is unsafe because it is a mutable static (we have to
+// ensure thread safety).
+static mut PROGRAMS: Lazy<HashMap<u32, Weak<Program>>> = Lazy::new(HashMap::new);
+
+// Return the XDP program of the interface with ifindex, attach it (in mode) if
+// the interface has none yet. Fails if the program is attached in another
+// mode than the one requested.
+fn program(ifindex: u32, mode: XdpMode) -> io::Result<Rc<Program>> {
+    let programs = unsafe { &mut *ptr::addr_of_mut!(PROGRAMS) };
+    if let Some(program) = programs.get(&ifindex).and_then(Weak::upgrade) {
+        if mode != XdpMode::Auto && mode != program.mode {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("XDP program is attached in {:?} mode", program.mode),
+            ));
+        }
+        return Ok(program);
+    }
+    let program = Rc::new(attach(ifindex, mode)?);
+    programs.insert(ifindex, Rc::downgrade(&program));
+    Ok(program)
+}
+
+// Open an AF_XDP socket bound to queue of ifname, and redirect packets
+// received on queue to the socket (via the interface’s XDP program, attached
+// in mode).
+pub fn open(ifname: &str, queue: u32, mode: XdpMode) -> io::Result<XdpSocket> {
+    if queue >= XSKMAP_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let name = CString::new(ifname).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (umem, pool) = umem(ifname, queue)?;
+    let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = Fd(fd);
+    let reg = XdpUmemReg {
+        addr: umem as u64,
+        len: (FRAME_SIZE * NFRAMES) as u64,
+        chunk_size: FRAME_SIZE as u32,
+        headroom: 0,
+        flags: 0,
+        tx_metadata_len: 0,
+    };
+    setsockopt(&fd, XDP_UMEM_REG, &reg)?;
+    for &ring in &[
+        XDP_UMEM_FILL_RING,
+        XDP_UMEM_COMPLETION_RING,
+        XDP_RX_RING,
+        XDP_TX_RING,
+    ] {
+        setsockopt(&fd, ring, &RING_SIZE)?;
+    }
+    let mut offsets: XdpMmapOffsets = Default::default();
+    let mut size = mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.0,
+            SOL_XDP,
+            XDP_MMAP_OFFSETS,
+            &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+            &mut size,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fill = map_ring(&fd, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING, 8)?;
+    let comp = map_ring(&fd, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING, 8)?;
+    let rx = map_ring(&fd, &offsets.rx, XDP_PGOFF_RX_RING, 16)?;
+    let tx = map_ring(&fd, &offsets.tx, XDP_PGOFF_TX_RING, 16)?;
+    let addr = SockaddrXdp {
+        sxdp_family: libc::AF_XDP as u16,
+        sxdp_flags: 0,
+        sxdp_ifindex: ifindex,
+        sxdp_queue_id: queue,
+        sxdp_shared_umem_fd: 0,
+    };
+    let res = unsafe {
+        libc::bind(
+            fd.0,
+            &addr as *const SockaddrXdp as *const libc::sockaddr,
+            mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut socket = XdpSocket {
+        program: None,
+        fd,
+        fill,
+        comp,
+        rx,
+        tx,
+        umem,
+        pool,
+        frames: (0..NFRAMES).map(|_| None).collect(),
+        nheld: 0,
+    };
+    let program = program(ifindex, mode)?;
+    program.insert(queue, &socket.fd)?;
+    socket.program = Some(program);
+    socket.refill();
+    Ok(socket)
+}
+
+fn setsockopt<T>(fd: &Fd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd.0,
+            SOL_XDP,
+            option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Map a ring of RING_SIZE entries of entry_size bytes.
+fn map_ring(
+    fd: &Fd,
+    offsets: &XdpRingOffset,
+    pgoff: libc::off_t,
+    entry_size: usize,
+) -> io::Result<Ring> {
+    let map_size = offsets.desc as usize + RING_SIZE as usize * entry_size;
+    let map = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            map_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.0,
+            pgoff,
+        )
+    };
+    if map == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let map = map as *mut u8;
+    unsafe {
+        Ok(Ring {
+            map,
+            map_size,
+            producer: map.add(offsets.producer as usize) as *const AtomicU32,
+            consumer: map.add(offsets.consumer as usize) as *const AtomicU32,
+            desc: map.add(offsets.desc as usize),
+            mask: RING_SIZE - 1,
+        })
+    }
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_int> {
+    let res = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>()) };
+    match res {
+        res if res < 0 => Err(io::Error::last_os_error()),
+        res => Ok(res as libc::c_int),
+    }
+}
+
+// Load an XDP program that redirects packets received on each queue to the
+// socket in its XSKMAP at the queue’s index (see Program::insert), and attach
+// it to the interface with ifindex.
+fn attach(ifindex: u32, mode: XdpMode) -> io::Result<Program> {
+    let map = Fd(bpf(
+        BPF_MAP_CREATE,
+        &mut BpfMapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries: XSKMAP_SIZE,
+        },
+    )?);
+    let insns = [
+        // r2 = ctx->rx_queue_index
+        BpfInsn {
+            code: 0x61,
+            regs: 1 << 4 | 2,
+            off: 16,
+            imm: 0,
+        },
+        // r1 = map (64-bit immediate, map file descriptor)
+        BpfInsn {
+            code: 0x18,
+            regs: 1 << 4 | 1,
+            off: 0,
+            imm: map.0,
+        },
+        BpfInsn {
+            code: 0,
+            regs: 0,
+            off: 0,
+            imm: 0,
+        },
+        // r3 = XDP_PASS (action if there is no socket for the queue)
+        BpfInsn {
+            code: 0xb7,
+            regs: 3,
+            off: 0,
+            imm: XDP_PASS,
+        },
+        // r0 = bpf_redirect_map(r1, r2, r3)
+        BpfInsn {
+            code: 0x85,
+            regs: 0,
+            off: 0,
+            imm: BPF_FUNC_REDIRECT_MAP,
+        },
+        // return r0
+        BpfInsn {
+            code: 0x95,
+            regs: 0,
+            off: 0,
+            imm: 0,
+        },
+    ];
+    let license = b"Apache-2.0\0";
+    let mut name = [0; 16];
+    name[..7].copy_from_slice(b"rush_af");
+    let prog = Fd(bpf(
+        BPF_PROG_LOAD,
+        &mut BpfProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: 0,
+            prog_flags: 0,
+            prog_name: name,
+            prog_ifindex: 0,
+            expected_attach_type: BPF_XDP,
+        },
+    )?);
+    let link = |flags| {
+        bpf(
+            BPF_LINK_CREATE,
+            &mut BpfLinkCreateAttr {
+                prog_fd: prog.0 as u32,
+                target_ifindex: ifindex,
+                attach_type: BPF_XDP,
+                flags,
+            },
+        )
+        .map(Fd)
+    };
+    let (link, mode) = match mode {
+        XdpMode::Native => (link(XDP_FLAGS_DRV_MODE)?, XdpMode::Native),
+        XdpMode::Generic => (link(XDP_FLAGS_SKB_MODE)?, XdpMode::Generic),
+        XdpMode::Auto => match link(XDP_FLAGS_DRV_MODE) {
+            Ok(link) => (link, XdpMode::Native),
+            Err(_) => (link(XDP_FLAGS_SKB_MODE)?, XdpMode::Generic),
+        },
+    };
+    Ok(Program {
+        _link: link,
+        _prog: prog,
+        map,
+        mode,
+    })
+}
+
+impl Program {
+    // Redirect packets received on queue to socket.
+    fn insert(&self, queue: u32, socket: &Fd) -> io::Result<()> {
+        let value = socket.0 as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &mut BpfMapUpdateAttr {
+                map_fd: self.map.0 as u32,
+                pad: 0,
+                key: &queue as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+impl XdpSocket {
+    pub fn pool(&self) -> packet::Pool {
+        self.pool
+    }
+
+    pub fn held_packets(&self) -> usize {
+        self.nheld
+    }
+
+    // Index of the UMEM frame that holds p.
+    fn frame(&self, p: &packet::Packet) -> usize {
+        (p.data.as_ptr() as usize - self.umem as usize) / FRAME_SIZE
+    }
+
+    // Hand packet to the kernel (it is returned via take).
+    fn hold(&mut self, p: packet::PacketBox) -> usize {
+        let frame = self.frame(&p);
+        self.frames[frame] = Some(p);
+        self.nheld += 1;
+        frame
+    }
+
+    fn take(&mut self, addr: u64) -> packet::PacketBox {
+        self.nheld -= 1;
+        self.frames[addr as usize / FRAME_SIZE].take().unwrap()
+    }
+
+    // Put free packets on the fill ring (as long as the pool has packets).
+    fn refill(&mut self) {
+        let nfree = self.fill.nfree();
+        let mut n = 0;
+        while n < nfree {
+            let p = match packet::try_allocate_from(self.pool) {
+                Some(p) => p,
+                None => break,
+            };
+            let frame = self.hold(p);
+            unsafe {
+                *self.fill.entry::<u64>(self.fill.producer(), n) = (frame * FRAME_SIZE) as u64
+            };
+            n += 1;
+        }
+        self.fill.produce(n);
+    }
+
+    // Receive up to npackets packets into packets, and replenish the fill
+    // ring.
+    pub fn receive(&mut self, packets: &mut Vec<packet::PacketBox>, npackets: usize) {
+        let n = self.rx.navailable().min(npackets as u32);
+        for i in 0..n {
+            let desc = unsafe { &*self.rx.entry::<XdpDesc>(self.rx.consumer(), i) };
+            let mut p = self.take(desc.addr);
+            // NB: data starts at the frame’s headroom, unless the XDP program
+            // moved the start of the packet.
+            let offset = desc.addr as usize % FRAME_SIZE - XDP_PACKET_HEADROOM;
+            let length = desc.len as usize;
+            if offset > 0 {
+                p.data.copy_within(offset..offset + length, 0);
+            }
+            p.length = length as u16;
+            packets.push(p);
+        }
+        self.rx.consume(n);
+        self.refill();
+    }
+
+    // Free packets the kernel has finished transmitting.
+    fn complete(&mut self) {
+        let n = self.comp.navailable();
+        for i in 0..n {
+            let addr = unsafe { *self.comp.entry::<u64>(self.comp.consumer(), i) };
+            drop(self.take(addr));
+        }
+        self.comp.consume(n);
+    }
+
+    // Transmit packets from input. Packets allocated from the UMEM pool are
+    // transmitted as is, other packets are copied to a UMEM packet (and dropped
+    // if they do not fit). Packets are left on input if the TX ring is full or
+    // the UMEM pool is exhausted. Returns the number of packets dropped.
+    pub fn transmit(&mut self, input: &mut link::Link) -> u64 {
+        self.complete();
+        let nfree = self.tx.nfree();
+        let mut n = 0;
+        let mut ndropped = 0;
+        while n < nfree && !link::empty(input) {
+            let front = link::front(input);
+            let p = if front.pool() == self.pool && front.nsegments() == 1 {
+                link::receive(input)
+            } else if front.total_length() > BUFFER_SIZE {
+                link::receive(input);
+                ndropped += 1;
+                continue;
+            } else {
+                let mut q = match packet::try_allocate_from(self.pool) {
+                    Some(q) => q,
+                    None => break,
+                };
+                let p = link::receive(input);
+                for seg in p.segments() {
+                    let start = q.length as usize;
+                    q.data[start..start + seg.length as usize]
+                        .copy_from_slice(&seg.data[..seg.length as usize]);
+                    q.length += seg.length;
+                }
+                q
+            };
+            let length = p.length;
+            let frame = self.hold(p);
+            let desc = unsafe { &mut *self.tx.entry::<XdpDesc>(self.tx.producer(), n) };
+            desc.addr = (frame * FRAME_SIZE + XDP_PACKET_HEADROOM) as u64;
+            desc.len = length as u32;
+            desc.options = 0;
+            n += 1;
+        }
+        if n > 0 {
+            self.tx.produce(n);
+            // NB: errors (e.g., EAGAIN) leave descriptors in the ring, the
+            // kernel picks them up with the next send.
+            unsafe {
+                libc::sendto(
+                    self.fd.0,
+                    ptr::null(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null(),
+                    0,
+                )
+            };
+        }
+        ndropped
+    }
+
+    // Read the socket’s statistics (totals since it was opened).
+    pub fn stats(&self) -> XdpStats {
+        let mut stats: XdpStatistics = Default::default();
+        let mut size = mem::size_of::<XdpStatistics>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                self.fd.0,
+                SOL_XDP,
+                XDP_STATISTICS,
+                &mut stats as *mut XdpStatistics as *mut libc::c_void,
+                &mut size,
+            )
+        };
+        if res < 0 {
+            return Default::default();
+        }
+        XdpStats {
+            rxdrops: stats.rx_dropped + stats.rx_ring_full + stats.rx_invalid_descs,
+            txerrors: stats.tx_invalid_descs,
+        }
+    }
+}
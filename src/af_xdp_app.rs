@@ -0,0 +1,187 @@
+use super::af_xdp;
+use super::engine;
+use super::lib;
+use super::link;
+use super::packet;
+
+use std::cell::RefCell;
+
+// AfXdp app: send and receive packets on a network interface queue via an
+// AF_XDP socket
+//
+//   ifname: name of the interface to bind to
+//   queue: interface queue to bind to
+//   mode: how to attach the XDP program to the interface (see
+//     af_xdp::XdpMode, ignored if another AfXdp app on the interface has
+//     attached it already)
+//
+// Packets received on the queue are sent on output, packets received on
+// input are transmitted on the queue. Packets are received into a packet pool
+// backed by the socket’s UMEM, packets from this pool are transmitted without
+// copying (e.g., when forwarding packets between two AfXdp apps on the same
+// queue), other packets are copied. AfXdp apps on different queues of the
+// same interface share the interface’s XDP program.
+
+#[derive(Clone, Debug)]
+pub struct AfXdp {
+    pub ifname: String,
+    pub queue: u32,
+    pub mode: af_xdp::XdpMode,
+}
+impl engine::AppConfig for AfXdp {
+    fn new(&self) -> Box<dyn engine::App> {
+        let socket = af_xdp::open(&self.ifname, self.queue, self.mode).unwrap_or_else(|error| {
+            panic!(
+                "Failed to open AF_XDP socket on {}/{}: {}",
+                self.ifname, self.queue, error
+            )
+        });
+        Box::new(AfXdpApp {
+            conf: self.clone(),
+            socket: RefCell::new(socket),
+            rx_packets: RefCell::new(Vec::with_capacity(engine::PULL_NPACKETS)),
+            stats: RefCell::new(Default::default()),
+        })
+    }
+}
+pub struct AfXdpApp {
+    conf: AfXdp,
+    socket: RefCell<af_xdp::XdpSocket>,
+    rx_packets: RefCell<Vec<packet::PacketBox>>,
+    stats: RefCell<AppStats>,
+}
+#[derive(Default)]
+struct AppStats {
+    rxpackets: u64,
+    txpackets: u64,
+    txdrops: u64,
+    socket: af_xdp::XdpStats, // socket statistics at last report
+}
+impl engine::App for AfXdpApp {
+    fn has_pull(&self) -> bool {
+        true
+    }
+    fn pull(&self, app: &engine::AppState) {
+        if let Some(output) = app.output.get("output") {
+            let mut output = output.borrow_mut();
+            let mut packets = self.rx_packets.borrow_mut();
+            // leave packets in the RX ring if there is no room downstream
+            let npackets = engine::pull_npackets(&output);
+            // NB: packets may hold packets left over from the previous pull
+            let nheld = packets.len();
+            self.socket.borrow_mut().receive(&mut packets, npackets);
+            self.stats.borrow_mut().rxpackets += (packets.len() - nheld) as u64;
+            link::transmit_batch(&mut output, &mut packets);
+        }
+    }
+    fn has_push(&self) -> bool {
+        true
+    }
+    fn push(&self, app: &engine::AppState) {
+        if let Some(input) = app.input.get("input") {
+            let mut input = input.borrow_mut();
+            let mut stats = self.stats.borrow_mut();
+            let nreadable = link::nreadable(&input);
+            let ndropped = self.socket.borrow_mut().transmit(&mut input);
+            let ntransmitted = nreadable - link::nreadable(&input);
+            stats.txpackets += ntransmitted as u64 - ndropped;
+            stats.txdrops += ndropped;
+        }
+    }
+    fn has_report(&self) -> bool {
+        true
+    }
+    fn report(&self) {
+        let socket = self.socket.borrow().stats();
+        let mut stats = self.stats.borrow_mut();
+        println!(
+            "  AF_XDP stats for {}/{} since last report:",
+            self.conf.ifname, self.conf.queue
+        );
+        println!("     rxpackets:\t{:10}", lib::comma_value(stats.rxpackets));
+        println!(
+            "     rxdrops:\t{:10}",
+            lib::comma_value(socket.rxdrops - stats.socket.rxdrops)
+        );
+        println!("     txpackets:\t{:10}", lib::comma_value(stats.txpackets));
+        println!(
+            "     txdrops:\t{:10}",
+            lib::comma_value(stats.txdrops + socket.txerrors - stats.socket.txerrors)
+        );
+        *stats = AppStats {
+            socket,
+            ..Default::default()
+        };
+    }
+    fn held_packets(&self) -> usize {
+        self.socket.borrow().held_packets()
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::basic_apps;
+    use crate::config;
+
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    #[test]
+    fn af_xdp() {
+        if unsafe { libc::getuid() } != 0 {
+            println!("Skipping test (need to be root)");
+            return;
+        }
+        // NB: network namespaces are per thread, the veth pair only exists in
+        // this test’s namespace.
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            println!("Skipping test (failed to create network namespace)");
+            return;
+        }
+        if !(ip(&[
+            "link", "add", "xdp0", "type", "veth", "peer", "name", "xdp1",
+        ]) && ip(&["link", "set", "xdp0", "up"])
+            && ip(&["link", "set", "xdp1", "up"]))
+        {
+            println!("Skipping test (failed to create veth pair)");
+            return;
+        }
+        let mut c = config::new();
+        config::app(&mut c, "xdp_source", &basic_apps::Source { size: 60 });
+        let xdp0 = AfXdp {
+            ifname: "xdp0".to_string(),
+            queue: 0,
+            mode: af_xdp::XdpMode::Native,
+        };
+        config::app(&mut c, "xdp0", &xdp0);
+        let xdp1 = AfXdp {
+            ifname: "xdp1".to_string(),
+            queue: 0,
+            mode: af_xdp::XdpMode::Native,
+        };
+        config::app(&mut c, "xdp1", &xdp1);
+        config::app(&mut c, "xdp_sink", &basic_apps::Sink {});
+        config::link(&mut c, "xdp_source.output -> xdp0.input");
+        config::link(&mut c, "xdp1.output -> xdp_sink.input");
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            duration: Some(Duration::from_millis(100)),
+            report_links: true,
+            report_apps: true,
+            ..Default::default()
+        }));
+        let rxpackets = engine::state().link_table["xdp1.output -> xdp_sink.input"]
+            .borrow()
+            .txpackets;
+        engine::configure(&config::new());
+        assert!(rxpackets > 0, "No packets received");
+    }
+}
@@ -23,14 +23,19 @@ impl engine::App for SourceApp {
         true
     }
     fn pull(&self, app: &engine::AppState) {
+        let mut packets = Vec::with_capacity(engine::PULL_NPACKETS);
         for output in app.output.values() {
             let mut output = output.borrow_mut();
-            for _ in 0..engine::PULL_NPACKETS {
-                let mut p = packet::allocate();
+            for _ in 0..engine::pull_npackets(&output) {
+                let mut p = match packet::try_allocate() {
+                    Some(p) => p,
+                    None => break,
+                };
                 lib::fill(&mut p.data, self.size as usize, 0);
                 p.length = self.size;
-                link::transmit(&mut output, p);
+                packets.push(p);
             }
+            link::transmit_batch(&mut output, &mut packets);
         }
     }
 }
@@ -51,11 +56,12 @@ impl engine::App for SinkApp {
         true
     }
     fn push(&self, app: &engine::AppState) {
+        let mut packets = Vec::new();
         for input in app.input.values() {
             let mut input = input.borrow_mut();
-            while !link::empty(&input) {
-                packet::free(link::receive(&mut input));
-            }
+            let n = link::nreadable(&input);
+            link::receive_batch(&mut input, &mut packets, n);
+            packets.clear();
         }
     }
 }
@@ -76,20 +82,23 @@ impl engine::App for TeeApp {
         true
     }
     fn push(&self, app: &engine::AppState) {
-        //let noutputs = app.output.len();
+        let mut packets = Vec::new();
+        let mut clones = Vec::new();
         for input in app.input.values() {
             let mut input = input.borrow_mut();
-            while !link::empty(&input) {
-                let p = link::receive(&mut input);
-                //let mut outn = 0;
-                for output in app.output.values() {
-                    let mut output = output.borrow_mut();
-                    //outn += 1;
-                    link::transmit(&mut output, packet::clone(&p));
-                    //if outn == noutputs { packet::clone(&p) } else { p }
-                }
-                packet::free(p);
+            let n = link::nreadable(&input);
+            link::receive_batch(&mut input, &mut packets, n);
+            for output in app.output.values() {
+                let mut output = output.borrow_mut();
+                clones.extend(packets.iter().filter_map(|p| packet::try_clone(p)));
+                // NB: packets that could not be cloned (pool exhausted) or
+                // that are refused by the output are dropped
+                output.txdrop += (packets.len() - clones.len()) as u64;
+                link::transmit_batch(&mut output, &mut clones);
+                output.txdrop += clones.len() as u64;
+                clones.clear();
             }
+            packets.clear();
         }
     }
 }
@@ -7,19 +7,21 @@
 //   new() -> Config - Create a new empty configuration
 //   app(&mut Config, name:&str, &AppConfig) - Add an app to a configuration
 //   link(&mut Config, linkspec:&str) - Add a link to a configuration
+//   parse_link(linkspec:&str) -> LinkSpec - Parse a link specification
 
 use super::engine;
+use super::link;
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 use std::collections::HashMap;
-use std::collections::HashSet;
 
 // Config can be applied by engine.
+// Links are indexed by name, i.e. their specification without options.
 #[derive(Clone)]
 pub struct Config {
     pub apps: HashMap<String, Box<dyn engine::AppArg>>,
-    pub links: HashSet<String>,
+    pub links: HashMap<String, LinkSpec>,
 }
 
 // API: Create a new configuration.
@@ -27,7 +29,7 @@ pub struct Config {
 pub fn new() -> Config {
     Config {
         apps: HashMap::new(),
-        links: HashSet::new(),
+        links: HashMap::new(),
     }
 }
 
@@ -45,34 +47,81 @@ pub fn app(config: &mut Config, name: &str, app: &dyn engine::AppArg) {
 
 // API: Add a link to the configuration.
 //
+// The link specification may be followed by options:
+//   size=<n> - size of the link’s ring buffer (power of two, capacity n-1)
+//   policy=<tail-drop|head-drop|refuse> - what to do with packets transmitted
+//     on a full link (see link::DropPolicy)
+//   histograms=<on|off> - record occupancy and dwell time histograms (see
+//     link::set_histograms, engine::report_links)
+//
 // Example: config::link(&mut c, "nic.tx -> vm.rx")
+// Example: config::link(&mut c, "nic.tx -> vm.rx size=4096 policy=head-drop")
 pub fn link(config: &mut Config, spec: &str) {
-    config.links.insert(canonical_link(spec));
+    let spec = parse_link(spec);
+    config.links.insert(format_link(&spec), spec);
 }
 
-// Given "a.out -> b.in" return
-//   LinkSpec { from: "a", output:"out", to: "b", input: "in" }.
+// Given "a.out -> b.in size=4096" return
+//   LinkSpec { from: "a", output:"out", to: "b", input: "in",
+//              size: 4096, policy: DropPolicy::TailDrop }.
 pub fn parse_link(spec: &str) -> LinkSpec {
     let cap = LINK_SYNTAX
         .captures(spec)
         .expect(&*format!("link parse error: {}", spec));
-    LinkSpec {
+    let mut link = LinkSpec {
         from: (&cap[1]).to_string(),
         output: (&cap[2]).to_string(),
         to: (&cap[3]).to_string(),
         input: (&cap[4]).to_string(),
+        size: link::LINK_RING_SIZE,
+        policy: link::DropPolicy::TailDrop,
+        histograms: false,
+    };
+    for option in cap[5].split_whitespace() {
+        let mut option = option.splitn(2, '=');
+        match (option.next(), option.next()) {
+            (Some("size"), Some(size)) => {
+                link.size = size
+                    .parse()
+                    .ok()
+                    .filter(|size: &usize| *size >= 2 && size.is_power_of_two())
+                    .unwrap_or_else(|| panic!("link size must be a power of two: {}", spec))
+            }
+            (Some("policy"), Some(policy)) => {
+                link.policy = parse_policy(policy)
+                    .unwrap_or_else(|| panic!("link policy parse error: {}", spec))
+            }
+            (Some("histograms"), Some("on")) => link.histograms = true,
+            (Some("histograms"), Some("off")) => link.histograms = false,
+            _ => panic!("link option parse error: {}", spec),
+        }
     }
+    link
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct LinkSpec {
     pub from: String,
     pub output: String,
     pub to: String,
     pub input: String,
+    pub size: usize,
+    pub policy: link::DropPolicy,
+    pub histograms: bool,
 }
 
-static LINK_SYNTAX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r" *([\w_]+)\.([\w_]+) *-> *([\w_]+)\.([\w_]+) *").unwrap());
+static LINK_SYNTAX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^ *([\w_]+)\.([\w_]+) *-> *([\w_]+)\.([\w_]+)((?: +[\w_]+=[\w_-]+)*) *$").unwrap()
+});
+
+fn parse_policy(policy: &str) -> Option<link::DropPolicy> {
+    match policy {
+        "tail-drop" => Some(link::DropPolicy::TailDrop),
+        "head-drop" => Some(link::DropPolicy::HeadDrop),
+        "refuse" => Some(link::DropPolicy::Refuse),
+        _ => None,
+    }
+}
 
 fn format_link(spec: &LinkSpec) -> String {
     format!(
@@ -81,10 +130,6 @@ fn format_link(spec: &LinkSpec) -> String {
     )
 }
 
-fn canonical_link(spec: &str) -> String {
-    format_link(&parse_link(spec))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,4 +144,33 @@ mod tests {
         link(&mut c, "source.output -> sink.input");
         println!("Added an link");
     }
+
+    #[test]
+    fn link_options() {
+        let spec = parse_link(" a.out->b.in  policy=refuse size=4096 ");
+        assert_eq!((spec.size, spec.policy), (4096, link::DropPolicy::Refuse));
+        assert_eq!(format_link(&spec), "a.out -> b.in");
+        assert_eq!(
+            parse_link("a.out -> b.in size=1024 policy=tail-drop"),
+            parse_link("a.out -> b.in")
+        );
+        let spec = parse_link("a.out -> b.in");
+        assert_eq!(spec.size, link::LINK_RING_SIZE);
+        assert_eq!(spec.policy, link::DropPolicy::TailDrop);
+        assert!(!spec.histograms);
+        let spec = parse_link("a.out -> b.in histograms=on size=64");
+        assert_eq!((spec.size, spec.histograms), (64, true));
+        // the same link with other options replaces the link
+        let mut c = new();
+        link(&mut c, "a.out -> b.in size=64");
+        link(&mut c, "a.out -> b.in size=128");
+        assert_eq!(c.links.len(), 1);
+        assert_eq!(c.links["a.out -> b.in"].size, 128);
+    }
+
+    #[test]
+    #[should_panic(expected = "power of two")]
+    fn link_size() {
+        parse_link("a.out -> b.in size=1000");
+    }
 }
@@ -11,20 +11,30 @@
 //   AppState - struct representing an app in the current app network
 //   App, AppConfig - traits that defines an app, and its configuration
 //   PULL_NPACKETS - number of packets to be inhaled in app’s pull() methods
-//   configure(&mut EngineState, &config) - apply configuration to app network
-//   main(&EngineState, Options) - run the engine breathe loop
+//   pull_npackets(&Link) -> usize - number of packets to inhale onto a link
+//   configure(&config) - apply configuration to app network
+//   main(Option<Options>) - run the engine breathe loop
 //   Options - engine breathe loop options
 //   now() -> Instant - return current monotonic engine time
 //   timeout(Duration) -> [()->bool] - make timer returning true after duration
+//   throttle(Duration) -> [()->bool] - make timer returning true once per
+//     duration
 //   report_load() - print load report
 //   report_links() - print link statistics
+//   report_apps() - print app reports
+//   report_packets() - print packet pool usage (and allocations by app)
+//   add_frees(), add_freebytes(u64), add_freebits(u64) - count freed packets
+//   tap(link:&str, Tap) - attach a tap to a link in the app network
+//   untap(link:&str) -> Option<Tap> - detach a tap from a link
 
 use super::config;
+use super::histogram::Histogram;
 use super::lib;
 use super::link;
+use super::packet;
+use super::tap;
 
-use once_cell::unsync::Lazy;
-use std::cell::RefCell;
+use std::cell::{RefCell, UnsafeCell};
 use std::cmp::min;
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -101,6 +111,9 @@ impl Engine {
             if options.report_apps {
                 self.report_apps();
             }
+            if options.report_packets {
+                self.report_packets();
+            }
         }
 
         self.monotonic_now = None;
@@ -194,12 +207,18 @@ impl Engine {
         self.monotonic_now = Some(Instant::now());
         for name in self.state.inhale {
             let app = self.state.app_table.get(&*name).unwrap();
+            packet::set_allocation_tag(Some(&name));
             app.app.pull(&app);
         }
         for name in self.state.exhale {
             let app = self.state.app_table.get(&*name).unwrap();
+            packet::set_allocation_tag(Some(&name));
             app.app.push(&app);
         }
+        packet::set_allocation_tag(None);
+        for link in &self.state.sampled_links {
+            link::sample_occupancy(&mut link.borrow_mut());
+        }
         self.stats.breaths += 1;
     }
 
@@ -235,10 +254,11 @@ impl Engine {
     // Successive calls to configure() will migrate from the old to the
     // new app network by making the changes needed.
     pub fn configure(&mut self, config: &config::Config) {
-        // First determine the links that are going away and remove them.
-        for link in self.state.link_table.clone().keys() {
-            if config.links.get(link).is_none() {
-                self.state.unlink_apps(link)
+        // First determine the links that are going away (or whose options
+        // changed) and remove them.
+        for (name, spec) in self.state.link_specs.clone() {
+            if config.links.get(&name) != Some(&spec) {
+                self.state.unlink_apps(&name)
             }
         }
         // Do the same for apps.
@@ -261,14 +281,43 @@ impl Engine {
             }
         }
         // Rebuild links.
-        for link in config.links.iter() {
-            self.state.link_apps(link);
+        for (name, spec) in config.links.iter() {
+            self.state.link_apps(name, spec);
         }
+        // Collect links whose occupancy is sampled each breath.
+        self.state.sampled_links = self
+            .state
+            .link_table
+            .values()
+            .filter(|link| link::occupancy_histogram(&link.borrow()).is_some())
+            .cloned()
+            .collect();
         // Compute breathe order.
         self.state.compute_breathe_order();
     }
 
-    // Print a link report (packets sent, percent dropped)
+    // Attach a tap to the named link (see link::set_tap), replacing any
+    // previously attached tap. Links are named by their specification without
+    // options.
+    //
+    // Example: engine::tap("nic.tx -> vm.rx", tap::pcap("/tmp/nic.pcap")?)
+    pub fn tap(&self, name: &str, tap: tap::Tap) {
+        let link = self
+            .state
+            .link_table
+            .get(name)
+            .unwrap_or_else(|| panic!("No such link: {}", name));
+        link::set_tap(&mut link.borrow_mut(), Some(tap));
+    }
+
+    // Detach and return the tap attached to the named link (if any).
+    pub fn untap(&self, name: &str) -> Option<tap::Tap> {
+        let link = self.state.link_table.get(name)?;
+        link::set_tap(&mut link.borrow_mut(), None)
+    }
+
+    // Print a link report (packets sent, percent dropped, and histograms of
+    // occupancy and dwell time for links that record them)
     pub fn report_links(&self) {
         println!("Link report:");
         let mut names: Vec<_> = self.state.link_table.keys().collect();
@@ -283,6 +332,73 @@ impl Engine {
                 name,
                 loss_rate(txdrop, txpackets)
             );
+            if let Some(occupancy) = link::occupancy_histogram(&link) {
+                report_histogram("occupancy (packets)", occupancy);
+            }
+            if let Some(dwell) = link::dwell_histogram(&link) {
+                report_histogram("dwell time (ns)", dwell);
+            }
+            if let Some(tap) = link::tap(&link) {
+                println!(
+                    "    tap: {} captured, {} failed",
+                    lib::comma_value(tap.captured()),
+                    lib::comma_value(tap.failed())
+                );
+            }
+        }
+    }
+
+    // Print a packet report
+    //
+    // Lists the packets allocated for each pool, and accounts for the packets
+    // in use: enqueued on links, held by apps (e.g., in driver rings), or
+    // neither (leaked or hoarded). In debug mode (see packet::set_debug) the
+    // packets in use are also listed by the app that allocated them.
+    pub fn report_packets(&self) {
+        println!("Packet report:");
+        let mut in_use = 0;
+        for pool in packet::pools() {
+            let stats = packet::pool_stats(pool);
+            in_use += stats.in_use;
+            println!(
+                "  {} ({} byte buffers): {} allocated, {} in use, {} free \
+                 (high water: {}, growths: {}, failures: {})",
+                stats.name,
+                stats.buffer_size,
+                lib::comma_value(stats.allocated as u64),
+                lib::comma_value(stats.in_use as u64),
+                lib::comma_value(stats.free as u64),
+                lib::comma_value(stats.high_water as u64),
+                stats.growths,
+                lib::comma_value(stats.failures)
+            );
+        }
+        let on_links: usize = self
+            .state
+            .link_table
+            .values()
+            .map(|link| link::nreadable(&link.borrow()))
+            .sum();
+        let held: usize = self
+            .state
+            .app_table
+            .values()
+            .map(|app| app.app.held_packets())
+            .sum();
+        println!(
+            "  {} in use: {} on links, {} held by apps, {} elsewhere",
+            lib::comma_value(in_use as u64),
+            lib::comma_value(on_links as u64),
+            lib::comma_value(held as u64),
+            lib::comma_value(in_use.saturating_sub(on_links + held) as u64)
+        );
+        if packet::debug() {
+            println!("  Packets in use by allocating app:");
+            let mut outstanding: Vec<_> = packet::outstanding_allocations().into_iter().collect();
+            outstanding.sort();
+            for (tag, count) in outstanding {
+                println!("    {}: {}", tag, lib::comma_value(count as u64));
+            }
         }
     }
 
@@ -307,6 +423,88 @@ impl Engine {
     }
 }
 
+// The engine is a singleton (one per thread), the functions below operate on
+// the calling thread’s engine.
+// NB: apps call these functions (e.g., now()) while the engine is running
+// main(), they must not call configure() or main() though.
+thread_local! {
+    static ENGINE: UnsafeCell<Engine> = UnsafeCell::new(Engine::new());
+}
+
+fn engine() -> &'static mut Engine {
+    ENGINE.with(|engine| unsafe { &mut *engine.get() })
+}
+
+pub fn configure(config: &config::Config) {
+    engine().configure(config)
+}
+
+pub fn main(options: Option<Options>) {
+    engine().main(options)
+}
+
+pub fn state() -> &'static EngineState {
+    engine().state()
+}
+
+pub fn stats() -> &'static EngineStats {
+    engine().stats()
+}
+
+pub fn now() -> Instant {
+    engine().now()
+}
+
+pub fn timeout(duration: Duration) -> Box<dyn Fn() -> bool> {
+    engine().timeout(duration)
+}
+
+pub fn throttle(duration: Duration) -> Box<dyn FnMut() -> bool> {
+    engine().throttle(duration)
+}
+
+pub fn tap(name: &str, tap: tap::Tap) {
+    engine().tap(name, tap)
+}
+
+pub fn untap(name: &str) -> Option<tap::Tap> {
+    engine().untap(name)
+}
+
+pub fn report_load() {
+    engine().report_load()
+}
+
+pub fn report_links() {
+    engine().report_links()
+}
+
+pub fn report_apps() {
+    engine().report_apps()
+}
+
+pub fn report_packets() {
+    engine().report_packets()
+}
+
+// NB: packets are also freed while the thread’s engine is dropped (e.g., the
+// packets on its links), those are not counted.
+fn with_stats(f: impl FnOnce(&mut Engine)) {
+    let _ = ENGINE.try_with(|engine| f(unsafe { &mut *engine.get() }));
+}
+
+pub fn add_frees() {
+    with_stats(|engine| engine.add_frees())
+}
+
+pub fn add_freebytes(bytes: u64) {
+    with_stats(|engine| engine.add_freebytes(bytes))
+}
+
+pub fn add_freebits(bits: u64) {
+    with_stats(|engine| engine.add_freebits(bits))
+}
+
 // Counters for global engine statistics.
 #[derive(Default)]
 pub struct EngineStats {
@@ -322,14 +520,16 @@ impl EngineStats {
     }
 }
 
-// Global engine state; singleton obtained via engine::init()
+// Global engine state; singleton obtained via engine::state()
 //
 // The set of all active apps and links in the system, indexed by name.
 pub struct EngineState {
     pub link_table: HashMap<String, SharedLink>,
+    link_specs: HashMap<String, config::LinkSpec>,
     pub app_table: HashMap<String, AppState>,
     pub inhale: Vec<String>,
     pub exhale: Vec<String>,
+    pub sampled_links: Vec<SharedLink>,
 }
 
 impl EngineState {
@@ -337,15 +537,17 @@ impl EngineState {
         EngineState {
             app_table: HashMap::new(),
             link_table: HashMap::new(),
+            link_specs: HashMap::new(),
             inhale: Vec::new(),
             exhale: Vec::new(),
+            sampled_links: Vec::new(),
         }
     }
 
     // Remove link between two apps.
-    fn unlink_apps(&mut self, spec: &str) {
-        self.link_table.remove(spec);
-        let spec = config::parse_link(spec);
+    fn unlink_apps(&mut self, name: &str) {
+        self.link_table.remove(name);
+        let spec = self.link_specs.remove(name).unwrap();
         self.app_table
             .get_mut(&spec.from)
             .unwrap()
@@ -361,10 +563,13 @@ impl EngineState {
     // Insert new app instance into network.
     fn start_app(&mut self, name: &str, conf: &dyn AppArg) {
         let conf = conf.box_clone();
+        packet::set_allocation_tag(Some(name));
+        let app = conf.new();
+        packet::set_allocation_tag(None);
         self.app_table.insert(
             name.to_string(),
             AppState {
-                app: conf.new(),
+                app,
                 conf,
                 input: HashMap::new(),
                 output: HashMap::new(),
@@ -381,22 +586,22 @@ impl EngineState {
     }
 
     // Link two apps in the network.
-    fn link_apps(&mut self, spec: &str) {
+    fn link_apps(&mut self, name: &str, spec: &config::LinkSpec) {
         let link = self
             .link_table
-            .entry(spec.to_string())
-            .or_insert_with(new_shared_link);
-        let spec = config::parse_link(spec);
+            .entry(name.to_string())
+            .or_insert_with(|| new_shared_link(spec));
         self.app_table
             .get_mut(&spec.from)
             .unwrap()
             .output
-            .insert(spec.output, link.clone());
+            .insert(spec.output.clone(), link.clone());
         self.app_table
             .get_mut(&spec.to)
             .unwrap()
             .input
-            .insert(spec.input, link.clone());
+            .insert(spec.input.clone(), link.clone());
+        self.link_specs.insert(name.to_string(), spec.clone());
     }
 
     // Compute engine breathe order
@@ -412,12 +617,11 @@ impl EngineState {
         self.exhale.clear();
         // Build map of successors
         let mut successors: HashMap<String, HashSet<String>> = HashMap::new();
-        for link in self.link_table.keys() {
-            let spec = config::parse_link(&link);
+        for spec in self.link_specs.values() {
             successors
-                .entry(spec.from)
+                .entry(spec.from.clone())
                 .or_insert_with(HashSet::new)
-                .insert(spec.to);
+                .insert(spec.to.clone());
         }
         // Put pull apps in inhalers
         for (name, app) in self.app_table.iter() {
@@ -511,6 +715,8 @@ pub struct AppState {
 //   push: exhale packets out the the app network (move them from input links
 //         to output links, or peripheral device queues)
 //   stop: stop the app (deinitialize)
+//   held_packets: number of packets held by the app outside of links (e.g., in
+//                 device rings), used for packet accounting
 pub trait App {
     fn has_pull(&self) -> bool {
         false
@@ -536,10 +742,22 @@ pub trait App {
     fn stop(&self) {
         unimplemented!();
     }
+    fn held_packets(&self) -> usize {
+        0
+    }
 }
 // Recommended number of packets to inhale in pull()
+// NB: based on the default link capacity (see link::LINK_RING_SIZE).
 pub const PULL_NPACKETS: usize = link::LINK_MAX_PACKETS / 10;
 
+// Number of packets to inhale onto output in pull(): PULL_NPACKETS, or less if
+// the link can not carry that many packets (backpressure).
+// NB: apps that inhale packets from a finite buffer (e.g., a NIC’s receive
+// ring) can leave packets in the buffer until there is room downstream.
+pub fn pull_npackets(output: &link::Link) -> usize {
+    min(PULL_NPACKETS, link::nwritable(output))
+}
+
 // Constructor trait/callback for app instance specifications
 //
 //   new: initialize and return app (resulting app must implement App trait)
@@ -586,9 +804,13 @@ impl Clone for Box<dyn AppArg> {
     }
 }
 
-// Allocate a fresh shared link.
-fn new_shared_link() -> SharedLink {
-    Rc::new(RefCell::new(link::new()))
+// Allocate a fresh shared link (as specified by spec).
+fn new_shared_link(spec: &config::LinkSpec) -> SharedLink {
+    let mut link = link::with_options(spec.size, spec.policy);
+    if spec.histograms {
+        link::set_histograms(&mut link, true);
+    }
+    Rc::new(RefCell::new(link))
 }
 
 // Engine breathe loop Options
@@ -599,6 +821,7 @@ fn new_shared_link() -> SharedLink {
 //  report_load: print a load report upon return
 //  report_links: print summarized statistics for each link upon return
 //  report_apps: print app defined report for each app
+//  report_packets: print packet pool usage
 #[derive(Default)]
 pub struct Options {
     pub done: Option<Box<dyn Fn() -> bool>>,
@@ -607,6 +830,28 @@ pub struct Options {
     pub report_load: bool,
     pub report_links: bool,
     pub report_apps: bool,
+    pub report_packets: bool,
+}
+
+// Print summary and non-empty buckets of a histogram (for report_links)
+fn report_histogram(name: &str, histogram: &Histogram) {
+    println!(
+        "    {}: {} samples, mean {:.1}, p50 {}, p99 {}, max {}",
+        name,
+        lib::comma_value(histogram.count()),
+        histogram.mean(),
+        lib::comma_value(histogram.percentile(50.0)),
+        lib::comma_value(histogram.percentile(99.0)),
+        lib::comma_value(histogram.max())
+    );
+    for (lo, hi, count) in histogram.buckets() {
+        println!(
+            "      {:>12} - {:<12} {:>12}",
+            lib::comma_value(lo),
+            lib::comma_value(hi),
+            lib::comma_value(count)
+        );
+    }
 }
 
 fn loss_rate(drop: u64, sent: u64) -> u64 {
@@ -645,6 +890,7 @@ mod tests {
             done: Some(Box::new(|| true)),
             report_load: true,
             report_links: true,
+            report_packets: true,
             ..Default::default()
         }));
         let stats = stats();
@@ -654,6 +900,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn backpressure() {
+        let mut c = config::new();
+        config::app(&mut c, "source", &basic_apps::Source { size: 60 });
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> sink.input size=64 histograms=on");
+        configure(&c);
+        // links are named without their options
+        tap("source.output -> sink.input", tap::ring(8));
+        main(Some(Options {
+            done: Some(Box::new(|| true)),
+            report_links: true,
+            ..Default::default()
+        }));
+        let tap = untap("source.output -> sink.input").unwrap();
+        let link = state()
+            .link_table
+            .get("source.output -> sink.input")
+            .unwrap()
+            .borrow();
+        assert_eq!(tap.captured(), link.txpackets);
+        assert!(link.txpackets > 0);
+        assert_eq!(link.txdrop, 0, "Source did not respect backpressure");
+        assert!(link::occupancy_histogram(&link).unwrap().count() > 0);
+        assert!(link::dwell_histogram(&link).unwrap().count() > 0);
+    }
+
+    #[test]
+    fn drain() {
+        // sinks drain links larger than the default size in a single breath
+        let mut c = config::new();
+        config::app(&mut c, "source", &basic_apps::Source { size: 60 });
+        config::app(&mut c, "sink", &basic_apps::Sink {});
+        config::link(&mut c, "source.output -> sink.input size=4096");
+        configure(&c);
+        let mut packets = (0..2000).map(|_| packet::allocate()).collect();
+        let link = &state().link_table["source.output -> sink.input"];
+        link::transmit_batch(&mut link.borrow_mut(), &mut packets);
+        main(Some(Options {
+            done: Some(Box::new(|| true)),
+            no_report: true,
+            ..Default::default()
+        }));
+        assert!(link::empty(&link.borrow()));
+        configure(&config::new());
+    }
+
     #[test]
     fn breathe_order() {
         println!("Case 1:");
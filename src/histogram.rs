@@ -0,0 +1,127 @@
+// HISTOGRAMS
+//
+// This module implements histograms with logarithmic (power of two) buckets,
+// e.g. for link occupancy and packet dwell time (see link::set_histograms).
+//
+//   Histogram - histogram of u64 values
+//   new() -> Histogram - create an empty histogram
+//   Histogram.add(u64) - record a value
+//   Histogram.count() -> u64 - number of values recorded
+//   Histogram.mean() -> f64 - mean of recorded values
+//   Histogram.max() -> u64 - maximum recorded value
+//   Histogram.percentile(f64) -> u64 - upper bound of the given percentile
+//   Histogram.buckets() -> Iterator<(lo, hi, count)> - non-empty buckets
+
+// Bucket 0 holds zero values, bucket i holds values in [2^(i-1), 2^i).
+const NBUCKETS: usize = 65;
+
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: [u64; NBUCKETS],
+    count: u64,
+    sum: u128,
+    max: u64,
+}
+
+pub fn new() -> Histogram {
+    Histogram {
+        buckets: [0; NBUCKETS],
+        count: 0,
+        sum: 0,
+        max: 0,
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    64 - value.leading_zeros() as usize
+}
+
+// Return the bounds [lo, hi] of bucket.
+fn bounds(bucket: usize) -> (u64, u64) {
+    match bucket {
+        0 => (0, 0),
+        64 => (1 << 63, u64::MAX),
+        _ => (1 << (bucket - 1), (1 << bucket) - 1),
+    }
+}
+
+impl Histogram {
+    #[inline(always)]
+    pub fn add(&mut self, value: u64) {
+        self.buckets[bucket(value)] += 1;
+        self.count += 1;
+        self.sum += value as u128;
+        if value > self.max {
+            self.max = value;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.sum as f64 / count as f64,
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // Return the upper bound of the bucket that contains the p-th percentile
+    // (0 < p <= 100) of recorded values.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let rank = (self.count as f64 * p / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && count > 0 {
+                return bounds(bucket).1.min(self.max);
+            }
+        }
+        self.max
+    }
+
+    // Iterate over non-empty buckets as (lo, hi, count), where lo and hi are
+    // the (inclusive) bounds of the bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| {
+                let (lo, hi) = bounds(bucket);
+                (lo, hi, count)
+            })
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut h = new();
+        assert_eq!(h.mean(), 0.0);
+        for value in &[0, 1, 2, 3, 4, 100, 1000] {
+            h.add(*value);
+        }
+        h.add(u64::MAX);
+        assert_eq!(h.count(), 8);
+        assert_eq!(h.max(), u64::MAX);
+        let buckets: Vec<_> = h.buckets().collect();
+        assert_eq!(buckets[0], (0, 0, 1));
+        assert_eq!(buckets[1], (1, 1, 1));
+        assert_eq!(buckets[2], (2, 3, 2));
+        assert_eq!(buckets[3], (4, 7, 1));
+        assert_eq!(buckets[4], (64, 127, 1));
+        assert_eq!(buckets[6], (1 << 63, u64::MAX, 1));
+        assert_eq!(h.percentile(50.0), 3);
+        assert_eq!(h.percentile(75.0), 127);
+        assert_eq!(h.percentile(100.0), u64::MAX);
+    }
+}
@@ -2,45 +2,262 @@ use crate::link;
 use crate::memory;
 use crate::packet;
 
+use std::cell::Cell;
 use std::cmp;
 use std::collections::VecDeque;
 use std::error::Error;
+use std::fs::File;
+use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use super::constants::*;
 // use super::interrupts::*;
 // use super::memory::*;
-// use super::vfio::*;
+use super::vfio::*;
 
-use super::pci::pci_map_resource;
-// use super::vfio::VFIO_PCI_BAR0_REGION_INDEX;
-use super::DeviceStats;
+use super::pci::{bind_driver, pci_map_resource};
+use super::{DeviceStats, STATS_QUEUES};
 // use super::Interrupts;
 use super::IxyDevice;
 use super::MAX_QUEUES;
+use super::{DeviceConfig, FlowFilter, RssConfig, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP};
+use super::{MAX_RSS_QUEUES, RSS_RETA_SIZE};
 
 const DRIVER_NAME: &str = "ixy-ixgbe";
 
-const NUM_RX_QUEUE_ENTRIES: usize = 512;
-const NUM_TX_QUEUE_ENTRIES: usize = 512;
 const TX_CLEAN_BATCH: usize = 32;
 
+// alignment of the descriptor rings (section 7.1.9)
+const RING_ALIGN: usize = 128;
+
+// limits of the ring size (number of descriptors) and MTU
+const MIN_RING_SIZE: usize = 64;
+const MAX_RING_SIZE: usize = 4096;
+const MIN_MTU: u16 = 68;
+const MAX_MTU: u16 = 9710;
+// Ethernet header and FCS, added to the MTU to get the maximum frame size
+const FRAME_OVERHEAD: u32 = 18;
+const DEFAULT_MAX_FRAME_SIZE: u32 = 1518;
+// ethertype of 802.1Q VLAN tags
+const VLAN_ETHERTYPE: u32 = 0x8100;
+// largest VLAN ID
+const MAX_VLAN: u16 = 4095;
+
 fn wrap_ring(index: usize, ring_size: usize) -> usize {
     (index + 1) & (ring_size - 1)
 }
 
-pub struct IxgbeDevice {
-    pci_addr: String,
+/// Access to the registers and DMA memory of an 82599.
+///
+/// Implemented by `Mmio` for real devices and by the simulated 82599 used in tests.
+pub trait Registers: Sized {
+    /// Opens the device at `pci_addr`.
+    fn open(pci_addr: &str) -> Result<Self, Box<dyn Error>>;
+
+    /// Returns the register `reg`.
+    fn get_reg32(&self, reg: u32) -> u32;
+
+    /// Sets the register `reg` to `value`.
+    fn set_reg32(&self, reg: u32, value: u32);
+
+    /// Allocates `bytes` of DMA memory aligned to `align` and returns its virtual address.
+    fn dma_alloc(&self, bytes: usize, align: usize) -> io::Result<*mut u8>;
+
+    /// Frees the DMA memory at `ptr` allocated by `dma_alloc(bytes, align)`.
+    fn dma_free(&self, ptr: *mut u8, bytes: usize, align: usize);
+
+    /// Returns the address the device uses to access the DMA memory at `ptr`.
+    fn virtual_to_physical(&self, ptr: *const u8) -> u64;
+
+    /// Returns true if the device's DMA goes through the IOMMU.
+    fn iommu(&self) -> bool;
+
+    /// Claims the device for exclusive or `shared` use (until dropped) and returns true if this
+    /// is its first user, which has to initialize the device. Other claims wait until the first
+    /// user calls `initialized`.
+    ///
+    /// Fails if the device is in use and the claim is exclusive or the device is in exclusive
+    /// use.
+    fn claim(&mut self, shared: bool) -> Result<bool, Box<dyn Error>>;
+
+    /// Signals that this user has initialized the device (or its queues).
+    fn initialized(&mut self);
+
+    /// Releases the claim on the device (once dropped) and returns true if this is its last
+    /// user, which has to stop the device. The device can not be claimed until then.
+    fn release(&mut self) -> bool;
+}
+
+/// Memory mapped registers of a pci device (via sysfs or VFIO) and hugepage DMA memory.
+///
+/// Claims are implemented with flock(2) locks on the device's sysfs directory (held by all
+/// users) and config file (held during initialization).
+pub struct Mmio {
     addr: *mut u8,
     len: usize,
-    num_rx_queues: u16,
-    num_tx_queues: u16,
-    rx_queues: Vec<IxgbeRxQueue>,
-    tx_queues: Vec<IxgbeTxQueue>,
+    // device is accessed via VFIO, DMA goes through the IOMMU
+    iommu: bool,
+    // VFIO device file descriptor
+    vfio_fd: Option<RawFd>,
+    pci_addr: String,
+    claim: Option<(File, File)>,
+}
+
+impl Registers for Mmio {
+    /// Maps the registers of the device at `pci_addr`, via VFIO if the device is bound to
+    /// vfio-pci.
+    fn open(pci_addr: &str) -> Result<Mmio, Box<dyn Error>> {
+        let iommu = vfio_available(pci_addr);
+        if !iommu && unsafe { libc::getuid() } != 0 {
+            println!("not running as root, this will probably fail");
+        }
+        let (addr, len, vfio_fd) = if iommu {
+            let device_fd = vfio_init(pci_addr)?;
+            let (addr, len) = vfio_map_region(device_fd, VFIO_PCI_BAR0_REGION_INDEX)?;
+            (addr, len, Some(device_fd))
+        } else {
+            let (addr, len) = pci_map_resource(pci_addr)?;
+            (addr, len, None)
+        };
+        Ok(Mmio {
+            addr,
+            len,
+            iommu,
+            vfio_fd,
+            pci_addr: pci_addr.to_string(),
+            claim: None,
+        })
+    }
+
+    /// Returns the register at `self.addr` + `reg`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    fn get_reg32(&self, reg: u32) -> u32 {
+        assert!(
+            reg as usize <= self.len - 4 as usize,
+            "memory access out of bounds"
+        );
+
+        unsafe { ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }
+    }
+
+    /// Sets the register at `self.addr` + `reg` to `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    fn set_reg32(&self, reg: u32, value: u32) {
+        assert!(
+            reg as usize <= self.len - 4 as usize,
+            "memory access out of bounds"
+        );
+
+        unsafe {
+            ptr::write_volatile((self.addr as usize + reg as usize) as *mut u32, value);
+        }
+    }
+
+    fn dma_alloc(&self, bytes: usize, align: usize) -> io::Result<*mut u8> {
+        memory::try_dma_alloc(bytes, align)
+    }
+
+    fn dma_free(&self, ptr: *mut u8, bytes: usize, align: usize) {
+        memory::dma_free(ptr, bytes, align)
+    }
+
+    fn virtual_to_physical(&self, ptr: *const u8) -> u64 {
+        memory::virtual_to_physical(ptr)
+    }
+
+    fn iommu(&self) -> bool {
+        self.iommu
+    }
+
+    fn claim(&mut self, shared: bool) -> Result<bool, Box<dyn Error>> {
+        let path = format!("/sys/bus/pci/devices/{}", self.pci_addr);
+        let users = File::open(&path)?;
+        let init = File::open(format!("{}/config", path))?;
+        // NB: locks are released when the files are closed (e.g., if we fail)
+        flock(&init, libc::LOCK_EX)?;
+        let first = match flock(&users, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e.into()),
+        };
+        if !first && !shared {
+            return Err(format!("device {} is in use", self.pci_addr).into());
+        }
+        if shared && flock(&users, libc::LOCK_SH | libc::LOCK_NB).is_err() {
+            return Err(format!("device {} is in exclusive use", self.pci_addr).into());
+        }
+        self.claim = Some((users, init));
+        Ok(first)
+    }
+
+    fn initialized(&mut self) {
+        if let Some((_, ref init)) = self.claim {
+            flock(init, libc::LOCK_UN).expect("failed to unlock device");
+        }
+    }
+
+    fn release(&mut self) -> bool {
+        match self.claim {
+            // we are the last user if no one else holds a lock on the device directory
+            Some((ref users, ref init)) => {
+                flock(init, libc::LOCK_EX).expect("failed to lock device");
+                flock(users, libc::LOCK_EX | libc::LOCK_NB).is_ok()
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Mmio {
+    /// Unmaps the registers, claims are released when their files are closed.
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+            if let Some(fd) = self.vfio_fd {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub struct IxgbeDevice<R: Registers = Mmio> {
+    pci_addr: String,
+    regs: R,
+    // queues used by this device (indexed by queue id)
+    rx_queues: Vec<Option<IxgbeRxQueue>>,
+    tx_queues: Vec<Option<IxgbeTxQueue>>,
+    // device is shared with users of other queues, stats are per queue
+    shared: bool,
+    // device was released, it can not be used anymore
+    released: bool,
+    // number of descriptors of each queue
+    ring_size: usize,
+    // VLAN offloads of the queues (see `DeviceConfig`)
+    vlan_strip: bool,
+    vlan_insert: bool,
+    // link was up when the stats were last read (see `read_link_flaps`)
+    link_up: Cell<bool>,
+    // packets dropped by `tx_batch` since the stats were last read
+    tx_dropped: Cell<u64>,
+    // received packets, transmitted to the output link in one batch
+    rx_packets: Vec<packet::PacketBox>,
 }
 
 struct IxgbeRxQueue {
@@ -48,6 +265,10 @@ struct IxgbeRxQueue {
     num_descriptors: usize,
     bufs_in_use: Vec<*mut packet::Packet>,
     rx_index: usize,
+    // segments received so far of a packet spanning multiple descriptors
+    pending: Option<packet::PacketBox>,
+    // VLAN tags are stripped into the packet metadata
+    vlan_strip: bool,
 }
 
 struct IxgbeTxQueue {
@@ -56,55 +277,52 @@ struct IxgbeTxQueue {
     bufs_in_use: VecDeque<*mut packet::Packet>,
     clean_index: usize,
     tx_index: usize,
+    // VLAN tags are inserted from the packet metadata
+    vlan_insert: bool,
+    // VLAN tag of the last context descriptor written to the queue
+    vlan_context: Option<u16>,
 }
 
-impl IxyDevice for IxgbeDevice {
+impl<R: Registers> IxyDevice for IxgbeDevice<R> {
     /// Returns an initialized `IxgbeDevice` on success.
-    ///
-    /// # Panics
-    /// Panics if `num_rx_queues` or `num_tx_queues` exceeds `MAX_QUEUES`.
     fn init(
         pci_addr: &str,
         num_rx_queues: u16,
         num_tx_queues: u16,
         _interrupt_timeout: i16,
-    ) -> Result<IxgbeDevice, Box<dyn Error>> {
-        if unsafe { libc::getuid() } != 0 {
-            println!("not running as root, this will probably fail");
+    ) -> Result<IxgbeDevice<R>, Box<dyn Error>> {
+        if num_rx_queues > MAX_QUEUES || num_tx_queues > MAX_QUEUES {
+            return Err(format!(
+                "cannot configure {} rx and {} tx queues: limit is {}",
+                num_rx_queues, num_tx_queues, MAX_QUEUES
+            )
+            .into());
         }
-
-        assert!(
-            num_rx_queues <= MAX_QUEUES,
-            "cannot configure {} rx queues: limit is {}",
-            num_rx_queues,
-            MAX_QUEUES
-        );
-        assert!(
-            num_tx_queues <= MAX_QUEUES,
-            "cannot configure {} tx queues: limit is {}",
-            num_tx_queues,
-            MAX_QUEUES
-        );
+        let config = DeviceConfig::default();
 
         // map device registers
-        let (addr, len) = pci_map_resource(pci_addr)?;
-
-        // initialize RX and TX queue
-        let rx_queues = Vec::with_capacity(num_rx_queues as usize);
-        let tx_queues = Vec::with_capacity(num_tx_queues as usize);
+        let mut regs = R::open(pci_addr)?;
+        regs.claim(false)?;
 
         // create the IxyDevice
-        let mut dev = IxgbeDevice {
-            pci_addr: pci_addr.to_string(),
-            addr,
-            len,
-            num_rx_queues,
-            num_tx_queues,
-            rx_queues,
-            tx_queues,
-        };
+        let mut dev = IxgbeDevice::new(pci_addr, regs, false, &config);
 
-        dev.reset_and_init(pci_addr)?;
+        dev.reset_and_init(&config)?;
+
+        for i in 0..num_rx_queues {
+            dev.init_rx_queue(i)?;
+        }
+
+        for i in 0..num_tx_queues {
+            dev.init_tx_queue(i)?;
+        }
+
+        dev.regs.initialized();
+
+        // wait some time for the link to come up
+        if let Some(timeout) = config.wait_for_link {
+            dev.wait_for_link(timeout);
+        }
 
         Ok(dev)
     }
@@ -116,12 +334,16 @@ impl IxyDevice for IxgbeDevice {
 
     /// Returns the card's iommu capability.
     fn is_card_iommu_capable(&self) -> bool {
-        false
+        self.regs.iommu()
     }
 
     /// Returns VFIO container file descriptor or [`None`] if IOMMU is not available.
     fn get_vfio_container(&self) -> Option<RawFd> {
-        None
+        if self.regs.iommu() {
+            get_vfio_container()
+        } else {
+            None
+        }
     }
 
     /// Returns the pci address of this device.
@@ -153,7 +375,7 @@ impl IxyDevice for IxgbeDevice {
         let high: u32 = u32::from(mac[4]) + (u32::from(mac[5]) << 8);
 
         self.set_reg32(IXGBE_RAL(0), low);
-        self.set_reg32(IXGBE_RAH(0), high);
+        self.set_reg32(IXGBE_RAH(0), high | IXGBE_RAH_AV);
     }
 
     /// Pushes up to `num_packets` received `Packet`s onto `buffer`.
@@ -162,16 +384,22 @@ impl IxyDevice for IxgbeDevice {
         let mut last_rx_index;
         let mut received_packets = 0;
 
+        // one timestamp per batch is accurate enough and much cheaper
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
         {
             let queue = self
                 .rx_queues
                 .get_mut(queue_id as usize)
+                .and_then(Option::as_mut)
                 .expect("invalid rx queue id");
 
             rx_index = queue.rx_index;
             last_rx_index = queue.rx_index;
 
-            for i in 0..num_packets {
+            while received_packets < num_packets {
                 let desc = unsafe { queue.descriptors.add(rx_index) as *mut ixgbe_adv_rx_desc };
                 let status =
                     unsafe { ptr::read_volatile(&mut (*desc).wb.upper.status_error as *mut u32) };
@@ -180,128 +408,227 @@ impl IxyDevice for IxgbeDevice {
                     break;
                 }
 
-                assert!(
-                    (status & IXGBE_RXDADV_STAT_EOP) != 0,
-                    "increase buffer size or decrease MTU"
-                );
+                // get a replacement buffer first, if there is none leave the
+                // packet in the ring (the device drops packets once it is full)
+                let np = match packet::try_allocate() {
+                    Some(np) => np,
+                    None => break,
+                };
 
-                // get next packet
-                let mut p = unsafe { Box::from_raw(queue.bufs_in_use[rx_index]) };
+                // get next segment
+                let mut p = unsafe { packet::PacketBox::from_raw(queue.bufs_in_use[rx_index]) };
                 p.length = unsafe { ptr::read_volatile(&(*desc).wb.upper.length as *const u16) };
 
                 // replace currently used buffer with new buffer (packet)
-                let mut np = packet::allocate();
-                queue.bufs_in_use[rx_index] = &mut *np;
-                mem::forget(np);
+                queue.bufs_in_use[rx_index] = packet::PacketBox::into_raw(np);
 
-                link::transmit(output, p);
+                // packets larger than a buffer span multiple descriptors, chain
+                // their segments until the last one (EOP)
+                match queue.pending {
+                    Some(ref mut head) => head.append(p),
+                    None => queue.pending = Some(p),
+                }
+
+                if (status & IXGBE_RXDADV_STAT_EOP) != 0 {
+                    let mut p = queue.pending.take().unwrap();
+                    p.meta.timestamp = timestamp;
+                    p.meta.queue = queue_id as u16;
+                    p.meta.flags = packet::META_TIMESTAMP | packet::META_QUEUE;
+                    // writeback fields are valid in the last descriptor
+                    unsafe {
+                        rx_metadata(&mut p.meta, desc, status, queue.vlan_strip);
+                    }
+
+                    self.rx_packets.push(p);
+                    received_packets += 1;
+                }
 
                 unsafe {
                     ptr::write_volatile(
                         &mut (*desc).read.pkt_addr as *mut u64,
-                        memory::virtual_to_physical((*queue.bufs_in_use[rx_index]).data.as_ptr()),
+                        self.regs
+                            .virtual_to_physical((*queue.bufs_in_use[rx_index]).data.as_ptr()),
                     );
                     ptr::write_volatile(&mut (*desc).read.hdr_addr as *mut u64, 0);
                 }
 
                 last_rx_index = rx_index;
                 rx_index = wrap_ring(rx_index, queue.num_descriptors);
-                received_packets = i + 1;
             }
         }
 
         if rx_index != last_rx_index {
             self.set_reg32(IXGBE_RDT(queue_id), last_rx_index as u32);
-            self.rx_queues[queue_id as usize].rx_index = rx_index;
+            if let Some(queue) = self.rx_queues[queue_id as usize].as_mut() {
+                queue.rx_index = rx_index;
+            }
         }
 
+        link::transmit_batch(output, &mut self.rx_packets);
+
         received_packets
     }
 
     /// Pops as many packets as possible from `packets` to put them into the device`s tx queue.
     fn tx_batch(&mut self, queue_id: u32, input: &mut link::Link) -> usize {
         let mut sent = 0;
+        let tx_index;
 
         {
             let mut queue = self
                 .tx_queues
                 .get_mut(queue_id as usize)
+                .and_then(Option::as_mut)
                 .expect("invalid tx queue id");
 
             let mut cur_index = queue.tx_index;
             let clean_index = clean_tx_queue(&mut queue);
 
             while !link::empty(input) {
-                let next_index = wrap_ring(cur_index, queue.num_descriptors);
-
-                if clean_index == next_index {
+                // each segment of a packet takes up one descriptor, and a VLAN tag different
+                // from that of the last context descriptor takes up a context descriptor
+                let front = link::front(input);
+                let nsegments = front.nsegments();
+                let vlan = if queue.vlan_insert && front.meta.flags & packet::META_VLAN != 0 {
+                    Some(front.meta.vlan)
+                } else {
+                    None
+                };
+                let context = vlan.is_some() && vlan != queue.vlan_context;
+                let free_descriptors = (clean_index + queue.num_descriptors - cur_index - 1)
+                    & (queue.num_descriptors - 1);
+
+                if nsegments + context as usize > queue.num_descriptors - 1 {
+                    // the packet never fits into the tx queue, drop it so that it does not
+                    // block the queue
+                    packet::free(link::receive(input));
+                    self.tx_dropped.set(self.tx_dropped.get() + 1);
+                    continue;
+                }
+                if free_descriptors < nsegments + context as usize {
                     // tx queue of device is full
                     break;
                 }
 
-                let mut p = link::receive(input);
-
-                queue.tx_index = wrap_ring(queue.tx_index, queue.num_descriptors);
-
-                unsafe {
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
-                        memory::virtual_to_physical(p.data.as_ptr()),
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
+                let p = link::receive(input);
+                let length = p.total_length() as u32;
+
+                // section 7.2.3.2.3 - the context descriptor holds the VLAN tag to insert
+                if context {
+                    unsafe {
+                        let desc =
+                            queue.descriptors.add(cur_index) as *mut ixgbe_adv_tx_context_desc;
+                        ptr::write_volatile(
+                            desc,
+                            ixgbe_adv_tx_context_desc {
+                                vlan_macip_lens: u32::from(vlan.unwrap())
+                                    << IXGBE_ADVTXD_VLAN_SHIFT,
+                                seqnum_seed: 0,
+                                type_tucmd_mlhl: IXGBE_ADVTXD_DCMD_DEXT | IXGBE_ADVTXD_DTYP_CTXT,
+                                mss_l4len_idx: 0,
+                            },
+                        );
+                    }
+                    queue.bufs_in_use.push_back(packet::null_mut());
+                    queue.vlan_context = vlan;
+                    cur_index = wrap_ring(cur_index, queue.num_descriptors);
+                }
+                // the data descriptors refer to the context descriptor (index 0)
+                let (vle, cc) = match vlan {
+                    Some(_) => (IXGBE_ADVTXD_DCMD_VLE, IXGBE_ADVTXD_CC),
+                    None => (0, 0),
+                };
+
+                for (i, seg) in p.segments().enumerate() {
+                    // only the last descriptor of a packet is marked EOP
+                    let eop = if i == nsegments - 1 {
                         IXGBE_ADVTXD_DCMD_EOP
-                            | IXGBE_ADVTXD_DCMD_RS
-                            | IXGBE_ADVTXD_DCMD_IFCS
-                            | IXGBE_ADVTXD_DCMD_DEXT
-                            | IXGBE_ADVTXD_DTYP_DATA
-                            | p.length as u32,
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
-                        (p.length as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT,
-                    );
+                    } else {
+                        0
+                    };
+
+                    unsafe {
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
+                            self.regs.virtual_to_physical(seg.data.as_ptr()),
+                        );
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
+                            eop | vle
+                                | IXGBE_ADVTXD_DCMD_RS
+                                | IXGBE_ADVTXD_DCMD_IFCS
+                                | IXGBE_ADVTXD_DCMD_DEXT
+                                | IXGBE_ADVTXD_DTYP_DATA
+                                | seg.length as u32,
+                        );
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
+                            length << IXGBE_ADVTXD_PAYLEN_SHIFT | cc,
+                        );
+                    }
+
+                    // the packet is freed once its last descriptor is cleaned
+                    // (see clean_tx_queue), other descriptors hold no buffer
+                    if i < nsegments - 1 {
+                        queue.bufs_in_use.push_back(packet::null_mut());
+                    }
+
+                    cur_index = wrap_ring(cur_index, queue.num_descriptors);
                 }
 
-                queue.bufs_in_use.push_back(&mut *p);
-                mem::forget(p);
-
-                cur_index = next_index;
+                queue.bufs_in_use.push_back(packet::PacketBox::into_raw(p));
+                queue.tx_index = cur_index;
                 sent += 1;
             }
+
+            tx_index = queue.tx_index;
         }
 
-        self.set_reg32(
-            IXGBE_TDT(queue_id),
-            self.tx_queues[queue_id as usize].tx_index as u32,
-        );
+        self.set_reg32(IXGBE_TDT(queue_id), tx_index as u32);
 
         sent
     }
 
-    /// Reads the stats of this device into `stats`.
+    /// Adds the stats of this device to `stats`.
+    ///
+    /// The statistics registers are cleared on read, so every register is read exactly once
+    /// and accumulated. If the device is shared, the packet and byte counts are those of the
+    /// queues used by this device, and the device-wide counters (errors, broadcast, multicast
+    /// and pause frames, link flaps) are only read by the user of rx queue 0.
     fn read_stats(&self, stats: &mut DeviceStats) {
-        let rx_pkts = u64::from(self.get_reg32(IXGBE_GPRC));
-        let tx_pkts = u64::from(self.get_reg32(IXGBE_GPTC));
-        let rx_bytes =
-            u64::from(self.get_reg32(IXGBE_GORCL)) + (u64::from(self.get_reg32(IXGBE_GORCH)) << 32);
-        let tx_bytes =
-            u64::from(self.get_reg32(IXGBE_GOTCL)) + (u64::from(self.get_reg32(IXGBE_GOTCH)) << 32);
-
-        stats.rx_pkts += rx_pkts;
-        stats.tx_pkts += tx_pkts;
-        stats.rx_bytes += rx_bytes;
-        stats.tx_bytes += tx_bytes;
+        if !self.shared {
+            stats.rx_pkts += u64::from(self.get_reg32(IXGBE_GPRC));
+            stats.tx_pkts += u64::from(self.get_reg32(IXGBE_GPTC));
+            stats.rx_bytes += self.get_reg64(IXGBE_GORCL, IXGBE_GORCH);
+            stats.tx_bytes += self.get_reg64(IXGBE_GOTCL, IXGBE_GOTCH);
+        }
+        if !self.shared || matches!(self.rx_queues.first(), Some(Some(_))) {
+            self.read_device_stats(stats);
+        }
+        self.read_queue_stats(stats);
+        stats.tx_dropped += self.tx_dropped.replace(0);
     }
 
     /// Resets the stats of this device.
     fn reset_stats(&mut self) {
-        self.get_reg32(IXGBE_GPRC);
-        self.get_reg32(IXGBE_GPTC);
-        self.get_reg32(IXGBE_GORCL);
-        self.get_reg32(IXGBE_GORCH);
-        self.get_reg32(IXGBE_GOTCL);
-        self.get_reg32(IXGBE_GOTCH);
+        self.read_stats(&mut Default::default());
+    }
+
+    /// Returns the number of packet buffers held in the rx and tx queues of this device.
+    fn get_bufs_in_use(&self) -> usize {
+        self.rx_queues
+            .iter()
+            .flatten()
+            .map(|queue| {
+                queue.bufs_in_use.len() + queue.pending.as_ref().map_or(0, |p| p.nsegments())
+            })
+            .sum::<usize>()
+            + self
+                .tx_queues
+                .iter()
+                .flatten()
+                .map(|queue| queue.bufs_in_use.len())
+                .sum::<usize>()
     }
 
     /// Returns the link speed of this device.
@@ -317,11 +644,133 @@ impl IxyDevice for IxgbeDevice {
             _ => 0,
         }
     }
+
+    /// Stops this device and returns the packets held in its queues to the freelist (and their
+    /// descriptor rings to the DMA memory allocator), the last user of the device also stops rx
+    /// and tx and binds the device to the kernel `driver`.
+    fn release(&mut self, driver: Option<&str>) -> Result<(), Box<dyn Error>> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+
+        let last = self.regs.release();
+
+        // section 4.6.7.1.2 - disable rx queues
+        let rx_queues = mem::take(&mut self.rx_queues);
+        for (i, queue) in rx_queues.into_iter().enumerate() {
+            if let Some(queue) = queue {
+                let i = i as u32;
+                self.clear_flags32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+                self.wait_clear_reg32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+                // NB: the pending packet (if any) is freed with the queue
+                for p in queue.bufs_in_use {
+                    packet::free(unsafe { packet::PacketBox::from_raw(p) });
+                }
+                self.regs.dma_free(
+                    queue.descriptors as *mut u8,
+                    queue.num_descriptors * mem::size_of::<ixgbe_adv_rx_desc>(),
+                    RING_ALIGN,
+                );
+            }
+        }
+
+        // section 4.6.8.2 - disable tx queues once their packets are sent
+        let tx_queues = mem::take(&mut self.tx_queues);
+        for (i, queue) in tx_queues.into_iter().enumerate() {
+            if let Some(queue) = queue {
+                let i = i as u32;
+                let time = Instant::now();
+                while self.get_reg32(IXGBE_TDH(i)) != self.get_reg32(IXGBE_TDT(i))
+                    && time.elapsed().as_secs() < 1
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                self.clear_flags32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
+                self.wait_clear_reg32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
+                for p in queue.bufs_in_use.into_iter().filter(|p| !p.is_null()) {
+                    packet::free(unsafe { packet::PacketBox::from_raw(p) });
+                }
+                self.regs.dma_free(
+                    queue.descriptors as *mut u8,
+                    queue.num_descriptors * mem::size_of::<ixgbe_adv_tx_desc>(),
+                    RING_ALIGN,
+                );
+            }
+        }
+
+        self.rx_packets.clear();
+
+        if last {
+            self.clear_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+            self.clear_flags32(IXGBE_DMATXCTL, IXGBE_DMATXCTL_TE);
+            if let Some(driver) = driver {
+                if self.regs.iommu() {
+                    return Err("cannot bind a driver to a device used via VFIO".into());
+                }
+                bind_driver(&self.pci_addr, driver)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-impl IxgbeDevice {
-    /// Resets and initializes this device.
-    fn reset_and_init(&mut self, _pci_addr: &str) -> Result<(), Box<dyn Error>> {
+impl<R: Registers> IxgbeDevice<R> {
+    /// Returns an `IxgbeDevice` that uses rx queue `rxq` and tx queue `txq` of the device at
+    /// `pci_addr`, which may be shared with users of other queues (in this or other processes).
+    ///
+    /// The first user resets and initializes the device with the card-wide options of `config`
+    /// (RSS, Flow Director filters, MAC address, promisc mode and MTU), subsequent users only
+    /// initialize their queues.
+    pub fn init_queue(
+        pci_addr: &str,
+        config: &DeviceConfig,
+    ) -> Result<IxgbeDevice<R>, Box<dyn Error>> {
+        check_config(config)?;
+
+        let mut regs = R::open(pci_addr)?;
+        let first = regs.claim(true)?;
+
+        let mut dev = IxgbeDevice::new(pci_addr, regs, true, config);
+
+        if first {
+            dev.reset_and_init(config)?;
+        }
+
+        dev.init_rx_queue(config.rxq)?;
+        dev.init_tx_queue(config.txq)?;
+
+        dev.regs.initialized();
+
+        if let Some(timeout) = config.wait_for_link {
+            dev.wait_for_link(timeout);
+        }
+
+        Ok(dev)
+    }
+
+    /// Returns an `IxgbeDevice` (without queues) that accesses the device via `regs`, its
+    /// queues will be initialized according to `config`.
+    fn new(pci_addr: &str, regs: R, shared: bool, config: &DeviceConfig) -> IxgbeDevice<R> {
+        IxgbeDevice {
+            pci_addr: pci_addr.to_string(),
+            regs,
+            rx_queues: Vec::new(),
+            tx_queues: Vec::new(),
+            shared,
+            released: false,
+            ring_size: config.ring_size,
+            vlan_strip: config.vlan_strip,
+            vlan_insert: config.vlan_insert,
+            link_up: Cell::new(false),
+            tx_dropped: Cell::new(0),
+            rx_packets: Vec::with_capacity(config.ring_size),
+        }
+    }
+
+    /// Resets and initializes this device (without its queues) according to `config`.
+    fn reset_and_init(&mut self, config: &DeviceConfig) -> Result<(), Box<dyn Error>> {
         // info!("resetting device {}", pci_addr);
         // section 4.6.3.1 - disable all interrupts
         self.disable_interrupts();
@@ -356,32 +805,31 @@ impl IxgbeDevice {
         // reset-on-read registers, just read them once
         self.reset_stats();
 
+        if let Some(mac) = config.macaddr {
+            self.set_mac_addr(mac);
+        }
+
         // section 4.6.7 - init rx
-        self.init_rx()?;
+        self.init_rx(config)?;
 
         // section 4.6.8 - init tx
         self.init_tx()?;
 
-        for i in 0..self.num_rx_queues {
-            self.start_rx_queue(i)?;
-        }
+        self.set_promisc(config.promisc);
 
-        for i in 0..self.num_tx_queues {
-            self.start_tx_queue(i)?;
+        for (id, filter) in config.filters.iter().enumerate() {
+            self.add_flow_filter(id as u16, filter)?;
         }
 
-        // enable promisc mode by default to make testing easier
-        self.set_promisc(true);
-
-        // wait some time for the link to come up
-        self.wait_for_link();
-
         Ok(())
     }
 
     // sections 4.6.7
-    /// Initializes the rx queues of this device.
-    fn init_rx(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Initializes the receive path of this device, queues are initialized by `init_rx_queue`.
+    ///
+    /// Flow Director is enabled (for filters with the fields of the first filter) if `config`
+    /// has filters.
+    fn init_rx(&mut self, config: &DeviceConfig) -> Result<(), Box<dyn Error>> {
         // disable rx while re-configuring it
         self.clear_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
 
@@ -398,70 +846,334 @@ impl IxgbeDevice {
         // accept broadcast packets
         self.set_flags32(IXGBE_FCTRL, IXGBE_FCTRL_BAM);
 
-        // configure queues, same for all queues
-        for i in 0..self.num_rx_queues {
-            // debug!("initializing rx queue {}", i);
-            // enable advanced rx descriptors
-            self.set_reg32(
-                IXGBE_SRRCTL(u32::from(i)),
-                (self.get_reg32(IXGBE_SRRCTL(u32::from(i))) & !IXGBE_SRRCTL_DESCTYPE_MASK)
-                    | IXGBE_SRRCTL_DESCTYPE_ADV_ONEBUF,
-            );
-            // let nic drop packets if no rx descriptor is available instead of buffering them
-            self.set_flags32(IXGBE_SRRCTL(u32::from(i)), IXGBE_SRRCTL_DROP_EN);
+        // section 8.2.3.22.13 - maximum frame size, larger frames need jumbo frames enabled
+        let frame_size = u32::from(config.mtu) + FRAME_OVERHEAD;
+        self.set_reg32(IXGBE_MAXFRS, frame_size << IXGBE_MHADD_MFS_SHIFT);
+        if frame_size > DEFAULT_MAX_FRAME_SIZE {
+            self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_JUMBOEN);
+        } else {
+            self.clear_flags32(IXGBE_HLREG0, IXGBE_HLREG0_JUMBOEN);
+        }
 
-            // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                (NUM_RX_QUEUE_ENTRIES) as usize * mem::size_of::<ixgbe_adv_rx_desc>();
+        // section 7.4.4 - only accept tagged packets of the VLANs in the filter table
+        self.init_vlan_filter(&config.vlan_filter);
 
-            let dma_virt = memory::dma_alloc(ring_size_bytes, 128);
-            let dma_phys = memory::virtual_to_physical(dma_virt);
+        // section 7.1.2.8 - distribute packets to queues by hash
+        self.init_rss(&config.rss)?;
 
-            // initialize to 0xff to prevent rogue memory accesses on premature dma activation
-            unsafe {
-                memset(dma_virt as *mut u8, ring_size_bytes, 0xff);
+        // section 7.1.2.7 - steer flows to queues by perfect-match filters
+        if let Some(filter) = config.filters.first() {
+            self.init_fdir(filter)?;
+        }
+
+        // last sentence of section 4.6.7 - set some magic bits
+        self.set_flags32(IXGBE_CTRL_EXT, IXGBE_CTRL_EXT_NS_DIS);
+
+        // start rx, queues are enabled individually (see section 4.6.7.1)
+        self.set_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+
+        Ok(())
+    }
+
+    /// Fills the VLAN filter table with `vlans` and enables filtering, unless `vlans` is empty.
+    fn init_vlan_filter(&self, vlans: &[u16]) {
+        let mut table = [0u32; 128];
+        for &vlan in vlans {
+            table[usize::from(vlan / 32)] |= 1 << (vlan % 32);
+        }
+        for (i, &entry) in table.iter().enumerate() {
+            self.set_reg32(IXGBE_VFTA(i as u32), entry);
+        }
+        let vlnctrl = (self.get_reg32(IXGBE_VLNCTRL) & !(IXGBE_VLNCTRL_VFE | IXGBE_VLNCTRL_VET))
+            | VLAN_ETHERTYPE;
+        if vlans.is_empty() {
+            self.set_reg32(IXGBE_VLNCTRL, vlnctrl);
+        } else {
+            self.set_reg32(IXGBE_VLNCTRL, vlnctrl | IXGBE_VLNCTRL_VFE);
+        }
+    }
+
+    // section 7.1.2.8
+    /// Configures receive side scaling according to `rss`.
+    fn init_rss(&self, rss: &RssConfig) -> Result<(), Box<dyn Error>> {
+        if rss.queues == 0 || rss.queues > MAX_RSS_QUEUES {
+            return Err(format!(
+                "cannot distribute packets to {} rx queues: limit is {}",
+                rss.queues, MAX_RSS_QUEUES
+            )
+            .into());
+        }
+        if !(rss.reta.is_empty() || rss.reta.len() == RSS_RETA_SIZE) {
+            return Err(format!("RSS table must have {} entries", RSS_RETA_SIZE).into());
+        }
+        if let Some(q) = rss.reta.iter().find(|&&q| q >= rss.queues) {
+            return Err(format!("RSS table refers to rx queue {} (of {})", q, rss.queues).into());
+        }
+
+        // the key is stored in little endian order
+        for (i, key) in rss.key.chunks(4).enumerate() {
+            let key = u32::from_le_bytes([key[0], key[1], key[2], key[3]]);
+            self.set_reg32(IXGBE_RSSRK(i as u32), key);
+        }
+
+        // each register holds four 8 bit entries, the queue is in the lower 4 bits
+        for i in 0..RSS_RETA_SIZE / 4 {
+            let mut reta = 0;
+            for j in (0..4).rev() {
+                let entry = i * 4 + j;
+                let queue = match rss.reta.get(entry) {
+                    Some(&queue) => queue,
+                    None => entry as u16 % rss.queues,
+                };
+                reta = reta << 8 | u32::from(queue);
             }
+            self.set_reg32(IXGBE_RETA(i as u32), reta);
+        }
 
+        // the descriptors carry the RSS hash instead of the fragment checksum only if PCSD is set
+        if rss.queues > 1 {
+            self.set_flags32(IXGBE_RXCSUM, IXGBE_RXCSUM_PCSD);
             self.set_reg32(
-                IXGBE_RDBAL(u32::from(i)),
-                (dma_phys as u64 & 0xffff_ffff) as u32,
+                IXGBE_MRQC,
+                IXGBE_MRQC_RSSEN | (rss.fields & IXGBE_MRQC_RSS_FIELD_MASK),
             );
-            self.set_reg32(IXGBE_RDBAH(u32::from(i)), (dma_phys as u64 >> 32) as u32);
-            self.set_reg32(IXGBE_RDLEN(u32::from(i)), ring_size_bytes as u32);
+        } else {
+            self.set_reg32(IXGBE_MRQC, 0);
+            self.clear_flags32(IXGBE_RXCSUM, IXGBE_RXCSUM_PCSD);
+        }
 
-            // debug!("rx ring {} phys addr: {:#x}", i, dma.phys);
-            // debug!("rx ring {} virt addr: {:p}", i, dma.virt);
+        Ok(())
+    }
 
-            // set ring to empty at start
-            self.set_reg32(IXGBE_RDH(u32::from(i)), 0);
-            self.set_reg32(IXGBE_RDT(u32::from(i)), 0);
+    // section 7.1.2.7
+    /// Enables Flow Director perfect-match filters that match the fields of `filter` (see
+    /// `FlowFilter`).
+    fn init_fdir(&self, filter: &FlowFilter) -> Result<(), Box<dyn Error>> {
+        // all filters share the same masks
+        self.set_fdir_masks(fdir_masks(filter)?);
 
-            let rx_queue = IxgbeRxQueue {
-                descriptors: dma_virt as *mut ixgbe_adv_rx_desc,
-                num_descriptors: NUM_RX_QUEUE_ENTRIES,
-                rx_index: 0,
-                bufs_in_use: Vec::with_capacity(NUM_RX_QUEUE_ENTRIES),
-            };
+        self.set_reg32(IXGBE_FDIRHKEY, IXGBE_ATR_BUCKET_HASH_KEY);
+        self.set_reg32(IXGBE_FDIRSKEY, IXGBE_ATR_SIGNATURE_HASH_KEY);
 
-            self.rx_queues.push(rx_queue);
+        // 64 KB of the rx packet buffer hold the filters (8K buckets), packets matched by
+        // dropping filters go to the drop queue
+        self.set_reg32(
+            IXGBE_FDIRCTRL,
+            IXGBE_FDIRCTRL_PBALLOC_64K
+                | IXGBE_FDIRCTRL_PERFECT_MATCH
+                | IXGBE_FDIR_DROP_QUEUE << IXGBE_FDIRCTRL_DROP_Q_SHIFT
+                | 0xa << IXGBE_FDIRCTRL_MAX_LENGTH_SHIFT
+                | 4 << IXGBE_FDIRCTRL_FULL_THRESH_SHIFT,
+        );
+        self.wait_set_reg32(IXGBE_FDIRCTRL, IXGBE_FDIRCTRL_INIT_DONE);
+
+        Ok(())
+    }
+
+    /// Installs the Flow Director filter `filter` with the id `id` (replacing any filter for the
+    /// same flow).
+    ///
+    /// Fails if Flow Director is not enabled or `filter` does not specify the same fields as the
+    /// filters this device was initialized with.
+    pub fn add_flow_filter(&mut self, id: u16, filter: &FlowFilter) -> Result<(), Box<dyn Error>> {
+        if self.get_reg32(IXGBE_FDIRCTRL) & IXGBE_FDIRCTRL_PERFECT_MATCH == 0 {
+            return Err("flow director is not enabled".into());
+        }
+        if fdir_masks(filter)? != self.get_fdir_masks() {
+            return Err("flow filters must all match the same fields".into());
         }
+        let mut cmd = IXGBE_FDIRCMD_CMD_ADD_FLOW
+            | IXGBE_FDIRCMD_FILTER_UPDATE
+            | IXGBE_FDIRCMD_LAST
+            | IXGBE_FDIRCMD_QUEUE_EN
+            | fdir_flow_type(filter)? << IXGBE_FDIRCMD_FLOW_TYPE_SHIFT;
+        cmd |= match filter.queue {
+            Some(queue) if queue < MAX_QUEUES => u32::from(queue) << IXGBE_FDIRCMD_RX_QUEUE_SHIFT,
+            Some(queue) => return Err(format!("invalid rx queue {}", queue).into()),
+            None => IXGBE_FDIRCMD_DROP | IXGBE_FDIR_DROP_QUEUE << IXGBE_FDIRCMD_RX_QUEUE_SHIFT,
+        };
 
-        // last sentence of section 4.6.7 - set some magic bits
-        self.set_flags32(IXGBE_CTRL_EXT, IXGBE_CTRL_EXT_NS_DIS);
+        // IPv6 is not supported
+        for i in 0..3 {
+            self.set_reg32(IXGBE_FDIRSIPv6(i), 0);
+        }
+        self.set_reg32(IXGBE_FDIRIPSA, filter.src_ip.map_or(0, u32::from));
+        self.set_reg32(IXGBE_FDIRIPDA, filter.dst_ip.map_or(0, u32::from));
+        self.set_reg32(
+            IXGBE_FDIRPORT,
+            u32::from(filter.dst_port.unwrap_or(0)) << IXGBE_FDIRPORT_DESTINATION_SHIFT
+                | u32::from(filter.src_port.unwrap_or(0)),
+        );
+        self.set_reg32(IXGBE_FDIRVLAN, u32::from(filter.vlan.unwrap_or(0)));
+        self.set_reg32(
+            IXGBE_FDIRHASH,
+            fdir_bucket_hash(filter)? | u32::from(id) << IXGBE_FDIRHASH_SIG_SW_INDEX_SHIFT,
+        );
+        self.set_reg32(IXGBE_FDIRCMD, cmd);
+        self.wait_clear_reg32(IXGBE_FDIRCMD, IXGBE_FDIRCMD_CMD_MASK);
+
+        Ok(())
+    }
+
+    /// Removes the Flow Director filter `filter` with the id `id`.
+    pub fn remove_flow_filter(
+        &mut self,
+        id: u16,
+        filter: &FlowFilter,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.get_reg32(IXGBE_FDIRCTRL) & IXGBE_FDIRCTRL_PERFECT_MATCH == 0 {
+            return Err("flow director is not enabled".into());
+        }
+        self.set_reg32(
+            IXGBE_FDIRHASH,
+            fdir_bucket_hash(filter)? | u32::from(id) << IXGBE_FDIRHASH_SIG_SW_INDEX_SHIFT,
+        );
+        self.set_reg32(IXGBE_FDIRCMD, IXGBE_FDIRCMD_CMD_REMOVE_FLOW);
+        self.wait_clear_reg32(IXGBE_FDIRCMD, IXGBE_FDIRCMD_CMD_MASK);
+
+        Ok(())
+    }
+
+    /// Sets the Flow Director mask registers (FDIRM, FDIRSIP4M, FDIRDIP4M, FDIRTCPM/UDPM).
+    fn set_fdir_masks(&self, masks: [u32; 4]) {
+        self.set_reg32(IXGBE_FDIRM, masks[0]);
+        self.set_reg32(IXGBE_FDIRSIP4M, masks[1]);
+        self.set_reg32(IXGBE_FDIRDIP4M, masks[2]);
+        self.set_reg32(IXGBE_FDIRTCPM, masks[3]);
+        self.set_reg32(IXGBE_FDIRUDPM, masks[3]);
+    }
+
+    /// Returns the Flow Director mask registers (see `set_fdir_masks`).
+    fn get_fdir_masks(&self) -> [u32; 4] {
+        [
+            self.get_reg32(IXGBE_FDIRM),
+            self.get_reg32(IXGBE_FDIRSIP4M),
+            self.get_reg32(IXGBE_FDIRDIP4M),
+            self.get_reg32(IXGBE_FDIRTCPM),
+        ]
+    }
+
+    // section 4.6.7.1
+    /// Initializes and enables rx queue `queue_id` of this device.
+    fn init_rx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
+        // debug!("initializing rx queue {}", queue_id);
+        let i = u32::from(queue_id);
+
+        // disable the queue while (re-)configuring it, it may have been used before
+        self.clear_flags32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+        self.wait_clear_reg32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+
+        // section 7.4.3 - strip VLAN tags into the descriptors
+        if self.vlan_strip {
+            self.set_flags32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_VME);
+        } else {
+            self.clear_flags32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_VME);
+        }
+
+        // enable advanced rx descriptors
+        self.set_reg32(
+            IXGBE_SRRCTL(i),
+            (self.get_reg32(IXGBE_SRRCTL(i)) & !IXGBE_SRRCTL_DESCTYPE_MASK)
+                | IXGBE_SRRCTL_DESCTYPE_ADV_ONEBUF,
+        );
+        // let nic drop packets if no rx descriptor is available instead of buffering them
+        self.set_flags32(IXGBE_SRRCTL(i), IXGBE_SRRCTL_DROP_EN);
+
+        // section 7.1.9 - setup descriptor ring
+        let ring_size_bytes = self.ring_size * mem::size_of::<ixgbe_adv_rx_desc>();
+
+        let dma_virt = self.regs.dma_alloc(ring_size_bytes, RING_ALIGN)?;
+        let dma_phys = self.regs.virtual_to_physical(dma_virt);
+
+        // initialize to 0xff to prevent rogue memory accesses on premature dma activation
+        unsafe {
+            memset(dma_virt as *mut u8, ring_size_bytes, 0xff);
+        }
+
+        self.set_reg32(IXGBE_RDBAL(i), (dma_phys as u64 & 0xffff_ffff) as u32);
+        self.set_reg32(IXGBE_RDBAH(i), (dma_phys as u64 >> 32) as u32);
+        self.set_reg32(IXGBE_RDLEN(i), ring_size_bytes as u32);
+
+        // debug!("rx ring {} phys addr: {:#x}", i, dma.phys);
+        // debug!("rx ring {} virt addr: {:p}", i, dma.virt);
+
+        // set ring to empty at start
+        self.set_reg32(IXGBE_RDH(i), 0);
+        self.set_reg32(IXGBE_RDT(i), 0);
 
         // probably a broken feature, this flag is initialized with 1 but has to be set to 0
-        for i in 0..self.num_rx_queues {
-            self.clear_flags32(IXGBE_DCA_RXCTRL(u32::from(i)), 1 << 12);
+        self.clear_flags32(IXGBE_DCA_RXCTRL(i), 1 << 12);
+
+        // count the queue's packets in statistics counter i % 16
+        self.map_queue_stats(IXGBE_RQSMR(i / 4), i);
+
+        let mut queue = IxgbeRxQueue {
+            descriptors: dma_virt as *mut ixgbe_adv_rx_desc,
+            num_descriptors: self.ring_size,
+            rx_index: 0,
+            bufs_in_use: Vec::with_capacity(self.ring_size),
+            pending: None,
+            vlan_strip: self.vlan_strip,
+        };
+
+        if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
+            self.regs.dma_free(dma_virt, ring_size_bytes, RING_ALIGN);
+            return Err("number of queue entries must be a power of 2".into());
         }
 
-        // start rx
-        self.set_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+        for i in 0..queue.num_descriptors {
+            let np = match packet::try_allocate() {
+                Some(np) => np,
+                None => {
+                    for p in queue.bufs_in_use {
+                        packet::free(unsafe { packet::PacketBox::from_raw(p) });
+                    }
+                    self.regs.dma_free(dma_virt, ring_size_bytes, RING_ALIGN);
+                    return Err(format!(
+                        "packet pool exhausted filling rx queue {} ({} descriptors)",
+                        queue_id, queue.num_descriptors
+                    )
+                    .into());
+                }
+            };
+
+            unsafe {
+                ptr::write_volatile(
+                    &mut (*queue.descriptors.add(i)).read.pkt_addr as *mut u64,
+                    self.regs.virtual_to_physical(np.data.as_ptr()),
+                );
+
+                ptr::write_volatile(
+                    &mut (*queue.descriptors.add(i)).read.hdr_addr as *mut u64,
+                    0,
+                );
+            }
+
+            // we need to remember which descriptor entry belongs to which mempool entry
+            queue.bufs_in_use.push(packet::PacketBox::into_raw(np));
+        }
+
+        let num_descriptors = queue.num_descriptors;
+        if self.rx_queues.len() <= queue_id as usize {
+            self.rx_queues.resize_with(queue_id as usize + 1, || None);
+        }
+        self.rx_queues[queue_id as usize] = Some(queue);
+
+        // enable queue and wait if necessary
+        self.set_flags32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+        self.wait_set_reg32(IXGBE_RXDCTL(i), IXGBE_RXDCTL_ENABLE);
+
+        // rx queue starts out full
+        self.set_reg32(IXGBE_RDH(i), 0);
+
+        // was set to 0 before
+        self.set_reg32(IXGBE_RDT(i), (num_descriptors - 1) as u32);
 
         Ok(())
     }
 
     // section 4.6.8
-    /// Initializes the tx queues of this device.
+    /// Initializes the transmit path of this device, queues are initialized by `init_tx_queue`.
     fn init_tx(&mut self) -> Result<(), Box<dyn Error>> {
         // crc offload and small packet padding
         self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_TXCRCEN | IXGBE_HLREG0_TXPADEN);
@@ -476,128 +1188,159 @@ impl IxgbeDevice {
         self.set_reg32(IXGBE_DTXMXSZRQ, 0xffff);
         self.clear_flags32(IXGBE_RTTDCS, IXGBE_RTTDCS_ARBDIS);
 
-        // configure queues
-        for i in 0..self.num_tx_queues {
-            // debug!("initializing tx queue {}", i);
-            // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                NUM_TX_QUEUE_ENTRIES as usize * mem::size_of::<ixgbe_adv_tx_desc>();
-
-            let dma_virt = memory::dma_alloc(ring_size_bytes, 128);
-            let dma_phys = memory::virtual_to_physical(dma_virt);
-            unsafe {
-                memset(dma_virt as *mut u8, ring_size_bytes, 0xff);
-            }
-
-            self.set_reg32(
-                IXGBE_TDBAL(u32::from(i)),
-                (dma_phys as u64 & 0xffff_ffff) as u32,
-            );
-            self.set_reg32(IXGBE_TDBAH(u32::from(i)), (dma_phys as u64 >> 32) as u32);
-            self.set_reg32(IXGBE_TDLEN(u32::from(i)), ring_size_bytes as u32);
-
-            // debug!("tx ring {} phys addr: {:#x}", i, dma.phys);
-            // debug!("tx ring {} virt addr: {:p}", i, dma.virt);
-
-            // descriptor writeback magic values, important to get good performance and low PCIe overhead
-            // see 7.2.3.4.1 and 7.2.3.5 for an explanation of these values and how to find good ones
-            // we just use the defaults from DPDK here, but this is a potentially interesting point for optimizations
-            let mut txdctl = self.get_reg32(IXGBE_TXDCTL(u32::from(i)));
-            // there are no defines for this in constants.rs for some reason
-            // pthresh: 6:0, hthresh: 14:8, wthresh: 22:16
-            txdctl &= !(0x3F | (0x3F << 8) | (0x3F << 16));
-            txdctl |= 36 | (8 << 8) | (4 << 16);
-
-            self.set_reg32(IXGBE_TXDCTL(u32::from(i)), txdctl);
-
-            let tx_queue = IxgbeTxQueue {
-                descriptors: dma_virt as *mut ixgbe_adv_tx_desc,
-                bufs_in_use: VecDeque::with_capacity(NUM_TX_QUEUE_ENTRIES),
-                num_descriptors: NUM_TX_QUEUE_ENTRIES,
-                clean_index: 0,
-                tx_index: 0,
-            };
-
-            self.tx_queues.push(tx_queue);
-        }
-
-        // final step: enable DMA
+        // enable DMA, queues are enabled individually
         self.set_reg32(IXGBE_DMATXCTL, IXGBE_DMATXCTL_TE);
 
         Ok(())
     }
 
-    /// Sets the rx queues` descriptors and enables the queues.
-    fn start_rx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
-        // debug!("starting rx queue {}", queue_id);
+    // section 4.6.8.1
+    /// Initializes and enables tx queue `queue_id` of this device.
+    fn init_tx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
+        // debug!("initializing tx queue {}", queue_id);
+        let i = u32::from(queue_id);
 
-        {
-            let queue = &mut self.rx_queues[queue_id as usize];
+        // disable the queue while (re-)configuring it, it may have been used before
+        self.clear_flags32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
+        self.wait_clear_reg32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
 
-            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-                return Err("number of queue entries must be a power of 2".into());
-            }
+        // section 7.1.9 - setup descriptor ring
+        let ring_size_bytes = self.ring_size * mem::size_of::<ixgbe_adv_tx_desc>();
 
-            for i in 0..queue.num_descriptors {
-                let mut np = packet::allocate();
+        let dma_virt = self.regs.dma_alloc(ring_size_bytes, RING_ALIGN)?;
+        let dma_phys = self.regs.virtual_to_physical(dma_virt);
+        unsafe {
+            memset(dma_virt as *mut u8, ring_size_bytes, 0xff);
+        }
 
-                unsafe {
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(i)).read.pkt_addr as *mut u64,
-                        memory::virtual_to_physical(np.data.as_ptr()),
-                    );
+        self.set_reg32(IXGBE_TDBAL(i), (dma_phys as u64 & 0xffff_ffff) as u32);
+        self.set_reg32(IXGBE_TDBAH(i), (dma_phys as u64 >> 32) as u32);
+        self.set_reg32(IXGBE_TDLEN(i), ring_size_bytes as u32);
+
+        // debug!("tx ring {} phys addr: {:#x}", i, dma.phys);
+        // debug!("tx ring {} virt addr: {:p}", i, dma.virt);
+
+        // descriptor writeback magic values, important to get good performance and low PCIe overhead
+        // see 7.2.3.4.1 and 7.2.3.5 for an explanation of these values and how to find good ones
+        // we just use the defaults from DPDK here, but this is a potentially interesting point for optimizations
+        let mut txdctl = self.get_reg32(IXGBE_TXDCTL(i));
+        // there are no defines for this in constants.rs for some reason
+        // pthresh: 6:0, hthresh: 14:8, wthresh: 22:16
+        txdctl &= !(0x3F | (0x3F << 8) | (0x3F << 16));
+        txdctl |= 36 | (8 << 8) | (4 << 16);
+
+        self.set_reg32(IXGBE_TXDCTL(i), txdctl);
+
+        // count the queue's packets in statistics counter i % 16
+        self.map_queue_stats(IXGBE_TQSM(i / 4), i);
+
+        let queue = IxgbeTxQueue {
+            descriptors: dma_virt as *mut ixgbe_adv_tx_desc,
+            bufs_in_use: VecDeque::with_capacity(self.ring_size),
+            num_descriptors: self.ring_size,
+            clean_index: 0,
+            tx_index: 0,
+            vlan_insert: self.vlan_insert,
+            vlan_context: None,
+        };
 
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(i)).read.hdr_addr as *mut u64,
-                        0,
-                    );
-                }
+        if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
+            return Err("number of queue entries must be a power of 2".into());
+        }
 
-                // we need to remember which descriptor entry belongs to which mempool entry
-                queue.bufs_in_use.push(&mut *np);
-                mem::forget(np);
-            }
+        if self.tx_queues.len() <= queue_id as usize {
+            self.tx_queues.resize_with(queue_id as usize + 1, || None);
         }
+        self.tx_queues[queue_id as usize] = Some(queue);
 
-        let queue = &self.rx_queues[queue_id as usize];
+        // tx queue starts out empty
+        self.set_reg32(IXGBE_TDH(i), 0);
+        self.set_reg32(IXGBE_TDT(i), 0);
 
         // enable queue and wait if necessary
-        self.set_flags32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
-        self.wait_set_reg32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
-
-        // rx queue starts out full
-        self.set_reg32(IXGBE_RDH(u32::from(queue_id)), 0);
-
-        // was set to 0 before in the init function
-        self.set_reg32(
-            IXGBE_RDT(u32::from(queue_id)),
-            (queue.num_descriptors - 1) as u32,
-        );
+        self.set_flags32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
+        self.wait_set_reg32(IXGBE_TXDCTL(i), IXGBE_TXDCTL_ENABLE);
 
         Ok(())
     }
 
-    /// Enables the tx queues.
-    fn start_tx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
-        // debug!("starting tx queue {}", queue_id);
+    /// Maps queue `queue_id` to statistics counter `queue_id` % 16 in the mapping register
+    /// `reg` (RQSMR or TQSM, four queues per register).
+    fn map_queue_stats(&self, reg: u32, queue_id: u32) {
+        let shift = (queue_id % 4) * 8;
+        let mapping = self.get_reg32(reg) & !(0xff << shift);
+        self.set_reg32(reg, mapping | (queue_id % 16) << shift);
+    }
 
+    /// Adds the statistics counters of the queues used by this device to `stats` (and to the
+    /// packet and byte counts if the device is shared).
+    fn read_queue_stats(&self, stats: &mut DeviceStats) {
+        for (i, _) in self
+            .rx_queues
+            .iter()
+            .enumerate()
+            .filter(|(_, q)| q.is_some())
         {
-            let queue = &mut self.tx_queues[queue_id as usize];
-
-            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-                return Err("number of queue entries must be a power of 2".into());
+            let i = i % STATS_QUEUES;
+            let reg = i as u32;
+            let pkts = u64::from(self.get_reg32(IXGBE_QPRC(reg)));
+            let bytes = self.get_reg64(IXGBE_QBRC_L(reg), IXGBE_QBRC_H(reg));
+            stats.rx_queue_pkts[i] += pkts;
+            stats.rx_no_dma_resources += u64::from(self.get_reg32(IXGBE_QPRDC(reg)));
+            if self.shared {
+                stats.rx_pkts += pkts;
+                stats.rx_bytes += bytes;
             }
         }
+        for (i, _) in self
+            .tx_queues
+            .iter()
+            .enumerate()
+            .filter(|(_, q)| q.is_some())
+        {
+            let i = i % STATS_QUEUES;
+            let reg = i as u32;
+            let pkts = u64::from(self.get_reg32(IXGBE_QPTC(reg)));
+            let bytes = self.get_reg64(IXGBE_QBTC_L(reg), IXGBE_QBTC_H(reg));
+            stats.tx_queue_pkts[i] += pkts;
+            if self.shared {
+                stats.tx_pkts += pkts;
+                stats.tx_bytes += bytes;
+            }
+        }
+    }
 
-        // tx queue starts out empty
-        self.set_reg32(IXGBE_TDH(u32::from(queue_id)), 0);
-        self.set_reg32(IXGBE_TDT(u32::from(queue_id)), 0);
-
-        // enable queue and wait if necessary
-        self.set_flags32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
-        self.wait_set_reg32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
+    // section 8.2.3.23
+    /// Adds the device-wide error, broadcast, multicast and flow control counters to `stats`
+    /// and counts link flaps.
+    fn read_device_stats(&self, stats: &mut DeviceStats) {
+        // one missed packets counter per packet buffer
+        for i in 0..8 {
+            stats.rx_missed += u64::from(self.get_reg32(IXGBE_MPC(i)));
+        }
+        stats.rx_crc_errors += u64::from(self.get_reg32(IXGBE_CRCERRS));
+        stats.rx_length_errors += u64::from(self.get_reg32(IXGBE_RLEC))
+            + u64::from(self.get_reg32(IXGBE_RUC))
+            + u64::from(self.get_reg32(IXGBE_ROC));
+        stats.rx_broadcast += u64::from(self.get_reg32(IXGBE_BPRC));
+        stats.rx_multicast += u64::from(self.get_reg32(IXGBE_MPRC));
+        stats.tx_broadcast += u64::from(self.get_reg32(IXGBE_BPTC));
+        stats.tx_multicast += u64::from(self.get_reg32(IXGBE_MPTC));
+        stats.rx_pause += u64::from(self.get_reg32(IXGBE_LXONRXCNT))
+            + u64::from(self.get_reg32(IXGBE_LXOFFRXCNT));
+        stats.tx_pause +=
+            u64::from(self.get_reg32(IXGBE_LXONTXC)) + u64::from(self.get_reg32(IXGBE_LXOFFTXC));
+        stats.link_flaps += self.read_link_flaps();
+    }
 
-        Ok(())
+    /// Returns 1 if the link went down since the last call, 0 otherwise.
+    ///
+    /// The 82599 has no link flap counter, flaps are detected by polling the link status when
+    /// the stats are read (flaps between two reads are missed).
+    fn read_link_flaps(&self) -> u64 {
+        let up = self.get_reg32(IXGBE_LINKS) & IXGBE_LINKS_UP != 0;
+        let was_up = self.link_up.replace(up);
+        u64::from(was_up && !up)
     }
 
     // see section 4.6.4
@@ -617,12 +1360,12 @@ impl IxgbeDevice {
         // datasheet wants us to wait for the link here, but we can continue and wait afterwards
     }
 
-    /// Waits for the link to come up.
-    fn wait_for_link(&self) {
+    /// Waits up to `timeout` for the link to come up.
+    fn wait_for_link(&self, timeout: Duration) {
         // info!("waiting for link");
         let time = Instant::now();
         let mut speed = self.get_link_speed();
-        while speed == 0 && time.elapsed().as_secs() < 10 {
+        while speed == 0 && time.elapsed() < timeout {
             thread::sleep(Duration::from_millis(100));
             speed = self.get_link_speed();
         }
@@ -640,47 +1383,34 @@ impl IxgbeDevice {
         }
     }
 
-    /// Returns the register at `self.addr` + `reg`.
-    ///
-    /// # Panics
-    ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Returns the register `reg`.
     fn get_reg32(&self, reg: u32) -> u32 {
-        assert!(
-            reg as usize <= self.len - 4 as usize,
-            "memory access out of bounds"
-        );
+        self.regs.get_reg32(reg)
+    }
 
-        unsafe { ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }
+    /// Returns the 64 bit counter with the low register `low` and the high register `high`
+    /// (the counter is cleared on read of the high register, so it must be read last).
+    fn get_reg64(&self, low: u32, high: u32) -> u64 {
+        let low = u64::from(self.get_reg32(low));
+        low + (u64::from(self.get_reg32(high)) << 32)
     }
 
-    /// Sets the register at `self.addr` + `reg` to `value`.
-    ///
-    /// # Panics
-    ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    /// Sets the register `reg` to `value`.
     fn set_reg32(&self, reg: u32, value: u32) {
-        assert!(
-            reg as usize <= self.len - 4 as usize,
-            "memory access out of bounds"
-        );
-
-        unsafe {
-            ptr::write_volatile((self.addr as usize + reg as usize) as *mut u32, value);
-        }
+        self.regs.set_reg32(reg, value);
     }
 
-    /// Sets the `flags` at `self.addr` + `reg`.
+    /// Sets the `flags` of register `reg`.
     fn set_flags32(&self, reg: u32, flags: u32) {
         self.set_reg32(reg, self.get_reg32(reg) | flags);
     }
 
-    /// Clears the `flags` at `self.addr` + `reg`.
+    /// Clears the `flags` of register `reg`.
     fn clear_flags32(&self, reg: u32, flags: u32) {
         self.set_reg32(reg, self.get_reg32(reg) & !flags);
     }
 
-    /// Waits for `self.addr` + `reg` to clear `value`.
+    /// Waits for register `reg` to clear `value`.
     fn wait_clear_reg32(&self, reg: u32, value: u32) {
         loop {
             let current = self.get_reg32(reg);
@@ -691,7 +1421,7 @@ impl IxgbeDevice {
         }
     }
 
-    /// Waits for `self.addr` + `reg` to set `value`.
+    /// Waits for register `reg` to set `value`.
     fn wait_set_reg32(&self, reg: u32, value: u32) {
         loop {
             let current = self.get_reg32(reg);
@@ -717,6 +1447,12 @@ impl IxgbeDevice {
     }
 }
 
+impl<R: Registers> Drop for IxgbeDevice<R> {
+    fn drop(&mut self) {
+        let _ = self.release(None);
+    }
+}
+
 /// Removes multiples of `TX_CLEAN_BATCH` packets from `queue`.
 fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
     let mut clean_index = queue.clean_index;
@@ -739,13 +1475,12 @@ fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
             cleanup_to -= queue.num_descriptors;
         }
 
-        let status = unsafe {
-            ptr::read_volatile(&(*queue.descriptors.add(cleanup_to)).wb.status as *const u32)
-        };
-
-        if (status & IXGBE_ADVTXD_STAT_DD) != 0 {
+        if unsafe { tx_descriptor_done(queue, cleanup_to) } {
             for _ in 0..cmp::min(TX_CLEAN_BATCH, queue.bufs_in_use.len()) {
-                packet::free(unsafe { Box::from_raw(queue.bufs_in_use.pop_front().unwrap()) });
+                let p = queue.bufs_in_use.pop_front().unwrap();
+                if !p.is_null() {
+                    packet::free(unsafe { packet::PacketBox::from_raw(p) });
+                }
             }
 
             clean_index = wrap_ring(cleanup_to, queue.num_descriptors);
@@ -759,6 +1494,171 @@ fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
     clean_index
 }
 
+/// Returns an error if `config` has queues, a ring size, MTU, MAC address or VLANs the device
+/// does not support.
+fn check_config(config: &DeviceConfig) -> Result<(), Box<dyn Error>> {
+    if config.rxq >= MAX_QUEUES || config.txq >= MAX_QUEUES {
+        return Err(format!(
+            "invalid rx queue {} or tx queue {}: limit is {}",
+            config.rxq,
+            config.txq,
+            MAX_QUEUES - 1
+        )
+        .into());
+    }
+    if !config.ring_size.is_power_of_two()
+        || config.ring_size < MIN_RING_SIZE
+        || config.ring_size > MAX_RING_SIZE
+    {
+        return Err(format!(
+            "invalid ring size {}: must be a power of 2 from {} to {}",
+            config.ring_size, MIN_RING_SIZE, MAX_RING_SIZE
+        )
+        .into());
+    }
+    if config.mtu < MIN_MTU || config.mtu > MAX_MTU {
+        return Err(format!(
+            "invalid mtu {}: must be from {} to {}",
+            config.mtu, MIN_MTU, MAX_MTU
+        )
+        .into());
+    }
+    if let Some(mac) = config.macaddr {
+        if mac[0] & 1 != 0 {
+            return Err("invalid mac address: must not be a multicast address".into());
+        }
+    }
+    if let Some(vlan) = config.vlan_filter.iter().find(|&&vlan| vlan > MAX_VLAN) {
+        return Err(format!("invalid vlan {}: limit is {}", vlan, MAX_VLAN).into());
+    }
+    Ok(())
+}
+
+/// Returns the Flow Director mask registers for the fields of `filter` (see `set_fdir_masks`).
+fn fdir_masks(filter: &FlowFilter) -> Result<[u32; 4], Box<dyn Error>> {
+    // pools, flexible bytes and IPv6 are not supported, VLAN priorities are ignored
+    let mut fdirm = IXGBE_FDIRM_POOL | IXGBE_FDIRM_FLEX | IXGBE_FDIRM_DIPv6 | IXGBE_FDIRM_VLANP;
+    if filter.protocol.is_none() {
+        if filter.src_port.is_some() || filter.dst_port.is_some() {
+            return Err("flow filters on ports must specify the protocol".into());
+        }
+        fdirm |= IXGBE_FDIRM_L4P;
+    }
+    if filter.vlan.is_none() {
+        fdirm |= IXGBE_FDIRM_VLANID;
+    }
+    // the address and port mask registers have bits set for ignored bits (NB: port masks
+    // are bit reversed, which makes no difference for full masks)
+    let ignore = |specified: bool, mask: u32| if specified { !mask } else { !0 };
+    let ports = ignore(filter.src_port.is_some(), 0xffff)
+        & ignore(
+            filter.dst_port.is_some(),
+            0xffff << IXGBE_FDIRTCPM_DPORTM_SHIFT,
+        );
+    Ok([
+        fdirm,
+        ignore(filter.src_ip.is_some(), !0),
+        ignore(filter.dst_ip.is_some(), !0),
+        ports,
+    ])
+}
+
+/// Returns the Flow Director flow type (L4 type) of `filter`.
+fn fdir_flow_type(filter: &FlowFilter) -> Result<u32, Box<dyn Error>> {
+    match filter.protocol {
+        None => Ok(0),
+        Some(IP_PROTOCOL_TCP) => Ok(IXGBE_ATR_L4TYPE_TCP),
+        Some(IP_PROTOCOL_UDP) => Ok(IXGBE_ATR_L4TYPE_UDP),
+        Some(protocol) => Err(format!("unsupported flow filter protocol {}", protocol).into()),
+    }
+}
+
+/// Returns the bucket hash of the Flow Director perfect-match `filter` (computed like the
+/// hash of received packets, see section 7.1.2.7.15).
+fn fdir_bucket_hash(filter: &FlowFilter) -> Result<u32, Box<dyn Error>> {
+    // the hash input is the XOR of the (big endian) dwords of the masked flow fields
+    let flow_vlan = fdir_flow_type(filter)? << 16 | u32::from(filter.vlan.unwrap_or(0));
+    let common = filter.dst_ip.map_or(0, u32::from)
+        ^ filter.src_ip.map_or(0, u32::from)
+        ^ (u32::from(filter.src_port.unwrap_or(0)) << 16 | u32::from(filter.dst_port.unwrap_or(0)));
+
+    let hi = common ^ flow_vlan ^ (flow_vlan >> 16);
+    let mut lo = common.rotate_left(16);
+    let mut hash = 0;
+    for n in 0..16 {
+        if n == 1 {
+            // the flow type and VLAN do not affect bit 0 of the low dword
+            lo ^= flow_vlan ^ (flow_vlan << 16);
+        }
+        if IXGBE_ATR_BUCKET_HASH_KEY & (1 << n) != 0 {
+            hash ^= lo >> n;
+        }
+        if IXGBE_ATR_BUCKET_HASH_KEY & (1 << (n + 16)) != 0 {
+            hash ^= hi >> n;
+        }
+    }
+
+    // 8K buckets
+    Ok(hash & 0x1fff)
+}
+
+/// Returns true if the device is done with descriptor `index` of `queue`.
+///
+/// Context descriptors are not written back, they are done once the data descriptor following
+/// them is.
+unsafe fn tx_descriptor_done(queue: &IxgbeTxQueue, index: usize) -> bool {
+    let mut index = index;
+    loop {
+        let desc = queue.descriptors.add(index);
+        if ptr::read_volatile(&(*desc).wb.status as *const u32) & IXGBE_ADVTXD_STAT_DD != 0 {
+            return true;
+        }
+        let cmd_type_len = ptr::read_volatile(&(*desc).read.cmd_type_len as *const u32);
+        if cmd_type_len & IXGBE_ADVTXD_DTYP_MASK != IXGBE_ADVTXD_DTYP_CTXT {
+            return false;
+        }
+        index = wrap_ring(index, queue.num_descriptors);
+    }
+}
+
+/// Fills `meta` from the writeback fields of the received descriptor `desc`, the VLAN tag is
+/// only valid if it was stripped (`vlan_strip`).
+unsafe fn rx_metadata(
+    meta: &mut packet::Metadata,
+    desc: *const ixgbe_adv_rx_desc,
+    status: u32,
+    vlan_strip: bool,
+) {
+    let pkt_info = u32::from(ptr::read_volatile(
+        &(*desc).wb.lower.lo_dword.hs_rss.pkt_info as *const u16,
+    ));
+    if (pkt_info & IXGBE_RXDADV_RSSTYPE_MASK) != IXGBE_RXDADV_RSSTYPE_NONE {
+        meta.rss_hash = ptr::read_volatile(&(*desc).wb.lower.hi_dword.rss as *const u32);
+        meta.flags |= packet::META_RSS_HASH;
+    }
+
+    if vlan_strip && (status & IXGBE_RXDADV_STAT_VP) != 0 {
+        meta.vlan = ptr::read_volatile(&(*desc).wb.upper.vlan as *const u16);
+        meta.flags |= packet::META_VLAN;
+    }
+
+    if (status & IXGBE_RXD_STAT_IPCS) != 0 {
+        meta.flags |= if (status & IXGBE_RXDADV_ERR_IPE) != 0 {
+            packet::META_IP_CSUM_BAD
+        } else {
+            packet::META_IP_CSUM_OK
+        };
+    }
+
+    if (status & IXGBE_RXD_STAT_L4CS) != 0 {
+        meta.flags |= if (status & IXGBE_RXDADV_ERR_TCPE) != 0 {
+            packet::META_L4_CSUM_BAD
+        } else {
+            packet::META_L4_CSUM_OK
+        };
+    }
+}
+
 // SNIPPED FROM ORIGINAL MEMORY.RS
 
 /// Initializes `len` fields of type `T` at `addr` with `value`.
@@ -767,3 +1667,437 @@ unsafe fn memset<T: Copy>(addr: *mut T, len: usize, value: T) {
         ptr::write_volatile(addr.add(i) as *mut T, value);
     }
 }
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::ixy82599::sim::Sim82599;
+
+    use std::net::Ipv4Addr;
+
+    fn sim_device() -> IxgbeDevice<Sim82599> {
+        IxgbeDevice::init("0000:00:00.0", 1, 1, 0).unwrap()
+    }
+
+    fn transmit(input: &mut link::Link, length: usize, tag: u8) {
+        let mut p = packet::allocate();
+        p.data[0] = tag;
+        p.length = length as u16;
+        assert!(link::transmit(input, p).is_none());
+    }
+
+    // Returns the configuration of a device that uses rx and tx queue `queue`.
+    fn queue_config(queue: u16) -> DeviceConfig {
+        DeviceConfig {
+            rxq: queue,
+            txq: queue,
+            ..Default::default()
+        }
+    }
+
+    // Returns a TCP/IPv4 packet from 10.0.0.1:`src_port` to 10.0.0.2:`dst_port`.
+    fn tcp_packet(src_port: u16, dst_port: u16) -> packet::PacketBox {
+        let mut p = packet::allocate();
+        p.data[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        p.data[14] = 0x45;
+        p.data[23] = IP_PROTOCOL_TCP;
+        p.data[26..34].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        p.data[34..36].copy_from_slice(&src_port.to_be_bytes());
+        p.data[36..38].copy_from_slice(&dst_port.to_be_bytes());
+        p.length = 60;
+        p
+    }
+
+    #[test]
+    fn sim_loopback() {
+        let mut dev = sim_device();
+        assert_eq!(dev.get_link_speed(), 10000);
+        // raise the MTU for the jumbo frame below
+        let jumbo = DeviceConfig {
+            mtu: 3000,
+            ..Default::default()
+        };
+        dev.init_rx(&jumbo).unwrap();
+        let mut input = link::new();
+        let mut output = link::new();
+        for i in 0..100 {
+            transmit(&mut input, 60, i);
+        }
+        // spans two 2 KB rx buffers
+        transmit(&mut input, 3000, 100);
+        assert_eq!(dev.tx_batch(0, &mut input), 101);
+        assert!(link::empty(&input));
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 101);
+        for i in 0..100 {
+            let p = link::receive(&mut output);
+            assert_eq!((p.data[0], p.length, p.nsegments()), (i, 60, 1));
+            assert_eq!(p.meta.flags & packet::META_QUEUE, packet::META_QUEUE);
+            packet::free(p);
+        }
+        let p = link::receive(&mut output);
+        assert_eq!((p.data[0], p.total_length(), p.nsegments()), (100, 3000, 2));
+        packet::free(p);
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 0);
+
+        // counters include the FCS and are cleared on read
+        let mut stats: DeviceStats = Default::default();
+        dev.read_stats(&mut stats);
+        assert_eq!((stats.rx_pkts, stats.tx_pkts), (101, 101));
+        assert_eq!((stats.rx_bytes, stats.tx_bytes), (9404, 9404));
+        dev.read_stats(&mut stats);
+        assert_eq!((stats.rx_pkts, stats.tx_bytes), (101, 9404));
+    }
+
+    #[test]
+    fn sim_clean_tx_queue() {
+        let mut dev = sim_device();
+        let mut input = link::new();
+        let mut output = link::new();
+        for i in 0..100 {
+            transmit(&mut input, 60, i);
+        }
+        dev.tx_batch(0, &mut input);
+        assert_eq!(dev.tx_queues[0].as_ref().unwrap().bufs_in_use.len(), 100);
+        // packets are freed in batches once the device is done with them
+        assert_eq!(clean_tx_queue(dev.tx_queues[0].as_mut().unwrap()), 96);
+        assert_eq!(dev.tx_queues[0].as_ref().unwrap().bufs_in_use.len(), 4);
+        // the rx queue is refilled as packets are received
+        let nrx = dev.get_bufs_in_use() - 4;
+        dev.rx_batch(0, &mut output, 1000);
+        assert_eq!(dev.get_bufs_in_use() - 4, nrx);
+        while !link::empty(&output) {
+            packet::free(link::receive(&mut output));
+        }
+    }
+
+    #[test]
+    fn sim_rss() {
+        let pci = "0000:00:00.1";
+        let rss = RssConfig {
+            queues: 4,
+            ..Default::default()
+        };
+        let mut devs: Vec<IxgbeDevice<Sim82599>> = (0..4)
+            .map(|q| {
+                let config = DeviceConfig {
+                    rss: rss.clone(),
+                    ..queue_config(q)
+                };
+                IxgbeDevice::init_queue(pci, &config).unwrap()
+            })
+            .collect();
+        // the device is shared, it cannot be used exclusively
+        assert!(IxgbeDevice::<Sim82599>::init(pci, 1, 1, 0).is_err());
+
+        // TCP/IPv4 packets with different source ports
+        let mut input = link::new();
+        for port in 0..100 {
+            assert!(link::transmit(&mut input, tcp_packet(1024 + port, 80)).is_none());
+        }
+        assert_eq!(devs[0].tx_batch(0, &mut input), 100);
+
+        // the default indirection table uses the queues in turn
+        let mut received = 0;
+        for (q, dev) in devs.iter_mut().enumerate() {
+            let mut output = link::new();
+            let n = dev.rx_batch(q as u32, &mut output, 1000);
+            assert!(n > 0, "no packets on queue {}", q);
+            received += n;
+            while !link::empty(&output) {
+                let p = link::receive(&mut output);
+                assert_eq!(p.meta.flags & packet::META_RSS_HASH, packet::META_RSS_HASH);
+                assert_eq!((p.meta.rss_hash & 0x7f) as usize % 4, q);
+                packet::free(p);
+            }
+        }
+        assert_eq!(received, 100);
+    }
+
+    #[test]
+    fn sim_flow_director() {
+        let pci = "0000:00:00.2";
+        let filter = |dst_port, queue| FlowFilter {
+            protocol: Some(IP_PROTOCOL_TCP),
+            dst_port: Some(dst_port),
+            queue,
+            ..Default::default()
+        };
+        let filters = [filter(80, Some(1)), filter(22, None)];
+        let config = DeviceConfig {
+            filters: filters.to_vec(),
+            ..queue_config(0)
+        };
+        let mut dev0 = IxgbeDevice::<Sim82599>::init_queue(pci, &config).unwrap();
+        let mut dev1 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(1)).unwrap();
+        // filters share the fields they match
+        let src_filter = FlowFilter {
+            src_ip: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            ..filter(80, Some(1))
+        };
+        assert!(dev0.add_flow_filter(2, &src_filter).is_err());
+
+        let mut input = link::new();
+        for &dst_port in &[80, 22, 443, 80] {
+            assert!(link::transmit(&mut input, tcp_packet(1024, dst_port)).is_none());
+        }
+        dev0.tx_batch(0, &mut input);
+        let mut output = link::new();
+        // port 80 is steered to queue 1, port 22 is dropped
+        assert_eq!(dev1.rx_batch(1, &mut output, 1000), 2);
+        assert_eq!(dev0.rx_batch(0, &mut output, 1000), 1);
+        let ports: Vec<u16> = (0..3)
+            .map(|_| {
+                let p = link::receive(&mut output);
+                let port = u16::from_be_bytes([p.data[36], p.data[37]]);
+                packet::free(p);
+                port
+            })
+            .collect();
+        assert_eq!(ports, [80, 80, 443]);
+
+        // removed filters no longer match
+        dev0.remove_flow_filter(0, &filters[0]).unwrap();
+        assert!(link::transmit(&mut input, tcp_packet(1024, 80)).is_none());
+        dev0.tx_batch(0, &mut input);
+        assert_eq!(dev0.rx_batch(0, &mut output, 1000), 1);
+        packet::free(link::receive(&mut output));
+    }
+
+    #[test]
+    fn sim_release() {
+        let pci = "0000:00:00.3";
+        let mut dev0 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(0)).unwrap();
+        let mut dev1 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(1)).unwrap();
+        let mut input = link::new();
+        for i in 0..10 {
+            transmit(&mut input, 60, i);
+        }
+        dev1.tx_batch(1, &mut input);
+        assert_eq!(
+            dev1.get_bufs_in_use(),
+            DeviceConfig::default().ring_size + 10
+        );
+        let mut output = link::new();
+        assert_eq!(dev0.rx_batch(0, &mut output, 1000), 10);
+        while !link::empty(&output) {
+            packet::free(link::receive(&mut output));
+        }
+
+        // releasing a queue returns its packets and rings, other queues keep running
+        let ndma = dev1.regs.dma_allocations();
+        dev1.release(None).unwrap();
+        assert_eq!(dev1.get_bufs_in_use(), 0);
+        assert_eq!(dev1.regs.dma_allocations(), ndma - 2);
+        assert_eq!(dev1.get_reg32(IXGBE_RXDCTL(1)) & IXGBE_RXDCTL_ENABLE, 0);
+        assert_eq!(dev1.get_reg32(IXGBE_TXDCTL(1)) & IXGBE_TXDCTL_ENABLE, 0);
+        assert_ne!(dev0.get_reg32(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN, 0);
+        drop(dev1);
+
+        // the queue can be used again
+        let mut dev1 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(1)).unwrap();
+        assert_eq!(dev1.regs.dma_allocations(), ndma);
+        transmit(&mut input, 60, 0);
+        assert_eq!(dev1.tx_batch(1, &mut input), 1);
+        assert_eq!(dev0.rx_batch(0, &mut output, 1000), 1);
+        packet::free(link::receive(&mut output));
+        drop(dev1);
+
+        // the last user stops the device
+        dev0.release(None).unwrap();
+        assert_eq!(dev0.get_reg32(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN, 0);
+        assert_eq!(dev0.get_reg32(IXGBE_DMATXCTL) & IXGBE_DMATXCTL_TE, 0);
+    }
+
+    #[test]
+    fn sim_pool_exhausted() {
+        // NB: packet pools are per thread, this only limits this test's pool
+        packet::init(packet::DEFAULT_POOL, 0, 100);
+        let pci = "0000:00:00.7";
+        let result = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(0));
+        assert!(result.is_err(), "rx queue filled from exhausted pool");
+        assert_eq!(packet::pool_stats(packet::DEFAULT_POOL).in_use, 0);
+        let sim = Sim82599::open(pci).unwrap();
+        assert_eq!(sim.dma_allocations(), 0);
+    }
+
+    #[test]
+    fn sim_config() {
+        let pci = "0000:00:00.4";
+        let invalid = [
+            DeviceConfig {
+                rxq: MAX_QUEUES,
+                ..Default::default()
+            },
+            DeviceConfig {
+                ring_size: 100,
+                ..Default::default()
+            },
+            DeviceConfig {
+                mtu: MAX_MTU + 1,
+                ..Default::default()
+            },
+            DeviceConfig {
+                macaddr: Some([1, 0, 0, 0, 0, 1]),
+                ..Default::default()
+            },
+        ];
+        for config in &invalid {
+            assert!(IxgbeDevice::<Sim82599>::init_queue(pci, config).is_err());
+        }
+
+        let config = DeviceConfig {
+            macaddr: Some([2, 0, 0, 0, 0, 1]),
+            promisc: false,
+            ring_size: 64,
+            mtu: 9000,
+            wait_for_link: None,
+            ..Default::default()
+        };
+        let mut dev = IxgbeDevice::<Sim82599>::init_queue(pci, &config).unwrap();
+        assert_eq!(dev.get_mac_addr(), [2, 0, 0, 0, 0, 1]);
+        assert_ne!(dev.get_reg32(IXGBE_RAH(0)) & IXGBE_RAH_AV, 0);
+        assert_eq!(dev.get_reg32(IXGBE_FCTRL) & IXGBE_FCTRL_UPE, 0);
+        assert_eq!(dev.get_bufs_in_use(), 64);
+
+        // frames up to the MTU are received, larger frames are dropped
+        let mut input = link::new();
+        let mut output = link::new();
+        transmit(&mut input, 9014, 0);
+        transmit(&mut input, 9015, 1);
+        assert_eq!(dev.tx_batch(0, &mut input), 2);
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 1);
+        let p = link::receive(&mut output);
+        assert_eq!((p.data[0], p.total_length()), (0, 9014));
+        packet::free(p);
+        assert_eq!(dev.get_reg32(IXGBE_ROC), 1);
+    }
+
+    #[test]
+    fn sim_stats() {
+        let pci = "0000:00:00.5";
+        let mut dev0 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(0)).unwrap();
+        let mut dev1 = IxgbeDevice::<Sim82599>::init_queue(pci, &queue_config(1)).unwrap();
+        let mut input = link::new();
+        let destinations = [[0xff; 6], [0x01, 0, 0x5e, 0, 0, 1], [0x02, 0, 0, 0, 0, 1]];
+        for destination in &destinations {
+            let mut p = packet::allocate();
+            p.data[..6].copy_from_slice(destination);
+            p.length = 60;
+            assert!(link::transmit(&mut input, p).is_none());
+        }
+        // too long for the default MTU
+        transmit(&mut input, 2000, 0);
+        assert_eq!(dev1.tx_batch(1, &mut input), 4);
+        let mut output = link::new();
+        assert_eq!(dev0.rx_batch(0, &mut output, 1000), 3);
+        while !link::empty(&output) {
+            packet::free(link::receive(&mut output));
+        }
+
+        // device-wide counters are read by the user of rx queue 0
+        let mut stats1: DeviceStats = Default::default();
+        dev1.read_stats(&mut stats1);
+        assert_eq!((stats1.rx_pkts, stats1.tx_pkts), (0, 4));
+        assert_eq!(stats1.tx_queue_pkts[1], 4);
+        assert_eq!(stats1.tx_broadcast, 0);
+        let mut stats0: DeviceStats = Default::default();
+        dev0.read_stats(&mut stats0);
+        assert_eq!((stats0.rx_pkts, stats0.tx_pkts), (3, 0));
+        assert_eq!(stats0.rx_queue_pkts[0], 3);
+        assert_eq!((stats0.rx_broadcast, stats0.rx_multicast), (1, 1));
+        assert_eq!((stats0.tx_broadcast, stats0.tx_multicast), (1, 1));
+        assert_eq!(stats0.rx_length_errors, 1);
+
+        // counters are accumulated across reads, link flaps are counted when the link is down
+        let old = stats0;
+        dev0.set_reg32(IXGBE_LINKS, 0);
+        dev0.read_stats(&mut stats0);
+        dev0.read_stats(&mut stats0);
+        assert_eq!((stats0.rx_pkts, stats0.link_flaps), (3, 1));
+        let diff = stats0.since(&old);
+        assert_eq!((diff.rx_pkts, diff.link_flaps), (0, 1));
+        let counters = stats0.counters();
+        assert!(counters.contains(&("rxpackets[0]".to_string(), 3)));
+        assert!(counters.contains(&("linkflaps".to_string(), 1)));
+    }
+
+    #[test]
+    fn sim_vlan() {
+        let pci = "0000:00:00.6";
+        let config = DeviceConfig {
+            vlan_strip: true,
+            vlan_insert: true,
+            vlan_filter: vec![100],
+            ..queue_config(0)
+        };
+        let mut dev = IxgbeDevice::<Sim82599>::init_queue(pci, &config).unwrap();
+        let tagged = |vlan: Option<u16>| {
+            let mut p = packet::allocate();
+            p.length = 60;
+            if let Some(vlan) = vlan {
+                p.meta.vlan = vlan;
+                p.meta.flags = packet::META_VLAN;
+            }
+            p
+        };
+
+        // tags are inserted from and stripped into the metadata, VLAN 200 is filtered
+        let mut input = link::new();
+        for &vlan in &[Some(100), Some(200), None, Some(100)] {
+            assert!(link::transmit(&mut input, tagged(vlan)).is_none());
+        }
+        assert_eq!(dev.tx_batch(0, &mut input), 4);
+        let mut output = link::new();
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 3);
+        for &vlan in &[Some(100), None, Some(100)] {
+            let p = link::receive(&mut output);
+            assert_eq!(p.length, 60);
+            assert_eq!(p.meta.flags & packet::META_VLAN != 0, vlan.is_some());
+            assert_eq!(p.meta.vlan, vlan.unwrap_or(0));
+            packet::free(p);
+        }
+
+        // context descriptors are cleaned along with their packets (the last descriptor of the
+        // first batch of 32 is a context descriptor)
+        assert!(link::transmit(&mut input, tagged(None)).is_none());
+        for i in 0..100 {
+            assert!(link::transmit(&mut input, tagged(Some(100 + i % 2))).is_none());
+        }
+        assert_eq!(dev.tx_batch(0, &mut input), 101);
+        assert_eq!(clean_tx_queue(dev.tx_queues[0].as_mut().unwrap()), 192);
+        assert_eq!(dev.tx_queues[0].as_ref().unwrap().bufs_in_use.len(), 15);
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 51);
+        while !link::empty(&output) {
+            packet::free(link::receive(&mut output));
+        }
+    }
+
+    #[test]
+    fn sim_tx_drop() {
+        let pci = "0000:00:01.0";
+        let config = DeviceConfig {
+            ring_size: 64,
+            ..queue_config(0)
+        };
+        let mut dev = IxgbeDevice::<Sim82599>::init_queue(pci, &config).unwrap();
+
+        // a packet with more segments than the tx queue has descriptors is dropped instead of
+        // blocking the queue
+        let mut input = link::new();
+        let mut p = packet::allocate();
+        for _ in 1..64 {
+            p.append(packet::allocate());
+        }
+        assert!(link::transmit(&mut input, p).is_none());
+        transmit(&mut input, 60, 1);
+        assert_eq!(dev.tx_batch(0, &mut input), 1);
+        assert!(link::empty(&input));
+        let mut stats: DeviceStats = Default::default();
+        dev.read_stats(&mut stats);
+        assert_eq!(stats.tx_dropped, 1);
+
+        let mut output = link::new();
+        assert_eq!(dev.rx_batch(0, &mut output, 1000), 1);
+        packet::free(link::receive(&mut output));
+    }
+}
@@ -18,21 +18,38 @@ mod constants;
 mod ixgbe;
 // pub mod memory;
 mod pci;
-// mod vfio;
-// mod virtio;
-// #[rustfmt::skip]
-// mod virtio_constants;
+#[cfg(test)]
+mod sim;
+mod vfio;
+mod virtio;
+#[rustfmt::skip]
+mod virtio_constants;
 
 // use self::interrupts::*;
 use self::ixgbe::*;
 // use self::memory::*;
 use self::pci::*;
-// use self::virtio::VirtioDevice;
+use self::virtio::VirtioDevice;
 
 use std::error::Error;
+use std::net::Ipv4Addr;
 use std::os::unix::io::RawFd;
+use std::time::Duration;
 
 const MAX_QUEUES: u16 = 64;
+/// Maximum number of rx queues receive side scaling can distribute packets to.
+const MAX_RSS_QUEUES: u16 = 16;
+
+/// Number of entries of the RSS indirection table.
+pub const RSS_RETA_SIZE: usize = 128;
+
+/// Packet types whose headers are hashed for receive side scaling (see `RssConfig::fields`).
+pub const RSS_IPV4: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV4;
+pub const RSS_IPV4_TCP: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV4_TCP;
+pub const RSS_IPV4_UDP: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV4_UDP;
+pub const RSS_IPV6: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV6;
+pub const RSS_IPV6_TCP: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV6_TCP;
+pub const RSS_IPV6_UDP: u32 = constants::IXGBE_MRQC_RSS_FIELD_IPV6_UDP;
 
 /// Used for implementing an ixy device driver like ixgbe or virtio.
 pub trait IxyDevice {
@@ -79,15 +96,11 @@ pub trait IxyDevice {
     ///
     /// dev.rx_batch(0, &mut buf, 32);
     /// ```
-    fn rx_batch(
-        &mut self,
-        queue_id: u32,
-        output: &mut link::Link,
-        num_packets: usize,
-    ) -> usize;
+    fn rx_batch(&mut self, queue_id: u32, output: &mut link::Link, num_packets: usize) -> usize;
 
     /// Takes `Packet`s out of `buffer` until `buffer` is empty or the network card's tx
-    /// queue is full. Returns the number of sent packets.
+    /// queue is full. Returns the number of sent packets. Packets that need more descriptors
+    /// than the tx queue has are dropped (see `DeviceStats::tx_dropped`).
     ///
     /// # Examples
     ///
@@ -129,6 +142,9 @@ pub trait IxyDevice {
     /// ```
     fn reset_stats(&mut self);
 
+    /// Returns the number of packet buffers currently held in the network card's rx and tx rings.
+    fn get_bufs_in_use(&self) -> usize;
+
     /// Returns the network card's link speed.
     ///
     /// # Examples
@@ -141,6 +157,14 @@ pub trait IxyDevice {
     /// ```
     fn get_link_speed(&self) -> u16;
 
+    /// Stops this device: disables its queues and returns the packets held in them to the
+    /// freelist. The last user of a network card also stops the card and binds it to the kernel
+    /// `driver` (if given). The device can not be used afterwards, but the network card can be
+    /// initialized again.
+    ///
+    /// Devices are released when dropped (without binding a driver).
+    fn release(&mut self, driver: Option<&str>) -> Result<(), Box<dyn Error>>;
+
     /// Takes `Packet`s out of `buffer` to send out. This will busy wait until all packets from
     /// `buffer` are queued.
     fn tx_batch_busy_wait(&mut self, queue_id: u32, input: &mut link::Link) {
@@ -150,16 +174,115 @@ pub trait IxyDevice {
     }
 }
 
+/// Number of per-queue statistics counters (queue `i` is counted in counter `i` % 16).
+pub const STATS_QUEUES: usize = 16;
+
 /// Holds network card stats about sent and received packets.
-#[derive(Default, Copy, Clone)]
+///
+/// Counters are accumulated by `IxyDevice::read_stats`, drivers that do not support a counter
+/// leave it at 0.
+#[derive(Default, Copy, Clone, Debug)]
 pub struct DeviceStats {
     pub rx_pkts: u64,
     pub tx_pkts: u64,
     pub rx_bytes: u64,
     pub tx_bytes: u64,
+    /// Packets missed because the receive packet buffer was full.
+    pub rx_missed: u64,
+    /// Packets received with a CRC error.
+    pub rx_crc_errors: u64,
+    /// Packets received with an invalid length, or too short or too long.
+    pub rx_length_errors: u64,
+    /// Packets dropped because their rx queue had no free descriptors.
+    pub rx_no_dma_resources: u64,
+    /// Packets dropped because they need more descriptors than their tx queue has.
+    pub tx_dropped: u64,
+    pub rx_broadcast: u64,
+    pub rx_multicast: u64,
+    pub tx_broadcast: u64,
+    pub tx_multicast: u64,
+    /// Pause (XON and XOFF) frames received.
+    pub rx_pause: u64,
+    /// Pause (XON and XOFF) frames transmitted.
+    pub tx_pause: u64,
+    /// Times the link was found to be down after being up.
+    pub link_flaps: u64,
+    /// Packets received per queue statistics counter.
+    pub rx_queue_pkts: [u64; STATS_QUEUES],
+    /// Packets transmitted per queue statistics counter.
+    pub tx_queue_pkts: [u64; STATS_QUEUES],
 }
 
 impl DeviceStats {
+    /// Returns the named counters of `self` (per-queue counters are only included if nonzero).
+    pub fn counters(&self) -> Vec<(String, u64)> {
+        let mut counters: Vec<(String, u64)> = [
+            ("rxpackets", self.rx_pkts),
+            ("rxbytes", self.rx_bytes),
+            ("txpackets", self.tx_pkts),
+            ("txbytes", self.tx_bytes),
+            ("rxmissed", self.rx_missed),
+            ("rxcrcerrors", self.rx_crc_errors),
+            ("rxlengtherrors", self.rx_length_errors),
+            ("rxnodmaresources", self.rx_no_dma_resources),
+            ("txdrops", self.tx_dropped),
+            ("rxbcast", self.rx_broadcast),
+            ("rxmcast", self.rx_multicast),
+            ("txbcast", self.tx_broadcast),
+            ("txmcast", self.tx_multicast),
+            ("rxpause", self.rx_pause),
+            ("txpause", self.tx_pause),
+            ("linkflaps", self.link_flaps),
+        ]
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value))
+        .collect();
+        for (name, queue_pkts) in &[
+            ("rxpackets", &self.rx_queue_pkts),
+            ("txpackets", &self.tx_queue_pkts),
+        ] {
+            for (i, &value) in queue_pkts.iter().enumerate() {
+                if value > 0 {
+                    counters.push((format!("{}[{}]", name, i), value));
+                }
+            }
+        }
+        counters
+    }
+
+    /// Returns the counters of `self` minus the counters of `old`.
+    pub fn since(&self, old: &DeviceStats) -> DeviceStats {
+        let queues = |new: &[u64; STATS_QUEUES], old: &[u64; STATS_QUEUES]| {
+            let mut diff = [0; STATS_QUEUES];
+            for (i, diff) in diff.iter_mut().enumerate() {
+                *diff = new[i].saturating_sub(old[i]);
+            }
+            diff
+        };
+        DeviceStats {
+            rx_pkts: self.rx_pkts.saturating_sub(old.rx_pkts),
+            tx_pkts: self.tx_pkts.saturating_sub(old.tx_pkts),
+            rx_bytes: self.rx_bytes.saturating_sub(old.rx_bytes),
+            tx_bytes: self.tx_bytes.saturating_sub(old.tx_bytes),
+            rx_missed: self.rx_missed.saturating_sub(old.rx_missed),
+            rx_crc_errors: self.rx_crc_errors.saturating_sub(old.rx_crc_errors),
+            rx_length_errors: self.rx_length_errors.saturating_sub(old.rx_length_errors),
+            rx_no_dma_resources: self
+                .rx_no_dma_resources
+                .saturating_sub(old.rx_no_dma_resources),
+            tx_dropped: self.tx_dropped.saturating_sub(old.tx_dropped),
+            rx_broadcast: self.rx_broadcast.saturating_sub(old.rx_broadcast),
+            rx_multicast: self.rx_multicast.saturating_sub(old.rx_multicast),
+            tx_broadcast: self.tx_broadcast.saturating_sub(old.tx_broadcast),
+            tx_multicast: self.tx_multicast.saturating_sub(old.tx_multicast),
+            rx_pause: self.rx_pause.saturating_sub(old.rx_pause),
+            tx_pause: self.tx_pause.saturating_sub(old.tx_pause),
+            link_flaps: self.link_flaps.saturating_sub(old.link_flaps),
+            rx_queue_pkts: queues(&self.rx_queue_pkts, &old.rx_queue_pkts),
+            tx_queue_pkts: queues(&self.tx_queue_pkts, &old.tx_queue_pkts),
+        }
+    }
+
     ///  Prints the stats differences between `stats_old` and `self`.
     #[allow(dead_code)]
     pub fn print_stats_diff(&self, dev: &dyn IxyDevice, stats_old: &DeviceStats, nanos: u64) {
@@ -205,6 +328,107 @@ impl DeviceStats {
     }
 }
 
+/// Receive side scaling configuration: distribution of received packets to rx queues.
+#[derive(Clone, Debug)]
+pub struct RssConfig {
+    /// Number of rx queues to distribute packets to (at most 16, 1 disables RSS).
+    pub queues: u16,
+    /// Secret key of the Toeplitz hash function.
+    pub key: [u8; 40],
+    /// Packet types to hash (`RSS_IPV4` etc.), other packets are received on queue 0.
+    pub fields: u32,
+    /// Indirection table mapping the lower 7 bits of the hash to a queue (`RSS_RETA_SIZE`
+    /// entries, or empty to use the queues in turn).
+    pub reta: Vec<u16>,
+}
+
+impl Default for RssConfig {
+    fn default() -> RssConfig {
+        RssConfig {
+            queues: 1,
+            // the key used by Microsoft's RSS verification suite
+            key: [
+                0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3,
+                0x8f, 0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3,
+                0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+            ],
+            fields: RSS_IPV4 | RSS_IPV4_TCP | RSS_IPV6 | RSS_IPV6_TCP,
+            reta: Vec::new(),
+        }
+    }
+}
+
+/// IP protocol numbers of `FlowFilter::protocol`.
+pub const IP_PROTOCOL_TCP: u8 = 6;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Flow Director perfect-match filter: receives IPv4 packets whose fields match on a given rx
+/// queue (instead of the queue selected by receive side scaling), or drops them.
+///
+/// Fields that are `None` match any value. All filters of a device must specify the same
+/// fields.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowFilter {
+    /// IP protocol (`IP_PROTOCOL_TCP` or `IP_PROTOCOL_UDP`), required to match ports.
+    pub protocol: Option<u8>,
+    pub src_ip: Option<Ipv4Addr>,
+    pub dst_ip: Option<Ipv4Addr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    /// VLAN ID (of tagged packets).
+    pub vlan: Option<u16>,
+    /// Rx queue to receive matching packets on, `None` drops them.
+    pub queue: Option<u16>,
+}
+
+/// Configuration of a device that uses a queue of a network card (see `ixy_init_queue`).
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    /// Rx queue to use.
+    pub rxq: u16,
+    /// Tx queue to use.
+    pub txq: u16,
+    /// Receive side scaling configuration (card-wide).
+    pub rss: RssConfig,
+    /// Flow Director filters (card-wide).
+    pub filters: Vec<FlowFilter>,
+    /// MAC address to set (card-wide), `None` keeps the address from the EEPROM.
+    pub macaddr: Option<[u8; 6]>,
+    /// Receive all packets regardless of their destination address (card-wide).
+    pub promisc: bool,
+    /// Number of descriptors of the rx and tx queues (a power of 2, at least 64).
+    pub ring_size: usize,
+    /// Largest IP packet that can be received (card-wide), excluding the Ethernet header.
+    pub mtu: u16,
+    /// Time to wait for the link to come up, if any.
+    pub wait_for_link: Option<Duration>,
+    /// Strip the VLAN tags of received packets into their metadata (see `packet::META_VLAN`).
+    pub vlan_strip: bool,
+    /// Insert the VLAN tags of transmitted packets from their metadata.
+    pub vlan_insert: bool,
+    /// VLAN IDs to receive tagged packets of (card-wide), empty to receive all tagged packets.
+    pub vlan_filter: Vec<u16>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> DeviceConfig {
+        DeviceConfig {
+            rxq: 0,
+            txq: 0,
+            rss: Default::default(),
+            filters: Vec::new(),
+            macaddr: None,
+            promisc: true,
+            ring_size: 512,
+            mtu: 1500,
+            wait_for_link: Some(Duration::from_secs(10)),
+            vlan_strip: false,
+            vlan_insert: false,
+            vlan_filter: Vec::new(),
+        }
+    }
+}
+
 /// Initializes the network card at `pci_addr`.
 ///
 /// `rx_queues` and `tx_queues` specify the number of queues that will be initialized and used
@@ -215,6 +439,60 @@ pub fn ixy_init(
     tx_queues: u16,
     interrupt_timeout: i16,
 ) -> Result<Box<dyn IxyDevice>, Box<dyn Error>> {
+    if is_virtio(pci_addr)? {
+        let device = VirtioDevice::init(pci_addr, rx_queues, tx_queues, interrupt_timeout)?;
+        Ok(Box::new(device))
+    } else {
+        // let's give it a try with ixgbe
+        let device = IxgbeDevice::<Mmio>::init(pci_addr, rx_queues, tx_queues, interrupt_timeout)?;
+        Ok(Box::new(device))
+    }
+}
+
+/// Initializes rx queue `rxq` and tx queue `txq` (see `config`) of the network card at
+/// `pci_addr`.
+///
+/// Other queues of the card can be used by other devices (in this or other processes). The
+/// first device to use the card initializes it and applies the card-wide options of `config`,
+/// for subsequent devices these options are ignored.
+///
+/// Virtio devices have a single queue and support none of the options but `macaddr` and
+/// `promisc` (`wait_for_link` is ignored, their link is always up).
+pub fn ixy_init_queue(
+    pci_addr: &str,
+    config: &DeviceConfig,
+) -> Result<Box<dyn IxyDevice>, Box<dyn Error>> {
+    if is_virtio(pci_addr)? {
+        let default = DeviceConfig::default();
+        if config.rxq != 0 || config.txq != 0 || config.rss.queues > 1 {
+            return Err(format!("device {} supports a single queue only", pci_addr).into());
+        }
+        if !config.filters.is_empty() {
+            return Err(format!("device {} does not support flow filters", pci_addr).into());
+        }
+        if config.ring_size != default.ring_size || config.mtu != default.mtu {
+            return Err(format!("device {} does not support ring_size and mtu", pci_addr).into());
+        }
+        if config.vlan_strip || config.vlan_insert || !config.vlan_filter.is_empty() {
+            return Err(format!("device {} does not support VLAN offloads", pci_addr).into());
+        }
+        let mut device = VirtioDevice::init(pci_addr, 1, 1, 0)?;
+        if let Some(mac) = config.macaddr {
+            device.set_mac_addr(mac);
+        }
+        if !config.promisc {
+            device.set_promisc(false)?;
+        }
+        Ok(Box::new(device))
+    } else {
+        let device = IxgbeDevice::<Mmio>::init_queue(pci_addr, config)?;
+        Ok(Box::new(device))
+    }
+}
+
+/// Returns true if the network card at `pci_addr` is a virtio device (fails if it is not a
+/// network card).
+fn is_virtio(pci_addr: &str) -> Result<bool, Box<dyn Error>> {
     let mut config_file = pci_open_resource(pci_addr, "config").expect("wrong pci address");
 
     let vendor_id = read_io16(&mut config_file, 0)?;
@@ -225,16 +503,8 @@ pub fn ixy_init(
         return Err(format!("device {} is not a network card", pci_addr).into());
     }
 
-    if vendor_id == 0x1af4 && device_id == 0x1000 {
-        // `device_id == 0x1041` would be for non-transitional devices which we don't support atm
-        // let device = VirtioDevice::init(pci_addr, rx_queues, tx_queues, interrupt_timeout)?;
-        // Ok(Box::new(device))
-        Err("Virtio not supported".into())
-    } else {
-        // let's give it a try with ixgbe
-        let device = IxgbeDevice::init(pci_addr, rx_queues, tx_queues, interrupt_timeout)?;
-        Ok(Box::new(device))
-    }
+    // `device_id == 0x1041` would be for non-transitional devices which we don't support atm
+    Ok(vendor_id == 0x1af4 && device_id == 0x1000)
 }
 
 impl IxyDevice for Box<dyn IxyDevice> {
@@ -271,12 +541,7 @@ impl IxyDevice for Box<dyn IxyDevice> {
         (**self).set_mac_addr(addr)
     }
 
-    fn rx_batch(
-        &mut self,
-        queue_id: u32,
-        output: &mut link::Link,
-        num_packets: usize,
-    ) -> usize {
+    fn rx_batch(&mut self, queue_id: u32, output: &mut link::Link, num_packets: usize) -> usize {
         (**self).rx_batch(queue_id, output, num_packets)
     }
 
@@ -292,7 +557,15 @@ impl IxyDevice for Box<dyn IxyDevice> {
         (**self).reset_stats()
     }
 
+    fn get_bufs_in_use(&self) -> usize {
+        (**self).get_bufs_in_use()
+    }
+
     fn get_link_speed(&self) -> u16 {
         (**self).get_link_speed()
     }
+
+    fn release(&mut self, driver: Option<&str>) -> Result<(), Box<dyn Error>> {
+        (**self).release(driver)
+    }
 }
@@ -25,6 +25,16 @@ pub fn unbind_driver(pci_addr: &str) -> Result<(), Box<dyn Error>> {
     }
 }
 
+/// Binds the kernel driver `driver` to the device at `pci_addr`.
+pub fn bind_driver(pci_addr: &str, driver: &str) -> Result<(), Box<dyn Error>> {
+    let path = format!("/sys/bus/pci/drivers/{}/bind", driver);
+
+    let mut f = fs::OpenOptions::new().write(true).open(path)?;
+    write!(f, "{}", pci_addr)?;
+
+    Ok(())
+}
+
 /// Enables direct memory access for the device at `pci_addr`.
 pub fn enable_dma(pci_addr: &str) -> Result<(), Box<dyn Error>> {
     let path = format!("/sys/bus/pci/devices/{}/config", pci_addr);
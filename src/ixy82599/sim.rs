@@ -0,0 +1,651 @@
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::ptr;
+use std::rc::{Rc, Weak};
+
+use super::constants::*;
+use super::ixgbe::Registers;
+use super::{MAX_QUEUES, STATS_QUEUES};
+
+/// In-memory model of an 82599 for testing the ixgbe driver without hardware.
+///
+/// Implements the handshakes of the driver's initialization (reset, EEPROM auto read, DMA init,
+/// link auto negotiation) and the descriptor rings. Packets transmitted on any tx queue are
+/// looped back to rx queue 0, or distributed to rx queues by receive side scaling and Flow
+/// Director perfect-match filters. VLAN tags are inserted, filtered and stripped. DMA memory is allocated on the heap, the device accesses it
+/// by virtual address.
+///
+/// All `Sim82599`s opened (on the same thread) for a `pci_addr` share the same device.
+pub struct Sim82599 {
+    state: Rc<RefCell<State>>,
+    // this user has claimed the device
+    claimed: bool,
+}
+
+thread_local! {
+    // simulated devices by pci address
+    static DEVICES: RefCell<HashMap<String, Weak<RefCell<State>>>> = RefCell::new(HashMap::new());
+}
+
+struct State {
+    regs: HashMap<u32, u32>,
+    // number of users that claimed the device, and whether it is claimed exclusively
+    users: usize,
+    exclusive: bool,
+    // statistics counters by register (by low register for 64 bit counters), cleared on read
+    counters: HashMap<u32, u64>,
+    // DMA memory allocated by the driver
+    dma: Vec<(*mut u8, Layout)>,
+    // Flow Director filters
+    fdir: Vec<FdirFilter>,
+    // VLAN tag of the context descriptor last written to each tx queue
+    tx_vlan: HashMap<u32, u16>,
+}
+
+/// A Flow Director perfect-match filter (as programmed via FDIRCMD).
+struct FdirFilter {
+    id: u32,
+    ipsa: u32,
+    ipda: u32,
+    port: u32,
+    vlan: u32,
+    cmd: u32,
+}
+
+/// Headers of a received frame relevant to packet distribution.
+struct Headers<'a> {
+    vlan: Option<u16>,
+    ipv4: bool,
+    // source and destination address
+    addrs: &'a [u8],
+    protocol: u8,
+    // source and destination port
+    ports: Option<&'a [u8]>,
+}
+
+// Length of the Ethernet FCS, counted in the octet statistics.
+const FCS_LENGTH: u64 = 4;
+// Minimum frame length (without FCS) when padding short frames.
+const MIN_FRAME_LENGTH: usize = 60;
+// Maximum frame length (with FCS) if jumbo frames are disabled.
+const MAX_FRAME_LENGTH: u32 = 1518;
+
+impl Registers for Sim82599 {
+    /// Returns the simulated 82599 at `pci_addr` (created in reset state if it does not exist).
+    fn open(pci_addr: &str) -> Result<Sim82599, Box<dyn Error>> {
+        let state = DEVICES.with(|devices| {
+            let mut devices = devices.borrow_mut();
+            if let Some(state) = devices.get(pci_addr).and_then(Weak::upgrade) {
+                return state;
+            }
+            let mut state = State {
+                regs: HashMap::new(),
+                users: 0,
+                exclusive: false,
+                counters: HashMap::new(),
+                dma: Vec::new(),
+                fdir: Vec::new(),
+                tx_vlan: HashMap::new(),
+            };
+            state.reset();
+            let state = Rc::new(RefCell::new(state));
+            devices.insert(pci_addr.to_string(), Rc::downgrade(&state));
+            state
+        });
+        Ok(Sim82599 {
+            state,
+            claimed: false,
+        })
+    }
+
+    /// Returns the register `reg`, statistics registers are cleared on read.
+    fn get_reg32(&self, reg: u32) -> u32 {
+        let mut state = self.state.borrow_mut();
+        match octet_counter(reg) {
+            // the octet counters are cleared on read of their high register
+            Some((low, true)) => (state.counters.remove(&low).unwrap_or(0) >> 32) as u32,
+            Some((low, false)) => state.counters.get(&low).copied().unwrap_or(0) as u32,
+            None => match state.counters.remove(&reg) {
+                Some(count) => count as u32,
+                None => state.reg(reg),
+            },
+        }
+    }
+
+    /// Sets the register `reg` to `value` and performs the device's reaction.
+    fn set_reg32(&self, reg: u32, value: u32) {
+        let mut state = self.state.borrow_mut();
+        state.regs.insert(reg, value);
+        match reg {
+            IXGBE_CTRL if value & IXGBE_CTRL_RST_MASK != 0 => state.reset(),
+            IXGBE_FDIRCTRL if value & IXGBE_FDIRCTRL_PERFECT_MATCH != 0 => {
+                state.regs.insert(reg, value | IXGBE_FDIRCTRL_INIT_DONE);
+            }
+            IXGBE_FDIRCMD => state.fdir_command(value),
+            IXGBE_AUTOC if value & IXGBE_AUTOC_AN_RESTART != 0 => {
+                // auto negotiation completes immediately
+                state.regs.insert(reg, value & !IXGBE_AUTOC_AN_RESTART);
+                state
+                    .regs
+                    .insert(IXGBE_LINKS, IXGBE_LINKS_UP | IXGBE_LINKS_SPEED_10G_82599);
+            }
+            _ => {
+                if let Some(queue) = (0..u32::from(MAX_QUEUES)).find(|&i| reg == IXGBE_TDT(i)) {
+                    state.transmit(queue);
+                }
+            }
+        }
+    }
+
+    fn dma_alloc(&self, bytes: usize, align: usize) -> io::Result<*mut u8> {
+        let layout = Layout::from_size_align(bytes, align).expect("invalid DMA allocation");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        assert!(!ptr.is_null(), "failed to allocate DMA memory");
+        self.state.borrow_mut().dma.push((ptr, layout));
+        Ok(ptr)
+    }
+
+    fn dma_free(&self, ptr: *mut u8, bytes: usize, align: usize) {
+        let layout = Layout::from_size_align(bytes, align).expect("invalid DMA allocation");
+        let mut state = self.state.borrow_mut();
+        let i = state
+            .dma
+            .iter()
+            .position(|&allocation| allocation == (ptr, layout))
+            .expect("DMA memory was not allocated");
+        state.dma.swap_remove(i);
+        unsafe { alloc::dealloc(ptr, layout) };
+    }
+
+    fn virtual_to_physical(&self, ptr: *const u8) -> u64 {
+        ptr as u64
+    }
+
+    fn iommu(&self) -> bool {
+        false
+    }
+
+    fn claim(&mut self, shared: bool) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state.borrow_mut();
+        if state.users > 0 && (!shared || state.exclusive) {
+            return Err("device is in use".into());
+        }
+        state.users += 1;
+        state.exclusive = !shared;
+        self.claimed = true;
+        Ok(state.users == 1)
+    }
+
+    fn initialized(&mut self) {}
+
+    fn release(&mut self) -> bool {
+        self.claimed && self.state.borrow().users == 1
+    }
+}
+
+impl Sim82599 {
+    /// Returns the number of DMA allocations of the device's users that are not freed.
+    pub fn dma_allocations(&self) -> usize {
+        self.state.borrow().dma.len()
+    }
+}
+
+impl Drop for Sim82599 {
+    fn drop(&mut self) {
+        if self.claimed {
+            let mut state = self.state.borrow_mut();
+            state.users -= 1;
+            state.exclusive = false;
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        for &(ptr, layout) in &self.dma {
+            unsafe { alloc::dealloc(ptr, layout) };
+        }
+    }
+}
+
+impl State {
+    /// Returns the register `reg` (0 if it was never set).
+    fn reg(&self, reg: u32) -> u32 {
+        self.regs.get(&reg).copied().unwrap_or(0)
+    }
+
+    /// Resets all registers, the EEPROM auto read and DMA initialization complete immediately.
+    fn reset(&mut self) {
+        self.regs.clear();
+        self.regs.insert(IXGBE_EEC, IXGBE_EEC_ARD);
+        self.regs.insert(IXGBE_RDRXCTL, IXGBE_RDRXCTL_DMAIDONE);
+        for i in 0..u32::from(MAX_QUEUES) {
+            // 2 KB receive buffers
+            self.regs.insert(IXGBE_SRRCTL(i), 2);
+        }
+        self.regs
+            .insert(IXGBE_MAXFRS, MAX_FRAME_LENGTH << IXGBE_MHADD_MFS_SHIFT);
+        self.counters.clear();
+        self.tx_vlan.clear();
+        self.fdir.clear();
+    }
+
+    /// Executes the Flow Director command `cmd` (adds or removes a filter).
+    fn fdir_command(&mut self, cmd: u32) {
+        let id = self.reg(IXGBE_FDIRHASH) >> IXGBE_FDIRHASH_SIG_SW_INDEX_SHIFT;
+        match cmd & IXGBE_FDIRCMD_CMD_MASK {
+            IXGBE_FDIRCMD_CMD_ADD_FLOW => {
+                self.fdir.retain(|filter| filter.id != id);
+                self.fdir.push(FdirFilter {
+                    id,
+                    ipsa: self.reg(IXGBE_FDIRIPSA),
+                    ipda: self.reg(IXGBE_FDIRIPDA),
+                    port: self.reg(IXGBE_FDIRPORT),
+                    vlan: self.reg(IXGBE_FDIRVLAN),
+                    cmd,
+                });
+            }
+            IXGBE_FDIRCMD_CMD_REMOVE_FLOW => self.fdir.retain(|filter| filter.id != id),
+            _ => (),
+        }
+        // commands complete immediately
+        self.regs
+            .insert(IXGBE_FDIRCMD, cmd & !IXGBE_FDIRCMD_CMD_MASK);
+    }
+
+    /// Processes the descriptors of tx `queue` from its head to its tail.
+    fn transmit(&mut self, queue: u32) {
+        if self.reg(IXGBE_DMATXCTL) & IXGBE_DMATXCTL_TE == 0
+            || self.reg(IXGBE_TXDCTL(queue)) & IXGBE_TXDCTL_ENABLE == 0
+        {
+            return;
+        }
+        let base = (u64::from(self.reg(IXGBE_TDBAH(queue))) << 32
+            | u64::from(self.reg(IXGBE_TDBAL(queue)))) as *mut ixgbe_adv_tx_desc;
+        let num_descriptors = self.reg(IXGBE_TDLEN(queue)) / 16;
+        let tail = self.reg(IXGBE_TDT(queue));
+        let mut head = self.reg(IXGBE_TDH(queue));
+        let mut frame = Vec::new();
+
+        while head != tail {
+            unsafe {
+                let desc = base.add(head as usize);
+                let read = ptr::read_volatile(&(*desc).read);
+                if read.cmd_type_len & IXGBE_ADVTXD_DTYP_MASK == IXGBE_ADVTXD_DTYP_CTXT {
+                    // context descriptors are not written back
+                    let context = ptr::read_volatile(desc as *const ixgbe_adv_tx_context_desc);
+                    let vlan = (context.vlan_macip_lens >> IXGBE_ADVTXD_VLAN_SHIFT) as u16;
+                    self.tx_vlan.insert(queue, vlan);
+                    head = (head + 1) % num_descriptors;
+                    continue;
+                }
+                let length = (read.cmd_type_len & IXGBE_ADVTXD_DTALEN_MASK) as usize;
+                let data = read.buffer_addr as *const u8;
+                frame.extend_from_slice(std::slice::from_raw_parts(data, length));
+
+                if read.cmd_type_len & IXGBE_ADVTXD_DCMD_EOP != 0 {
+                    if read.cmd_type_len & IXGBE_ADVTXD_DCMD_VLE != 0 {
+                        let vlan = self.tx_vlan.get(&queue).copied().unwrap_or(0);
+                        let tag = [0x81, 0x00, (vlan >> 8) as u8, vlan as u8];
+                        frame.splice(12..12, tag.iter().copied());
+                    }
+                    if self.reg(IXGBE_HLREG0) & IXGBE_HLREG0_TXPADEN != 0
+                        && frame.len() < MIN_FRAME_LENGTH
+                    {
+                        frame.resize(MIN_FRAME_LENGTH, 0);
+                    }
+                    let counter = self.queue_counter(IXGBE_TQSM, queue);
+                    let bytes = frame.len() as u64 + FCS_LENGTH;
+                    self.count(IXGBE_GPTC, 1);
+                    self.count(IXGBE_GOTCL, bytes);
+                    self.count(IXGBE_QPTC(counter), 1);
+                    self.count(IXGBE_QBTC_L(counter), bytes);
+                    self.count_address(&frame, IXGBE_BPTC, IXGBE_MPTC);
+                    self.receive(&frame);
+                    frame.clear();
+                }
+                if read.cmd_type_len & IXGBE_ADVTXD_DCMD_RS != 0 {
+                    ptr::write_volatile(&mut (*desc).wb.status, IXGBE_ADVTXD_STAT_DD);
+                }
+            }
+            head = (head + 1) % num_descriptors;
+        }
+
+        self.regs.insert(IXGBE_TDH(queue), head);
+    }
+
+    /// Writes `frame` into the descriptors of its rx queue (see `rss`), or drops it if the queue
+    /// has too few descriptors.
+    fn receive(&mut self, frame: &[u8]) {
+        // frames longer than MAXFRS are only accepted with jumbo frames enabled
+        let max_frame_length = if self.reg(IXGBE_HLREG0) & IXGBE_HLREG0_JUMBOEN != 0 {
+            self.reg(IXGBE_MAXFRS) >> IXGBE_MHADD_MFS_SHIFT
+        } else {
+            MAX_FRAME_LENGTH
+        };
+        if frame.len() as u64 + FCS_LENGTH > u64::from(max_frame_length) {
+            self.count(IXGBE_ROC, 1);
+            return;
+        }
+        // tagged frames must be in the VLAN filter table if filtering is enabled
+        let vlan = vlan_tag(frame);
+        if let Some(vlan) = vlan {
+            let vid = u32::from(vlan & 0xfff);
+            if self.reg(IXGBE_VLNCTRL) & IXGBE_VLNCTRL_VFE != 0
+                && self.reg(IXGBE_VFTA(vid / 32)) & (1 << (vid % 32)) == 0
+            {
+                return;
+            }
+        }
+        let headers = parse_headers(frame);
+        let (rss_type, hash) = headers
+            .as_ref()
+            .map_or((IXGBE_RXDADV_RSSTYPE_NONE, 0), |headers| self.rss(headers));
+        // without PCSD the descriptor carries the fragment checksum (not simulated) instead
+        let hi_dword = if self.reg(IXGBE_RXCSUM) & IXGBE_RXCSUM_PCSD != 0 {
+            hash
+        } else {
+            0
+        };
+        let queue = match headers.as_ref().and_then(|headers| self.fdir(headers)) {
+            Some(cmd) if cmd & IXGBE_FDIRCMD_DROP != 0 => return,
+            Some(cmd) => (cmd >> IXGBE_FDIRCMD_RX_QUEUE_SHIFT) & 0x7f,
+            None if rss_type != IXGBE_RXDADV_RSSTYPE_NONE => {
+                // each RETA register holds four 8 bit entries
+                let entry = hash & 0x7f;
+                (self.reg(IXGBE_RETA(entry / 4)) >> ((entry % 4) * 8)) & 0xf
+            }
+            None => 0,
+        };
+        if self.reg(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN == 0
+            || self.reg(IXGBE_RXDCTL(queue)) & IXGBE_RXDCTL_ENABLE == 0
+        {
+            return;
+        }
+        // VLAN tags are stripped into the descriptors if enabled for the queue
+        let mut stripped = Vec::new();
+        let (data, vlan) = match vlan {
+            Some(vlan) if self.reg(IXGBE_RXDCTL(queue)) & IXGBE_RXDCTL_VME != 0 => {
+                stripped.extend_from_slice(&frame[..12]);
+                stripped.extend_from_slice(&frame[16..]);
+                (&stripped[..], Some(vlan))
+            }
+            _ => (frame, None),
+        };
+        let base = (u64::from(self.reg(IXGBE_RDBAH(queue))) << 32
+            | u64::from(self.reg(IXGBE_RDBAL(queue)))) as *mut ixgbe_adv_rx_desc;
+        let num_descriptors = self.reg(IXGBE_RDLEN(queue)) / 16;
+        let buffer_size =
+            ((self.reg(IXGBE_SRRCTL(queue)) & IXGBE_SRRCTL_BSIZEPKT_MASK) * 1024) as usize;
+        let tail = self.reg(IXGBE_RDT(queue));
+        let mut head = self.reg(IXGBE_RDH(queue));
+
+        // descriptors from head to tail (exclusive) belong to the device
+        let available = (tail + num_descriptors - head) % num_descriptors;
+        let needed = data.len().div_ceil(buffer_size);
+        if (available as usize) < needed {
+            self.count(IXGBE_QPRDC(self.queue_counter(IXGBE_RQSMR, queue)), 1);
+            return;
+        }
+
+        let mut chunks = data.chunks(buffer_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            unsafe {
+                let desc = base.add(head as usize);
+                let data = ptr::read_volatile(&(*desc).read.pkt_addr) as *mut u8;
+                ptr::copy_nonoverlapping(chunk.as_ptr(), data, chunk.len());
+                let eop = if chunks.peek().is_none() {
+                    IXGBE_RXDADV_STAT_EOP
+                } else {
+                    0
+                };
+                ptr::write_volatile(&mut (*desc).wb.lower.lo_dword.data, rss_type);
+                ptr::write_volatile(&mut (*desc).wb.lower.hi_dword.rss, hi_dword);
+                ptr::write_volatile(
+                    &mut (*desc).wb.upper,
+                    ixgbe_adv_rx_desc_wb_upper {
+                        status_error: IXGBE_RXDADV_STAT_DD
+                            | eop
+                            | vlan.map_or(0, |_| IXGBE_RXDADV_STAT_VP),
+                        length: chunk.len() as u16,
+                        vlan: vlan.unwrap_or(0),
+                    },
+                );
+            }
+            head = (head + 1) % num_descriptors;
+        }
+
+        self.regs.insert(IXGBE_RDH(queue), head);
+        let counter = self.queue_counter(IXGBE_RQSMR, queue);
+        let bytes = frame.len() as u64 + FCS_LENGTH;
+        self.count(IXGBE_GPRC, 1);
+        self.count(IXGBE_GORCL, bytes);
+        self.count(IXGBE_QPRC(counter), 1);
+        self.count(IXGBE_QBRC_L(counter), bytes);
+        self.count_address(frame, IXGBE_BPRC, IXGBE_MPRC);
+    }
+
+    /// Adds `n` to the statistics counter `reg`.
+    fn count(&mut self, reg: u32, n: u64) {
+        *self.counters.entry(reg).or_insert(0) += n;
+    }
+
+    /// Counts `frame` in the counter `broadcast` or `multicast` if it has such a destination.
+    fn count_address(&mut self, frame: &[u8], broadcast: u32, multicast: u32) {
+        if frame.len() < 6 {
+            return;
+        }
+        if frame[..6].iter().all(|&byte| byte == 0xff) {
+            self.count(broadcast, 1);
+        } else if frame[0] & 1 != 0 {
+            self.count(multicast, 1);
+        }
+    }
+
+    /// Returns the statistics counter `queue` is mapped to by the `mapping` registers (RQSMR or
+    /// TQSM, four queues per register).
+    fn queue_counter(&self, mapping: fn(u32) -> u32, queue: u32) -> u32 {
+        (self.reg(mapping(queue / 4)) >> ((queue % 4) * 8)) & 0xf
+    }
+
+    /// Returns the command of the Flow Director filter that matches `headers` (counting matches
+    /// and misses in FDIRMATCH and FDIRMISS), if any.
+    fn fdir(&mut self, headers: &Headers<'_>) -> Option<u32> {
+        if self.reg(IXGBE_FDIRCTRL) & IXGBE_FDIRCTRL_PERFECT_MATCH == 0 || !headers.ipv4 {
+            return None;
+        }
+        let fdirm = self.reg(IXGBE_FDIRM);
+        let l4type = match headers.protocol {
+            6 => IXGBE_ATR_L4TYPE_TCP,
+            17 => IXGBE_ATR_L4TYPE_UDP,
+            _ => 0,
+        };
+        // the mask registers have bits set for ignored bits, port masks are bit reversed
+        let port_mask = match l4type {
+            IXGBE_ATR_L4TYPE_UDP => self.reg(IXGBE_FDIRUDPM),
+            _ => self.reg(IXGBE_FDIRTCPM),
+        };
+        let port_mask = !(u32::from((port_mask >> 16) as u16).reverse_bits() << 16
+            | u32::from((port_mask as u16).reverse_bits()));
+        let ip_masks = (!self.reg(IXGBE_FDIRSIP4M), !self.reg(IXGBE_FDIRDIP4M));
+        let addr = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let (ipsa, ipda) = (addr(&headers.addrs[0..4]), addr(&headers.addrs[4..8]));
+        let port = headers.ports.map_or(0, |ports| {
+            u32::from(u16::from_be_bytes([ports[2], ports[3]])) << IXGBE_FDIRPORT_DESTINATION_SHIFT
+                | u32::from(u16::from_be_bytes([ports[0], ports[1]]))
+        });
+        let vlan = u32::from(headers.vlan.unwrap_or(0) & 0xfff);
+
+        let cmd = self
+            .fdir
+            .iter()
+            .find(|filter| {
+                (fdirm & IXGBE_FDIRM_L4P != 0
+                    || (filter.cmd >> IXGBE_FDIRCMD_FLOW_TYPE_SHIFT) & IXGBE_ATR_L4TYPE_MASK
+                        == l4type)
+                    && (ipsa ^ filter.ipsa) & ip_masks.0 == 0
+                    && (ipda ^ filter.ipda) & ip_masks.1 == 0
+                    && (port ^ filter.port) & port_mask == 0
+                    && (fdirm & IXGBE_FDIRM_VLANID != 0
+                        || (headers.vlan.is_some() && vlan == filter.vlan & 0xfff))
+            })
+            .map(|filter| filter.cmd);
+        let counter = if cmd.is_some() {
+            IXGBE_FDIRMATCH
+        } else {
+            IXGBE_FDIRMISS
+        };
+        let count = self.reg(counter);
+        self.regs.insert(counter, count + 1);
+        cmd
+    }
+
+    /// Returns the RSS type and hash of a frame with `headers` according to MRQC, the type is
+    /// `IXGBE_RXDADV_RSSTYPE_NONE` if RSS is disabled or does not apply to the frame.
+    fn rss(&self, headers: &Headers<'_>) -> (u32, u32) {
+        let mrqc = self.reg(IXGBE_MRQC);
+        if mrqc & IXGBE_MRQC_RSSEN == 0 {
+            return (IXGBE_RXDADV_RSSTYPE_NONE, 0);
+        }
+        let ports = headers.ports;
+        let (rss_type, field) = match (headers.ipv4, headers.protocol, ports) {
+            (true, 6, Some(_)) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV4_TCP != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV4_TCP, ports)
+            }
+            (true, 17, Some(_)) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV4_UDP != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV4_UDP, ports)
+            }
+            (true, ..) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV4 != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV4, None)
+            }
+            (false, 6, Some(_)) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV6_TCP != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV6_TCP, ports)
+            }
+            (false, 17, Some(_)) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV6_UDP != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV6_UDP, ports)
+            }
+            (false, ..) if mrqc & IXGBE_MRQC_RSS_FIELD_IPV6 != 0 => {
+                (IXGBE_RXDADV_RSSTYPE_IPV6, None)
+            }
+            _ => return (IXGBE_RXDADV_RSSTYPE_NONE, 0),
+        };
+        let mut input = headers.addrs.to_vec();
+        input.extend_from_slice(field.unwrap_or(&[]));
+        (rss_type, toeplitz(&self.rss_key(), &input))
+    }
+
+    /// Returns the RSS key (stored in little endian order in the RSSRK registers).
+    fn rss_key(&self) -> Vec<u8> {
+        (0..10)
+            .flat_map(|i| self.reg(IXGBE_RSSRK(i)).to_le_bytes().to_vec())
+            .collect()
+    }
+}
+
+/// Returns the low register of the 64 bit octet counter that `reg` belongs to, and whether `reg`
+/// is its high register, or `None` if `reg` is not part of an octet counter.
+fn octet_counter(reg: u32) -> Option<(u32, bool)> {
+    let mut counters = vec![(IXGBE_GORCL, IXGBE_GORCH), (IXGBE_GOTCL, IXGBE_GOTCH)];
+    for i in 0..STATS_QUEUES as u32 {
+        counters.push((IXGBE_QBRC_L(i), IXGBE_QBRC_H(i)));
+        counters.push((IXGBE_QBTC_L(i), IXGBE_QBTC_H(i)));
+    }
+    counters.into_iter().find_map(|(low, high)| {
+        if reg == low {
+            Some((low, false))
+        } else if reg == high {
+            Some((low, true))
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the VLAN tag (TCI) of `frame`, if it is tagged.
+fn vlan_tag(frame: &[u8]) -> Option<u16> {
+    match frame.get(12..16)? {
+        [0x81, 0x00, high, low] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+/// Returns the VLAN, IP and TCP/UDP headers of `frame`, or `None` if it is not an IP packet.
+fn parse_headers(frame: &[u8]) -> Option<Headers<'_>> {
+    let vlan = vlan_tag(frame);
+    let (ethertype, ip) = match vlan {
+        Some(_) => (
+            u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]),
+            &frame[18..],
+        ),
+        None => (
+            u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]),
+            &frame[14..],
+        ),
+    };
+    // (addresses, protocol, offset of the layer 4 header)
+    let (addrs, protocol, l4) = match ethertype {
+        0x0800 if ip.len() >= 20 => {
+            let ihl = usize::from(ip[0] & 0xf) * 4;
+            let fragment = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+            let protocol = if fragment { 0 } else { ip[9] };
+            (&ip[12..20], protocol, ihl)
+        }
+        0x86dd if ip.len() >= 40 => (&ip[8..40], ip[6], 40),
+        _ => return None,
+    };
+    let ports = match protocol {
+        6 | 17 => ip.get(l4..l4 + 4),
+        _ => None,
+    };
+    Some(Headers {
+        vlan,
+        ipv4: ethertype == 0x0800,
+        addrs,
+        protocol,
+        ports,
+    })
+}
+
+/// Returns the Toeplitz hash of `input` with `key`.
+fn toeplitz(key: &[u8], input: &[u8]) -> u32 {
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= key_window(key, i * 8 + bit);
+            }
+        }
+    }
+    hash
+}
+
+/// Returns the 32 bits of `key` starting at bit `offset`.
+fn key_window(key: &[u8], offset: usize) -> u32 {
+    (0..32).fold(0, |window, i| {
+        let bit = offset + i;
+        let set = key
+            .get(bit / 8)
+            .is_some_and(|b| b & (0x80 >> (bit % 8)) != 0);
+        window << 1 | u32::from(set)
+    })
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::ixy82599::RssConfig;
+
+    #[test]
+    fn toeplitz_hash() {
+        // test vector from Microsoft's RSS verification suite
+        let key = RssConfig::default().key;
+        let addrs = [66, 9, 149, 187, 161, 142, 100, 80];
+        let ports = [0x0a, 0xea, 0x06, 0xe6]; // 2794 -> 1766
+        assert_eq!(toeplitz(&key, &addrs), 0x323e_8fc2);
+        let input: Vec<u8> = addrs.iter().chain(ports.iter()).copied().collect();
+        assert_eq!(toeplitz(&key, &input), 0x51cc_c178);
+    }
+}
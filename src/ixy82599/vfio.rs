@@ -0,0 +1,287 @@
+#![allow(non_camel_case_types)]
+
+use crate::memory;
+
+use once_cell::unsync::Lazy;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+
+use super::pci::{BUS_MASTER_ENABLE_BIT, COMMAND_REGISTER_OFFSET};
+
+// constants and structs from <linux/vfio.h> (not provided by the libc crate)
+const VFIO_API_VERSION: libc::c_int = 0;
+const VFIO_TYPE1_IOMMU: libc::c_ulong = 1;
+
+const VFIO_GET_API_VERSION: libc::c_ulong = 0x3b64;
+const VFIO_CHECK_EXTENSION: libc::c_ulong = 0x3b65;
+const VFIO_SET_IOMMU: libc::c_ulong = 0x3b66;
+const VFIO_GROUP_GET_STATUS: libc::c_ulong = 0x3b67;
+const VFIO_GROUP_SET_CONTAINER: libc::c_ulong = 0x3b68;
+const VFIO_GROUP_GET_DEVICE_FD: libc::c_ulong = 0x3b6a;
+const VFIO_DEVICE_GET_REGION_INFO: libc::c_ulong = 0x3b6c;
+const VFIO_IOMMU_MAP_DMA: libc::c_ulong = 0x3b71;
+
+const VFIO_GROUP_FLAGS_VIABLE: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_READ: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+/// IOMMU address width assumed if the IOMMU does not report it.
+const DEFAULT_IOMMU_ADDRESS_WIDTH: u32 = 39;
+
+pub const VFIO_PCI_BAR0_REGION_INDEX: u32 = 0;
+pub const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+
+#[repr(C)]
+#[derive(Default)]
+struct vfio_group_status {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct vfio_region_info {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct vfio_iommu_type1_dma_map {
+    argsz: u32,
+    flags: u32,
+    vaddr: u64,
+    iova: u64,
+    size: u64,
+}
+
+/// The VFIO container shared by all devices (the IOMMU context of this process).
+static mut CONTAINER: Option<RawFd> = None;
+
+/// The IOMMU groups added to the container (by group number) and their file descriptors.
+static mut GROUPS: Lazy<HashMap<String, RawFd>> = Lazy::new(HashMap::new);
+
+/// Returns true if the device at `pci_addr` is bound to the vfio-pci driver.
+pub fn vfio_available(pci_addr: &str) -> bool {
+    let path = format!("/sys/bus/pci/devices/{}/driver", pci_addr);
+    fs::read_link(path).is_ok_and(|driver| driver.ends_with("vfio-pci"))
+}
+
+/// Returns the VFIO container file descriptor or [`None`] if no device uses VFIO.
+pub fn get_vfio_container() -> Option<RawFd> {
+    unsafe { CONTAINER }
+}
+
+/// Initializes the device at `pci_addr` via VFIO and returns the device file descriptor.
+///
+/// Adds the device's IOMMU group to the VFIO container (creating the container, and switching
+/// DMA memory to the IOMMU, on first use) and enables DMA for the device. The device can be
+/// initialized again once its file descriptor is closed.
+pub fn vfio_init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
+    // find the IOMMU group of the device
+    let link = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr))?;
+    let group = link
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("failed to get iommu group")?;
+
+    // NB: a group can only be opened once, it stays attached to the container as long as it
+    // is open, which is as long as we run.
+    let groups = unsafe { &mut GROUPS };
+    let group_fd = match groups.get(group) {
+        Some(&group_fd) => group_fd,
+        None => {
+            let group_fd = open_group(group)?;
+            groups.insert(group.to_string(), group_fd);
+            group_fd
+        }
+    };
+
+    // DMA memory must be mapped at IOVAs the device's IOMMU can address
+    memory::limit_iova(iommu_address_width(pci_addr))?;
+
+    let name = CString::new(pci_addr)?;
+    let device_fd = ioctl(group_fd, VFIO_GROUP_GET_DEVICE_FD, name.as_ptr() as usize)?;
+
+    enable_dma(device_fd)?;
+
+    Ok(device_fd)
+}
+
+/// Opens IOMMU `group` and adds it to the VFIO container, returns the group file descriptor.
+fn open_group(group: &str) -> Result<RawFd, Box<dyn Error>> {
+    let group_file = open_rw(format!("/dev/vfio/{}", group))?;
+    let group_fd = group_file.as_raw_fd();
+
+    // the group is viable if all its devices are bound to vfio (or no driver)
+    let mut status = vfio_group_status {
+        argsz: mem::size_of::<vfio_group_status>() as u32,
+        ..Default::default()
+    };
+    ioctl(
+        group_fd,
+        VFIO_GROUP_GET_STATUS,
+        &mut status as *mut _ as usize,
+    )?;
+    if status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
+        return Err(format!(
+            "iommu group {} is not viable (are all its devices bound to vfio-pci?)",
+            group
+        )
+        .into());
+    }
+
+    let (container, new) = match get_vfio_container() {
+        Some(container) => (container, false),
+        None => (open_container()?, true),
+    };
+
+    // add the group to the container, the IOMMU type can only be set once the
+    // container has a group
+    let mut container_fd = container;
+    ioctl(
+        group_fd,
+        VFIO_GROUP_SET_CONTAINER,
+        &mut container_fd as *mut RawFd as usize,
+    )?;
+    if new {
+        // NB: use_iommu maps the existing DMA memory via vfio_map_dma, which uses CONTAINER.
+        // The container is only kept if IOMMU mode is enabled, otherwise it is closed (along
+        // with its mappings) so that a retry sets up a new one.
+        let result = ioctl(container, VFIO_SET_IOMMU, VFIO_TYPE1_IOMMU as usize).and_then(|_| {
+            unsafe { CONTAINER = Some(container) };
+            memory::use_iommu(vfio_map_dma)
+        });
+        if let Err(error) = result {
+            unsafe {
+                CONTAINER = None;
+                libc::close(container);
+            }
+            return Err(error.into());
+        }
+    }
+
+    Ok(group_file.into_raw_fd())
+}
+
+/// Mmaps the VFIO region `index` of the device and returns a pointer to the mapped memory.
+pub fn vfio_map_region(device_fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dyn Error>> {
+    let region = region_info(device_fd, index)?;
+    let len = region.size as usize;
+
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            device_fd,
+            region.offset as libc::off_t,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED || len == 0 {
+        Err(format!("vfio mapping failed: {}", io::Error::last_os_error()).into())
+    } else {
+        Ok((ptr as *mut u8, len))
+    }
+}
+
+/// Maps `size` bytes of memory at `addr` into the IOMMU at `iova`.
+pub fn vfio_map_dma(addr: u64, iova: u64, size: usize) -> io::Result<()> {
+    let container = get_vfio_container().ok_or(io::ErrorKind::NotFound)?;
+    let mut dma_map = vfio_iommu_type1_dma_map {
+        argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
+        flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+        vaddr: addr,
+        iova,
+        size: size as u64,
+    };
+    ioctl(
+        container,
+        VFIO_IOMMU_MAP_DMA,
+        &mut dma_map as *mut _ as usize,
+    )?;
+    Ok(())
+}
+
+/// Returns the address width (in bits) of the IOMMU of the device at `pci_addr`.
+///
+/// Intel IOMMUs report their maximum guest address width in their capability register, for
+/// other IOMMUs we assume the smallest width in common use.
+fn iommu_address_width(pci_addr: &str) -> u32 {
+    let path = format!("/sys/bus/pci/devices/{}/iommu/intel-iommu/cap", pci_addr);
+    let cap = fs::read_to_string(path)
+        .ok()
+        .and_then(|cap| u64::from_str_radix(cap.trim(), 16).ok());
+    match cap {
+        Some(cap) => ((cap >> 16) & 0x3f) as u32 + 1,
+        None => DEFAULT_IOMMU_ADDRESS_WIDTH,
+    }
+}
+
+/// Opens the VFIO container and checks that it supports the type 1 IOMMU.
+fn open_container() -> Result<RawFd, Box<dyn Error>> {
+    let container = open_rw("/dev/vfio/vfio")?;
+    let fd = container.as_raw_fd();
+    if ioctl(fd, VFIO_GET_API_VERSION, 0)? != VFIO_API_VERSION {
+        return Err("unknown VFIO API version".into());
+    }
+    if ioctl(fd, VFIO_CHECK_EXTENSION, VFIO_TYPE1_IOMMU as usize)? != 1 {
+        return Err("VFIO type 1 IOMMU is not supported".into());
+    }
+    Ok(container.into_raw_fd())
+}
+
+/// Enables direct memory access for the device (via its VFIO config region).
+fn enable_dma(device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let region = region_info(device_fd, VFIO_PCI_CONFIG_REGION_INDEX)?;
+    // NB: the File must not close the device fd
+    let file = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(device_fd) });
+    let offset = region.offset + COMMAND_REGISTER_OFFSET;
+
+    let mut buf = [0; 2];
+    file.read_exact_at(&mut buf, offset)?;
+    let dma = u16::from_ne_bytes(buf) | 1 << BUS_MASTER_ENABLE_BIT;
+    file.write_all_at(&dma.to_ne_bytes(), offset)?;
+
+    Ok(())
+}
+
+/// Returns the info of VFIO region `index` of the device.
+fn region_info(device_fd: RawFd, index: u32) -> io::Result<vfio_region_info> {
+    let mut region = vfio_region_info {
+        argsz: mem::size_of::<vfio_region_info>() as u32,
+        index,
+        ..Default::default()
+    };
+    ioctl(
+        device_fd,
+        VFIO_DEVICE_GET_REGION_INFO,
+        &mut region as *mut _ as usize,
+    )?;
+    Ok(region)
+}
+
+fn open_rw<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(path)
+}
+
+fn ioctl(fd: RawFd, request: libc::c_ulong, arg: usize) -> io::Result<libc::c_int> {
+    match unsafe { libc::ioctl(fd, request, arg) } {
+        res if res < 0 => Err(io::Error::last_os_error()),
+        res => Ok(res),
+    }
+}
@@ -0,0 +1,805 @@
+use crate::link;
+use crate::memory;
+use crate::packet;
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fs::File;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::pci::{bind_driver, enable_dma, pci_open_resource, unbind_driver};
+use super::virtio_constants::*;
+use super::DeviceStats;
+use super::IxyDevice;
+
+const DRIVER_NAME: &str = "ixy-virtio";
+
+// size of a control queue command buffer (class, command, data, ack)
+const COMMAND_SIZE: usize = 4;
+
+// features we use if the device offers them
+const DRIVER_FEATURES: u32 = 1 << VIRTIO_NET_F_MAC
+    | 1 << VIRTIO_NET_F_STATUS
+    | 1 << VIRTIO_NET_F_CTRL_VQ
+    | 1 << VIRTIO_NET_F_CTRL_RX;
+
+pub struct VirtioDevice {
+    pci_addr: String,
+    // legacy I/O BAR, accessed via its sysfs resource file
+    bar: File,
+    features: u32,
+    // rx and tx queue, set up by `init_queues`
+    rx_queue: Option<Virtqueue>,
+    tx_queue: Option<Virtqueue>,
+    // control queue and its command buffer (if VIRTIO_NET_F_CTRL_VQ)
+    ctrl_queue: Option<(Virtqueue, *mut u8)>,
+    // received packets, transmitted to the output link in one batch
+    rx_packets: Vec<packet::PacketBox>,
+    // the device has no counters, we count packets ourselves
+    stats: Cell<DeviceStats>,
+    // device was released, it can not be used anymore
+    released: bool,
+}
+
+/// Packet header that precedes every packet on the rx and tx queues (struct virtio_net_hdr).
+#[repr(C)]
+#[derive(Default)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+/// Virtqueue descriptor (struct vring_desc).
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Element of the used ring (struct vring_used_elem).
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Split virtqueue: descriptor table, available ring (driver to device) and used ring (device
+/// to driver) in one physically contiguous memory region.
+struct Virtqueue {
+    index: u16,
+    size: usize,
+    desc: *mut VirtqDesc,
+    // flags, idx, ring[size]
+    avail: *mut u16,
+    // flags, idx, followed by ring[size] of VirtqUsedElem
+    used: *mut u16,
+    // free descriptors
+    free: Vec<u16>,
+    // packet of each descriptor chain in use (by head descriptor)
+    bufs_in_use: Vec<*mut packet::Packet>,
+    // number of descriptor chains in use
+    in_flight: usize,
+    // net headers (one per descriptor, used by chain heads) and their physical address
+    headers: Option<(*mut VirtioNetHdr, u64)>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+/// Returns the size of the memory region of a virtqueue with `size` entries.
+fn vring_size(size: usize) -> usize {
+    let avail_end = size * mem::size_of::<VirtqDesc>() + 2 * (3 + size);
+    let used = 2 * 3 + size * mem::size_of::<VirtqUsedElem>();
+    align(avail_end) + align(used)
+}
+
+fn align(n: usize) -> usize {
+    (n + VIRTIO_PCI_VRING_ALIGN - 1) & !(VIRTIO_PCI_VRING_ALIGN - 1)
+}
+
+impl Virtqueue {
+    /// Creates a virtqueue with `size` entries in the (zeroed) memory at `mem` (see
+    /// `vring_size`). If `headers` is given, each descriptor chain is preceded by a net header.
+    fn new(
+        index: u16,
+        size: usize,
+        mem: *mut u8,
+        headers: Option<(*mut VirtioNetHdr, u64)>,
+    ) -> Self {
+        assert!(!mem.is_null(), "virtqueue memory is null");
+        let avail_offset = size * mem::size_of::<VirtqDesc>();
+        let used_offset = align(avail_offset + 2 * (3 + size));
+        let queue = Virtqueue {
+            index,
+            size,
+            desc: mem as *mut VirtqDesc,
+            avail: unsafe { mem.add(avail_offset) as *mut u16 },
+            used: unsafe { mem.add(used_offset) as *mut u16 },
+            free: (0..size as u16).rev().collect(),
+            bufs_in_use: vec![packet::null_mut(); size],
+            in_flight: 0,
+            headers,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        // we poll, tell the device not to interrupt us
+        unsafe { ptr::write_volatile(queue.avail, VRING_AVAIL_F_NO_INTERRUPT) };
+        queue
+    }
+
+    /// Returns the number of free descriptors.
+    fn nfree(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Adds a descriptor chain for `bufs` (physical address, length, flags) that belongs to the
+    /// packet `p` to the available ring. The chain becomes visible to the device on `publish`.
+    ///
+    /// # Panics
+    /// Panics if there are not enough free descriptors.
+    fn add(&mut self, mut bufs: impl Iterator<Item = (u64, u32, u16)>, p: *mut packet::Packet) {
+        let head = self.free.pop().expect("virtqueue is full");
+        let mut last = head;
+        match self.headers {
+            Some((headers, headers_phys)) => unsafe {
+                // the header is device writable if the buffers are
+                let header = headers.add(head as usize);
+                ptr::write_volatile(header, Default::default());
+                self.set_desc(
+                    head,
+                    headers_phys + (head as usize * mem::size_of::<VirtioNetHdr>()) as u64,
+                    mem::size_of::<VirtioNetHdr>() as u32,
+                    0,
+                );
+            },
+            None => {
+                let (addr, len, flags) = bufs.next().expect("empty descriptor chain");
+                self.set_desc(head, addr, len, flags);
+            }
+        }
+        for (addr, len, flags) in bufs {
+            let desc = self.free.pop().expect("virtqueue is full");
+            unsafe {
+                let last = self.desc.add(last as usize);
+                (*last).flags |= VRING_DESC_F_NEXT | (flags & VRING_DESC_F_WRITE);
+                (*last).next = desc;
+            }
+            self.set_desc(desc, addr, len, flags);
+            last = desc;
+        }
+        self.bufs_in_use[head as usize] = p;
+        self.in_flight += 1;
+        unsafe {
+            let slot = self
+                .avail
+                .add(2 + (self.avail_idx as usize & (self.size - 1)));
+            ptr::write_volatile(slot, head);
+        }
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+    }
+
+    fn set_desc(&mut self, desc: u16, addr: u64, len: u32, flags: u16) {
+        unsafe {
+            ptr::write_volatile(
+                self.desc.add(desc as usize),
+                VirtqDesc {
+                    addr,
+                    len,
+                    flags,
+                    next: 0,
+                },
+            );
+        }
+    }
+
+    /// Makes the descriptor chains added so far visible to the device. Returns true if the
+    /// device needs to be notified.
+    fn publish(&mut self) -> bool {
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.avail.add(1), self.avail_idx) };
+        fence(Ordering::SeqCst);
+        unsafe { ptr::read_volatile(self.used) & VRING_USED_F_NO_NOTIFY == 0 }
+    }
+
+    /// Returns true if the device has returned descriptor chains.
+    fn has_used(&self) -> bool {
+        unsafe { ptr::read_volatile(self.used.add(1)) != self.last_used }
+    }
+
+    /// Takes the next descriptor chain returned by the device, frees its descriptors, and
+    /// returns its packet and the number of bytes written by the device (excluding the net
+    /// header).
+    fn pop_used(&mut self) -> Option<(*mut packet::Packet, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let elem = unsafe {
+            let ring = self.used.add(2) as *const VirtqUsedElem;
+            ptr::read_volatile(ring.add(self.last_used as usize & (self.size - 1)))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        let head = elem.id as u16;
+        let mut desc = head;
+        loop {
+            self.free.push(desc);
+            let d = unsafe { &*self.desc.add(desc as usize) };
+            if d.flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            desc = d.next;
+        }
+        let p = mem::replace(&mut self.bufs_in_use[head as usize], packet::null_mut());
+        self.in_flight -= 1;
+        let len = match self.headers {
+            Some(_) => elem
+                .len
+                .saturating_sub(mem::size_of::<VirtioNetHdr>() as u32),
+            None => elem.len,
+        };
+        Some((p, len))
+    }
+
+    /// Frees the packets of all descriptor chains in use (the device must be reset).
+    fn free_bufs(&mut self) {
+        for p in self.bufs_in_use.iter_mut() {
+            let p = mem::replace(p, packet::null_mut());
+            if !p.is_null() {
+                packet::free(unsafe { packet::PacketBox::from_raw(p) });
+            }
+        }
+        self.in_flight = 0;
+    }
+
+    /// Returns the memory of this virtqueue (see `VirtioDevice::init_queue`) to the DMA memory
+    /// allocator (the device must be reset).
+    fn free_memory(self) {
+        memory::dma_free(
+            self.desc as *mut u8,
+            vring_size(self.size),
+            VIRTIO_PCI_VRING_ALIGN,
+        );
+        if let Some((headers, _)) = self.headers {
+            let headers_size = self.size * mem::size_of::<VirtioNetHdr>();
+            memory::dma_free(headers as *mut u8, headers_size, 8);
+        }
+    }
+}
+
+impl IxyDevice for VirtioDevice {
+    /// Returns an initialized `VirtioDevice` on success.
+    ///
+    /// Legacy virtio-net devices have a single rx and tx queue.
+    fn init(
+        pci_addr: &str,
+        num_rx_queues: u16,
+        num_tx_queues: u16,
+        _interrupt_timeout: i16,
+    ) -> Result<VirtioDevice, Box<dyn Error>> {
+        if unsafe { libc::getuid() } != 0 {
+            println!("not running as root, this will probably fail");
+        }
+
+        if num_rx_queues > 1 || num_tx_queues > 1 {
+            return Err("virtio-net supports only one rx and one tx queue".into());
+        }
+
+        unbind_driver(pci_addr)?;
+        enable_dma(pci_addr)?;
+        let bar = pci_open_resource(pci_addr, "resource0")?;
+
+        // section 3.1 (virtio 0.9.5) - device initialization: reset, acknowledge the device,
+        // negotiate features, set up the queues, and tell the device we are done
+        let mut dev = VirtioDevice {
+            pci_addr: pci_addr.to_string(),
+            bar,
+            features: 0,
+            rx_queue: None,
+            tx_queue: None,
+            ctrl_queue: None,
+            rx_packets: Vec::new(),
+            stats: Cell::new(Default::default()),
+            released: false,
+        };
+        dev.reset_and_init()?;
+
+        Ok(dev)
+    }
+
+    /// Returns the driver's name of this device.
+    fn get_driver_name(&self) -> &str {
+        DRIVER_NAME
+    }
+
+    /// Returns the card's iommu capability.
+    fn is_card_iommu_capable(&self) -> bool {
+        false
+    }
+
+    /// Returns VFIO container file descriptor or [`None`] if IOMMU is not available.
+    fn get_vfio_container(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Returns the pci address of this device.
+    fn get_pci_addr(&self) -> &str {
+        &self.pci_addr
+    }
+
+    /// Returns the mac address of this device.
+    fn get_mac_addr(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = self.read8(VIRTIO_PCI_CONFIG + VIRTIO_NET_CONFIG_MAC + i as u64);
+        }
+        mac
+    }
+
+    /// Sets the mac address of this device.
+    fn set_mac_addr(&self, mac: [u8; 6]) {
+        for (i, byte) in mac.iter().enumerate() {
+            self.write8(VIRTIO_PCI_CONFIG + VIRTIO_NET_CONFIG_MAC + i as u64, *byte);
+        }
+    }
+
+    /// Pushes up to `num_packets` received `Packet`s onto `buffer`.
+    fn rx_batch(&mut self, queue_id: u32, output: &mut link::Link, num_packets: usize) -> usize {
+        assert!(queue_id == 0, "invalid rx queue id");
+        let mut received_packets = 0;
+        let mut stats = self.stats.get();
+
+        // one timestamp per batch is accurate enough and much cheaper
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        let queue = self
+            .rx_queue
+            .as_mut()
+            .expect("virtio rx queue is not set up");
+        while received_packets < num_packets && queue.has_used() {
+            // get a replacement buffer first, if there is none leave the
+            // packet in the queue (the device drops packets once it is full)
+            let np = match packet::try_allocate() {
+                Some(np) => np,
+                None => break,
+            };
+
+            let (p, len) = queue.pop_used().unwrap();
+            let mut p = unsafe { packet::PacketBox::from_raw(p) };
+            p.length = len as u16;
+            p.meta.timestamp = timestamp;
+            p.meta.queue = queue_id as u16;
+            p.meta.flags = packet::META_TIMESTAMP | packet::META_QUEUE;
+            stats.rx_pkts += 1;
+            stats.rx_bytes += u64::from(len);
+            self.rx_packets.push(p);
+
+            add_rx_buffer(queue, np);
+            received_packets += 1;
+        }
+
+        if received_packets > 0 && queue.publish() {
+            self.notify(VIRTIO_NET_RX_QUEUE);
+        }
+        self.stats.set(stats);
+
+        link::transmit_batch(output, &mut self.rx_packets);
+
+        received_packets
+    }
+
+    /// Pops as many packets as possible from `packets` to put them into the device`s tx queue.
+    fn tx_batch(&mut self, queue_id: u32, input: &mut link::Link) -> usize {
+        assert!(queue_id == 0, "invalid tx queue id");
+        let mut sent = 0;
+        let mut stats = self.stats.get();
+
+        let queue = self
+            .tx_queue
+            .as_mut()
+            .expect("virtio tx queue is not set up");
+
+        // free packets the device is done with
+        while let Some((p, _)) = queue.pop_used() {
+            packet::free(unsafe { packet::PacketBox::from_raw(p) });
+        }
+
+        while !link::empty(input) {
+            // each segment of a packet takes up one descriptor (plus one for the header)
+            let ndescriptors = link::front(input).nsegments() + 1;
+            if ndescriptors > queue.size {
+                // the packet never fits into the tx queue, drop it so that it does not block
+                // the queue
+                packet::free(link::receive(input));
+                stats.tx_dropped += 1;
+                continue;
+            }
+            if queue.nfree() < ndescriptors {
+                // tx queue of device is full
+                break;
+            }
+
+            let p = link::receive(input);
+            stats.tx_pkts += 1;
+            stats.tx_bytes += p.total_length() as u64;
+
+            // the packet is freed once the device returns its descriptor chain
+            let p = packet::PacketBox::into_raw(p);
+            let bufs = unsafe { &*p }.segments().map(|seg| {
+                (
+                    memory::virtual_to_physical(seg.data.as_ptr()),
+                    u32::from(seg.length),
+                    0,
+                )
+            });
+            queue.add(bufs, p);
+            sent += 1;
+        }
+
+        if sent > 0 && queue.publish() {
+            self.notify(VIRTIO_NET_TX_QUEUE);
+        }
+        self.stats.set(stats);
+
+        sent
+    }
+
+    /// Reads the stats of this device into `stats`.
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        let counted = self.stats.replace(Default::default());
+        stats.rx_pkts += counted.rx_pkts;
+        stats.tx_pkts += counted.tx_pkts;
+        stats.rx_bytes += counted.rx_bytes;
+        stats.tx_bytes += counted.tx_bytes;
+        stats.tx_dropped += counted.tx_dropped;
+        stats.rx_queue_pkts[0] += counted.rx_pkts;
+        stats.tx_queue_pkts[0] += counted.tx_pkts;
+    }
+
+    /// Resets the stats of this device.
+    fn reset_stats(&mut self) {
+        self.stats.set(Default::default());
+    }
+
+    /// Returns the number of packet buffers held in the rx and tx queues of this device.
+    fn get_bufs_in_use(&self) -> usize {
+        let rx = self.rx_queue.as_ref().map_or(0, |queue| queue.in_flight);
+        let tx = self.tx_queue.as_ref().map_or(0, |queue| {
+            queue
+                .bufs_in_use
+                .iter()
+                .filter(|p| !p.is_null())
+                .map(|&p| unsafe { &*p }.nsegments())
+                .sum::<usize>()
+        });
+        rx + tx
+    }
+
+    /// Returns the link speed of this device.
+    ///
+    /// Virtio devices do not have a link speed, we report 1 Gbit/s if the link is up.
+    fn get_link_speed(&self) -> u16 {
+        if self.features & (1 << VIRTIO_NET_F_STATUS) != 0 {
+            let status = self.read16(VIRTIO_PCI_CONFIG + VIRTIO_NET_CONFIG_STATUS);
+            if status & VIRTIO_NET_S_LINK_UP == 0 {
+                return 0;
+            }
+        }
+        1000
+    }
+
+    /// Resets this device, returns the packets held in its queues to the freelist (and the queues
+    /// to the DMA memory allocator), and binds the device to the kernel `driver`.
+    fn release(&mut self, driver: Option<&str>) -> Result<(), Box<dyn Error>> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+
+        // the device stops using the queues once it is reset
+        self.write8(VIRTIO_PCI_STATUS, VIRTIO_CONFIG_STATUS_RESET);
+        for mut queue in self.rx_queue.take().into_iter().chain(self.tx_queue.take()) {
+            queue.free_bufs();
+            queue.free_memory();
+        }
+        if let Some((queue, command)) = self.ctrl_queue.take() {
+            queue.free_memory();
+            memory::dma_free(command, COMMAND_SIZE, COMMAND_SIZE);
+        }
+        self.rx_packets.clear();
+
+        if let Some(driver) = driver {
+            bind_driver(&self.pci_addr, driver)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for VirtioDevice {
+    fn drop(&mut self) {
+        let _ = self.release(None);
+    }
+}
+
+/// Adds a receive buffer (the packet `p`) to the rx `queue`.
+fn add_rx_buffer(queue: &mut Virtqueue, p: packet::PacketBox) {
+    let addr = memory::virtual_to_physical(p.data.as_ptr());
+    let len = p.data.len() as u32;
+    queue.add(
+        std::iter::once((addr, len, VRING_DESC_F_WRITE)),
+        packet::PacketBox::into_raw(p),
+    );
+}
+
+impl VirtioDevice {
+    /// Resets and initializes this device.
+    fn reset_and_init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write8(VIRTIO_PCI_STATUS, VIRTIO_CONFIG_STATUS_RESET);
+        let time = Instant::now();
+        while self.read8(VIRTIO_PCI_STATUS) != VIRTIO_CONFIG_STATUS_RESET {
+            if time.elapsed().as_secs() > 1 {
+                return Err("virtio device reset timed out".into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.write8(VIRTIO_PCI_STATUS, VIRTIO_CONFIG_STATUS_ACK);
+        self.write8(
+            VIRTIO_PCI_STATUS,
+            VIRTIO_CONFIG_STATUS_ACK | VIRTIO_CONFIG_STATUS_DRIVER,
+        );
+
+        // negotiate features
+        let host_features = self.read32(VIRTIO_PCI_HOST_FEATURES);
+        self.features = host_features & DRIVER_FEATURES;
+        self.write32(VIRTIO_PCI_GUEST_FEATURES, self.features);
+
+        // set up queues
+        let result = self.init_queues();
+        if result.is_err() {
+            self.write8(VIRTIO_PCI_STATUS, VIRTIO_CONFIG_STATUS_FAILED);
+            return result;
+        }
+
+        self.write8(
+            VIRTIO_PCI_STATUS,
+            VIRTIO_CONFIG_STATUS_ACK | VIRTIO_CONFIG_STATUS_DRIVER | VIRTIO_CONFIG_STATUS_DRIVER_OK,
+        );
+
+        // rx queue starts out full
+        let queue = self.rx_queue.as_mut().unwrap();
+        while queue.nfree() >= 2 {
+            let p = packet::try_allocate().ok_or("packet pool exhausted filling the rx queue")?;
+            add_rx_buffer(queue, p);
+        }
+        queue.publish();
+        self.notify(VIRTIO_NET_RX_QUEUE);
+
+        // enable promisc mode by default to make testing easier
+        if self.features & (1 << VIRTIO_NET_F_CTRL_RX) != 0 {
+            self.set_promisc(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets up the rx, tx, and (if available) control queues of this device.
+    fn init_queues(&mut self) -> Result<(), Box<dyn Error>> {
+        let rx_queue = self.init_queue(VIRTIO_NET_RX_QUEUE, true)?;
+        self.rx_packets = Vec::with_capacity(rx_queue.size);
+        self.rx_queue = Some(rx_queue);
+        self.tx_queue = Some(self.init_queue(VIRTIO_NET_TX_QUEUE, true)?);
+        if self.features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            let queue = self.init_queue(VIRTIO_NET_CTRL_QUEUE, false)?;
+            let command = memory::try_dma_alloc(COMMAND_SIZE, COMMAND_SIZE)?;
+            self.ctrl_queue = Some((queue, command));
+        }
+        Ok(())
+    }
+
+    /// Allocates the virtqueue `index` and hands it to the device.
+    fn init_queue(&self, index: u16, headers: bool) -> Result<Virtqueue, Box<dyn Error>> {
+        self.write16(VIRTIO_PCI_QUEUE_SEL, index);
+        let size = self.read16(VIRTIO_PCI_QUEUE_NUM) as usize;
+        if size == 0 {
+            return Err(format!("virtio queue {} does not exist", index).into());
+        }
+        if size & (size - 1) != 0 {
+            return Err("number of queue entries must be a power of 2".into());
+        }
+
+        let ring_size_bytes = vring_size(size);
+        let dma_virt = memory::try_dma_alloc(ring_size_bytes, VIRTIO_PCI_VRING_ALIGN)?;
+        let dma_phys = memory::virtual_to_physical(dma_virt);
+        unsafe { ptr::write_bytes(dma_virt, 0, ring_size_bytes) };
+
+        let headers = if headers {
+            let headers_size = size * mem::size_of::<VirtioNetHdr>();
+            let headers = memory::try_dma_alloc(headers_size, 8)?;
+            Some((
+                headers as *mut VirtioNetHdr,
+                memory::virtual_to_physical(headers),
+            ))
+        } else {
+            None
+        };
+
+        let queue = Virtqueue::new(index, size, dma_virt, headers);
+        self.write32(
+            VIRTIO_PCI_QUEUE_PFN,
+            (dma_phys >> VIRTIO_PCI_QUEUE_ADDR_SHIFT) as u32,
+        );
+
+        Ok(queue)
+    }
+
+    /// Enables or disables promisc mode of this device.
+    pub fn set_promisc(&mut self, enabled: bool) -> Result<(), Box<dyn Error>> {
+        if self.features & (1 << VIRTIO_NET_F_CTRL_RX) == 0 {
+            // without rx mode control the device is never promisc
+            return if enabled {
+                Err("virtio device does not support promisc mode".into())
+            } else {
+                Ok(())
+            };
+        }
+        let (queue, command) = self
+            .ctrl_queue
+            .as_mut()
+            .ok_or("virtio device has no control queue")?;
+        let command = *command;
+        let phys = memory::virtual_to_physical(command);
+        unsafe {
+            ptr::write_volatile(command, VIRTIO_NET_CTRL_RX);
+            ptr::write_volatile(command.add(1), VIRTIO_NET_CTRL_RX_PROMISC);
+            ptr::write_volatile(command.add(2), enabled as u8);
+            ptr::write_volatile(command.add(3), !VIRTIO_NET_OK);
+        }
+        let bufs = vec![
+            (phys, 2, 0),
+            (phys + 2, 1, 0),
+            (phys + 3, 1, VRING_DESC_F_WRITE),
+        ];
+        queue.add(bufs.into_iter(), packet::null_mut());
+        let index = queue.index;
+        if queue.publish() {
+            self.notify(index);
+        }
+
+        let (queue, _) = self.ctrl_queue.as_mut().unwrap();
+        let time = Instant::now();
+        while queue.pop_used().is_none() {
+            if time.elapsed().as_secs() > 1 {
+                return Err("virtio control command timed out".into());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        match unsafe { ptr::read_volatile(command.add(3)) } {
+            VIRTIO_NET_OK => Ok(()),
+            _ => Err("virtio control command failed".into()),
+        }
+    }
+
+    /// Notifies the device of new buffers on queue `index`.
+    fn notify(&self, index: u16) {
+        self.write16(VIRTIO_PCI_QUEUE_NOTIFY, index);
+    }
+
+    fn read8(&self, offset: u64) -> u8 {
+        let mut buf = [0; 1];
+        self.read(&mut buf, offset);
+        buf[0]
+    }
+
+    fn read16(&self, offset: u64) -> u16 {
+        let mut buf = [0; 2];
+        self.read(&mut buf, offset);
+        u16::from_ne_bytes(buf)
+    }
+
+    fn read32(&self, offset: u64) -> u32 {
+        let mut buf = [0; 4];
+        self.read(&mut buf, offset);
+        u32::from_ne_bytes(buf)
+    }
+
+    fn write8(&self, offset: u64, value: u8) {
+        self.write(&[value], offset);
+    }
+
+    fn write16(&self, offset: u64, value: u16) {
+        self.write(&value.to_ne_bytes(), offset);
+    }
+
+    fn write32(&self, offset: u64, value: u32) {
+        self.write(&value.to_ne_bytes(), offset);
+    }
+
+    /// Reads from the I/O BAR at `offset` (one access of the size of `buf`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the access fails.
+    fn read(&self, buf: &mut [u8], offset: u64) {
+        self.bar
+            .read_exact_at(buf, offset)
+            .unwrap_or_else(|e| panic!("failed to read virtio register {:#x}: {}", offset, e));
+    }
+
+    /// Writes `buf` to the I/O BAR at `offset` (one access of the size of `buf`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the access fails.
+    fn write(&self, buf: &[u8], offset: u64) {
+        self.bar
+            .write_all_at(buf, offset)
+            .unwrap_or_else(|e| panic!("failed to write virtio register {:#x}: {}", offset, e));
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+
+    use std::alloc::{self, Layout};
+
+    #[test]
+    fn virtqueue() {
+        let size = 8;
+        let layout = Layout::from_size_align(vring_size(size), VIRTIO_PCI_VRING_ALIGN).unwrap();
+        let mem = unsafe { alloc::alloc_zeroed(layout) };
+        let mut headers: Vec<VirtioNetHdr> = (0..size).map(|_| Default::default()).collect();
+        let mut queue = Virtqueue::new(1, size, mem, Some((headers.as_mut_ptr(), 0x1000)));
+
+        // a two segment packet takes up three descriptors
+        let p = packet::PacketBox::into_raw(packet::allocate());
+        let bufs = vec![(0x2000, 100, 0), (0x3000, 50, 0)];
+        queue.add(bufs.into_iter(), p);
+        assert_eq!(queue.nfree(), size - 3);
+        assert!(queue.publish());
+        assert!(!queue.has_used());
+        unsafe {
+            assert_eq!(ptr::read_volatile(queue.avail.add(1)), 1);
+            let head = *queue.avail.add(2) as usize;
+            let desc = &*queue.desc.add(head);
+            assert_eq!((desc.addr, desc.len), (0x1000 + head as u64 * 10, 10));
+            assert_eq!(desc.flags, VRING_DESC_F_NEXT);
+            let desc = &*queue.desc.add(desc.next as usize);
+            assert_eq!((desc.addr, desc.len), (0x2000, 100));
+            assert_eq!(desc.flags, VRING_DESC_F_NEXT);
+            let desc = &*queue.desc.add(desc.next as usize);
+            assert_eq!((desc.addr, desc.len, desc.flags), (0x3000, 50, 0));
+
+            // the device returns the chain
+            let ring = queue.used.add(2) as *mut VirtqUsedElem;
+            ptr::write(
+                ring,
+                VirtqUsedElem {
+                    id: head as u32,
+                    len: 10 + 150,
+                },
+            );
+            ptr::write_volatile(queue.used.add(1), 1);
+        }
+        let (q, len) = queue.pop_used().unwrap();
+        assert_eq!((q, len), (p, 150));
+        assert_eq!(queue.nfree(), size);
+        assert_eq!(queue.in_flight, 0);
+        assert!(queue.pop_used().is_none());
+        packet::free(unsafe { packet::PacketBox::from_raw(q) });
+        unsafe { alloc::dealloc(mem, layout) };
+    }
+}
@@ -0,0 +1,52 @@
+// Constants of the legacy virtio PCI interface (virtio 0.9.5) and virtio-net.
+
+// Legacy PCI I/O BAR register offsets
+pub const VIRTIO_PCI_HOST_FEATURES: u64 = 0x00; // features supported by the device (u32)
+pub const VIRTIO_PCI_GUEST_FEATURES: u64 = 0x04; // features activated by the driver (u32)
+pub const VIRTIO_PCI_QUEUE_PFN: u64 = 0x08; // page frame number of the selected queue (u32)
+pub const VIRTIO_PCI_QUEUE_NUM: u64 = 0x0c; // size of the selected queue (u16)
+pub const VIRTIO_PCI_QUEUE_SEL: u64 = 0x0e; // queue selector (u16)
+pub const VIRTIO_PCI_QUEUE_NOTIFY: u64 = 0x10; // queue notifier (u16)
+pub const VIRTIO_PCI_STATUS: u64 = 0x12; // device status (u8)
+pub const VIRTIO_PCI_ISR: u64 = 0x13; // interrupt status (u8)
+pub const VIRTIO_PCI_CONFIG: u64 = 0x14; // device specific configuration (without MSI-X)
+
+// Device status bits
+pub const VIRTIO_CONFIG_STATUS_RESET: u8 = 0x00;
+pub const VIRTIO_CONFIG_STATUS_ACK: u8 = 0x01;
+pub const VIRTIO_CONFIG_STATUS_DRIVER: u8 = 0x02;
+pub const VIRTIO_CONFIG_STATUS_DRIVER_OK: u8 = 0x04;
+pub const VIRTIO_CONFIG_STATUS_FAILED: u8 = 0x80;
+
+// Queue PFNs are in units of 4 KiB pages
+pub const VIRTIO_PCI_QUEUE_ADDR_SHIFT: u64 = 12;
+pub const VIRTIO_PCI_VRING_ALIGN: usize = 4096;
+
+// virtio-net feature bits
+pub const VIRTIO_NET_F_MAC: u32 = 5; // device has given MAC address
+pub const VIRTIO_NET_F_STATUS: u32 = 16; // configuration status field is available
+pub const VIRTIO_NET_F_CTRL_VQ: u32 = 17; // control channel is available
+pub const VIRTIO_NET_F_CTRL_RX: u32 = 18; // control channel RX mode support
+
+// virtio-net configuration (offsets relative to VIRTIO_PCI_CONFIG)
+pub const VIRTIO_NET_CONFIG_MAC: u64 = 0x00; // MAC address (6 bytes)
+pub const VIRTIO_NET_CONFIG_STATUS: u64 = 0x06; // link status (u16)
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// virtio-net queue indices
+pub const VIRTIO_NET_RX_QUEUE: u16 = 0;
+pub const VIRTIO_NET_TX_QUEUE: u16 = 1;
+pub const VIRTIO_NET_CTRL_QUEUE: u16 = 2;
+
+// Virtqueue descriptor flags
+pub const VRING_DESC_F_NEXT: u16 = 1; // buffer continues via the next field
+pub const VRING_DESC_F_WRITE: u16 = 2; // buffer is write-only (for the device)
+
+// Virtqueue ring flags
+pub const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1; // driver does not want interrupts
+pub const VRING_USED_F_NO_NOTIFY: u16 = 1; // device does not want notifications
+
+// Control virtqueue commands
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+pub const VIRTIO_NET_OK: u8 = 0;
@@ -1,90 +1,217 @@
 use super::engine;
+use super::ethernet;
 use super::ixy82599;
 use super::lib;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::time::Duration;
 
 // Ixy82599 app: drive an Intel 82599 network adapter
+//
+//   pci: PCI address of the adapter
+//   rxq, txq: receive and transmit queue to use (other queues can be used by other
+//     app instances, e.g. in other processes)
+//   rss: receive side scaling configuration (distribution of received packets to the
+//     receive queues), applied by the first app instance to use the adapter
+//   filters: Flow Director filters steering (or dropping) flows to receive queues,
+//     installed by the first app instance to use the adapter
+//   macaddr: MAC address to set (optional, defaults to the address in the EEPROM),
+//     applied by the first app instance to use the adapter
+//   promisc: receive packets regardless of their destination address (default true),
+//     applied by the first app instance to use the adapter
+//   ring_size: number of descriptors of the receive and transmit queue (default 512,
+//     a power of 2 from 64 to 4096)
+//   mtu: largest IP packet that can be received (default 1500), applied by the first
+//     app instance to use the adapter
+//   wait_for_link: time to wait for the link to come up (default 10s, None to not wait)
+//   vlan_strip: strip the VLAN tags of received packets into their metadata (default
+//     false)
+//   vlan_insert: insert the VLAN tags of transmitted packets from their metadata
+//     (default false)
+//   vlan_filter: VLAN IDs to receive tagged packets of (default empty, i.e., all),
+//     applied by the first app instance to use the adapter
+//   input, output: names of the input and output ports (default "input" and "output")
+//   driver: kernel driver to bind the adapter to when the last app instance using it
+//     is stopped (optional)
+//
+// Invalid configurations (e.g., queues or a ring size the adapter does not support)
+// make the app fail to initialize the adapter. The error is printed (and reported),
+// and the app neither receives nor transmits packets.
+//
+// The app needs to run as root, also when the adapter is bound to vfio-pci: DMA
+// memory is allocated from a hugetlbfs mounted by the app, and the adapter uses
+// physical addresses unless it is bound to vfio-pci.
+//
+// The app reports the adapter's statistics counters (packets and bytes, errors,
+// broadcast, multicast and pause frames, link flaps, packets per queue) since the
+// last report. If the adapter is shared, packets and bytes are those of the app's
+// queues, and the adapter-wide counters are reported by the app using rxq 0.
 
 #[derive(Clone, Debug)]
 pub struct Ixy82599 {
     pub pci: String,
+    pub rxq: u16,
+    pub txq: u16,
+    pub rss: ixy82599::RssConfig,
+    pub filters: Vec<ixy82599::FlowFilter>,
+    pub macaddr: Option<ethernet::MacAddress>,
+    pub promisc: bool,
+    pub ring_size: usize,
+    pub mtu: u16,
+    pub wait_for_link: Option<Duration>,
+    pub vlan_strip: bool,
+    pub vlan_insert: bool,
+    pub vlan_filter: Vec<u16>,
+    pub input: String,
+    pub output: String,
+    pub driver: Option<String>,
+}
+impl Default for Ixy82599 {
+    fn default() -> Ixy82599 {
+        let device = ixy82599::DeviceConfig::default();
+        Ixy82599 {
+            pci: String::new(),
+            rxq: device.rxq,
+            txq: device.txq,
+            rss: device.rss,
+            filters: device.filters,
+            macaddr: device.macaddr,
+            promisc: device.promisc,
+            ring_size: device.ring_size,
+            mtu: device.mtu,
+            wait_for_link: device.wait_for_link,
+            vlan_strip: device.vlan_strip,
+            vlan_insert: device.vlan_insert,
+            vlan_filter: device.vlan_filter,
+            input: String::from("input"),
+            output: String::from("output"),
+            driver: None,
+        }
+    }
 }
 impl engine::AppConfig for Ixy82599 {
     fn new(&self) -> Box<dyn engine::App> {
-        assert!(
-            unsafe { libc::getuid() } == 0,
-            "Need to be root to drive PCI devices"
-        );
-        let ixy = ixy82599::ixy_init(&self.pci, 1, 1, 0).unwrap();
-        Box::new(Ixy82599App {
+        match self.init() {
+            Ok(app) => Box::new(app),
+            Err(error) => {
+                println!("Ixy82599 failed to initialize {}: {}", self.pci, error);
+                Box::new(Ixy82599Failed {
+                    pci: self.pci.clone(),
+                    error: error.to_string(),
+                })
+            }
+        }
+    }
+}
+impl Ixy82599 {
+    fn init(&self) -> Result<Ixy82599App, Box<dyn Error>> {
+        if unsafe { libc::getuid() } != 0 {
+            return Err("need to be root to drive PCI devices".into());
+        }
+        let device = ixy82599::DeviceConfig {
+            rxq: self.rxq,
+            txq: self.txq,
+            rss: self.rss.clone(),
+            filters: self.filters.clone(),
+            macaddr: self.macaddr,
+            promisc: self.promisc,
+            ring_size: self.ring_size,
+            mtu: self.mtu,
+            wait_for_link: self.wait_for_link,
+            vlan_strip: self.vlan_strip,
+            vlan_insert: self.vlan_insert,
+            vlan_filter: self.vlan_filter.clone(),
+        };
+        let ixy = ixy82599::ixy_init_queue(&self.pci, &device)?;
+        Ok(Ixy82599App {
+            conf: self.clone(),
             ixy: RefCell::new(ixy),
             stats: RefCell::new(Default::default()),
+            reported: Cell::new(Default::default()),
         })
     }
 }
 pub struct Ixy82599App {
+    conf: Ixy82599,
     ixy: RefCell<Box<dyn ixy82599::IxyDevice>>,
+    // device stats accumulated since the app was started, and at the last report
     stats: RefCell<Box<ixy82599::DeviceStats>>,
+    reported: Cell<ixy82599::DeviceStats>,
+}
+impl Ixy82599App {
+    // Returns the device stats accumulated since the app was started.
+    pub fn stats(&self) -> ixy82599::DeviceStats {
+        let mut stats = self.stats.borrow_mut();
+        self.ixy.borrow().read_stats(&mut stats);
+        **stats
+    }
 }
 impl engine::App for Ixy82599App {
     fn has_pull(&self) -> bool {
         true
     }
     fn pull(&self, app: &engine::AppState) {
-        if let Some(output) = app.output.get("output") {
+        if let Some(output) = app.output.get(&self.conf.output) {
             let mut output = output.borrow_mut();
             let mut ixy = self.ixy.borrow_mut();
-            ixy.rx_batch(0, &mut output, engine::PULL_NPACKETS);
+            // leave packets in the receive ring if there is no room downstream
+            let npackets = engine::pull_npackets(&output);
+            ixy.rx_batch(u32::from(self.conf.rxq), &mut output, npackets);
         }
     }
     fn has_push(&self) -> bool {
         true
     }
     fn push(&self, app: &engine::AppState) {
-        if let Some(input) = app.input.get("input") {
+        if let Some(input) = app.input.get(&self.conf.input) {
             let mut input = input.borrow_mut();
             let mut ixy = self.ixy.borrow_mut();
-            ixy.tx_batch(0, &mut input);
+            ixy.tx_batch(u32::from(self.conf.txq), &mut input);
         }
     }
     fn has_report(&self) -> bool {
         true
     }
     fn report(&self) {
-        let ixy = self.ixy.borrow_mut();
-        let mut stats = self.stats.borrow_mut();
-        let last_rx_pkts = stats.rx_pkts;
-        let last_rx_bytes = stats.rx_bytes;
-        let last_tx_pkts = stats.tx_pkts;
-        let last_tx_bytes = stats.tx_bytes;
-        ixy.read_stats(&mut stats);
+        let stats = self.stats();
         println!(
             "  Device stats for {} since last report:",
-            ixy.get_pci_addr()
-        );
-        println!(
-            "     rxpackets:\t{:10}",
-            lib::comma_value(stats.rx_pkts - last_rx_pkts)
-        );
-        println!(
-            "     rxbytes:\t{:10}",
-            lib::comma_value(stats.rx_bytes - last_rx_bytes)
-        );
-        println!(
-            "     txpackets:\t{:10}",
-            lib::comma_value(stats.tx_pkts - last_tx_pkts)
-        );
-        println!(
-            "     txbytes:\t{:10}",
-            lib::comma_value(stats.tx_bytes - last_tx_bytes)
+            self.ixy.borrow().get_pci_addr()
         );
+        for (name, value) in stats.since(&self.reported.replace(stats)).counters() {
+            println!("     {}:\t{:10}", name, lib::comma_value(value));
+        }
+    }
+    fn held_packets(&self) -> usize {
+        self.ixy.borrow().get_bufs_in_use()
     }
     fn has_stop(&self) -> bool {
         true
     }
     fn stop(&self) {
-        unimplemented!("");
+        let mut ixy = self.ixy.borrow_mut();
+        if let Err(error) = ixy.release(self.conf.driver.as_deref()) {
+            println!(
+                "Ixy82599 failed to release {}: {}",
+                ixy.get_pci_addr(),
+                error
+            );
+        }
+    }
+}
+
+// Stands in for an Ixy82599App whose adapter failed to initialize.
+pub struct Ixy82599Failed {
+    pci: String,
+    error: String,
+}
+impl engine::App for Ixy82599Failed {
+    fn has_report(&self) -> bool {
+        true
+    }
+    fn report(&self) {
+        println!("  Device {} failed to initialize: {}", self.pci, self.error);
     }
 }
 
@@ -122,8 +249,22 @@ mod selftest {
         }
 
         let mut c = config::new();
-        config::app(&mut c, "nic0", &Ixy82599 { pci: nic0 });
-        config::app(&mut c, "nic1", &Ixy82599 { pci: nic1 });
+        config::app(
+            &mut c,
+            "nic0",
+            &Ixy82599 {
+                pci: nic0,
+                ..Default::default()
+            },
+        );
+        config::app(
+            &mut c,
+            "nic1",
+            &Ixy82599 {
+                pci: nic1,
+                ..Default::default()
+            },
+        );
         config::app(
             &mut c,
             "source",
@@ -148,6 +289,21 @@ mod selftest {
         }
     }
 
+    #[test]
+    fn init_error() {
+        // the app reports initialization errors instead of panicking
+        let mut c = config::new();
+        let nic = Ixy82599 {
+            pci: String::from("0000:ff:1f.7"),
+            ring_size: 100,
+            ..Default::default()
+        };
+        config::app(&mut c, "nic", &nic);
+        engine::configure(&c);
+        engine::report_apps();
+        engine::configure(&config::new());
+    }
+
     #[derive(Clone, Debug)]
     pub struct PacketGen {
         pub dst: String,
@@ -166,7 +322,7 @@ mod selftest {
         }
     }
     pub struct PacketGenApp {
-        packet: Box<packet::Packet>,
+        packet: packet::PacketBox,
     }
     impl engine::App for PacketGenApp {
         fn has_pull(&self) -> bool {
@@ -176,7 +332,10 @@ mod selftest {
             if let Some(output) = app.output.get("output") {
                 let mut output = output.borrow_mut();
                 while !link::full(&output) {
-                    link::transmit(&mut output, packet::clone(&self.packet));
+                    if let Some(p) = link::transmit(&mut output, packet::clone(&self.packet)) {
+                        packet::free(p);
+                        break;
+                    }
                 }
             }
         }
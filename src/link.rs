@@ -4,30 +4,71 @@
 // implemented as circular ring buffers, and link operations.
 //
 //   Link - opaque link structure
-//   LINK_MAX_PACKETS - capacity of a Link
-//   new() -> Link - allocate a new empty Link
+//   DropPolicy - what to do with packets transmitted on a full Link
+//   LINK_RING_SIZE - default ring size of a Link
+//   LINK_MAX_PACKETS - capacity of a Link (with the default ring size)
+//   new() -> Link - allocate a new empty Link (with default options)
+//   with_options(size, DropPolicy) -> Link - allocate a new empty Link
+//   capacity(&Link) -> usize - maximum number of packets on the Link
 //   full(&Link) -> bool - predicate to test if Link is full
 //   empty(&Link) -> bool - predicate to test if Link is empty
-//   receive(&mut Link) -> Box<Packet> - dequeue a packet from the Link
-//   transmit(&mut Link, Box<Packet>) - enqueue a packet on the Link
+//   nreadable(&Link) -> usize - number of packets enqueued on the Link
+//   nwritable(&Link) -> usize - number of packets that can be enqueued
+//   front(&Link) -> &Packet - the packet that would be dequeued next
+//   receive(&mut Link) -> PacketBox - dequeue a packet from the Link
+//   transmit(&mut Link, PacketBox) -> Option<PacketBox> - enqueue a packet
+//   receive_batch(&mut Link, &mut Vec<PacketBox>, max) -> n - dequeue packets
+//   transmit_batch(&mut Link, &mut Vec<PacketBox>) - enqueue packets
+//   set_tap(&mut Link, Option<Tap>) -> Option<Tap> - attach/detach a tap
+//   tap(&Link) -> Option<&Tap> - the attached tap
+//   set_histograms(&mut Link, bool) - enable/disable occupancy and dwell time
+//     histograms
+//   sample_occupancy(&mut Link) - record the Link’s occupancy (if enabled)
+//   occupancy_histogram(&Link) -> Option<&Histogram> - occupancy samples
+//   dwell_histogram(&Link) -> Option<&Histogram> - dwell time in nanoseconds
 
+use super::histogram::{self, Histogram};
 use super::packet;
+use super::tap;
 
-// Size of the ring buffer.
-const LINK_RING_SIZE: usize = 1024;
+use once_cell::sync::Lazy;
+use std::cmp;
+use std::mem;
+use std::time::Instant;
 
-// Capacity of a Link.
+// Default size of the ring buffer.
+pub const LINK_RING_SIZE: usize = 1024;
+
+// Capacity of a Link with the default ring size.
 pub const LINK_MAX_PACKETS: usize = LINK_RING_SIZE - 1;
 
+// Policy for packets transmitted on a full Link:
+//   TailDrop: drop the transmitted packet
+//   HeadDrop: drop the oldest packet on the link to make room
+//   Refuse: hand the packet back to the sender (e.g., to apply backpressure)
+// Dropped packets are counted in txdrop, refused packets are not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    TailDrop,
+    HeadDrop,
+    Refuse,
+}
+
 pub struct Link {
     // this is a circular ring buffer, as described at:
     //   http://en.wikipedia.org/wiki/Circular_buffer
-    packets: [*mut packet::Packet; LINK_RING_SIZE],
+    packets: Vec<*mut packet::Packet>,
+    mask: i32, // ring size - 1 (ring size is a power of two)
+    policy: DropPolicy,
     // Two cursors:
     //   read:  the next element to be read
     //   write: the next element to be written
     read: i32,
     write: i32,
+    // Optional tap capturing transmitted packets
+    tap: Option<Box<tap::Tap>>,
+    // Optional occupancy and dwell time histograms
+    histograms: Option<Box<Histograms>>,
     // Link stats:
     pub txpackets: u64,
     pub txbytes: u64,
@@ -36,13 +77,25 @@ pub struct Link {
     pub rxbytes: u64,
 }
 
-const SIZE: i32 = LINK_RING_SIZE as i32; // shorthand
-
 pub fn new() -> Link {
+    with_options(LINK_RING_SIZE, DropPolicy::TailDrop)
+}
+
+// Allocate a link with a ring of size slots (a power of two), i.e. a capacity
+// of size - 1 packets.
+pub fn with_options(size: usize, policy: DropPolicy) -> Link {
+    assert!(
+        size >= 2 && size.is_power_of_two() && size <= i32::MAX as usize,
+        "Link size must be a power of two"
+    );
     Link {
-        packets: [std::ptr::null_mut(); LINK_RING_SIZE],
+        packets: vec![packet::null_mut(); size],
+        mask: (size - 1) as i32,
+        policy,
         read: 0,
         write: 0,
+        tap: None,
+        histograms: None,
         txpackets: 0,
         txbytes: 0,
         txdrop: 0,
@@ -51,44 +104,211 @@ pub fn new() -> Link {
     }
 }
 
+pub fn capacity(r: &Link) -> usize {
+    r.mask as usize
+}
+
 pub fn empty(r: &Link) -> bool {
     r.read == r.write
 }
 
 pub fn full(r: &Link) -> bool {
-    (r.write + 1) & (SIZE - 1) == r.read
+    (r.write + 1) & r.mask == r.read
+}
+
+pub fn nreadable(r: &Link) -> usize {
+    ((r.write - r.read) & r.mask) as usize
+}
+
+pub fn nwritable(r: &Link) -> usize {
+    capacity(r) - nreadable(r)
+}
+
+pub fn front(r: &Link) -> &packet::Packet {
+    assert!(!empty(r), "Link underflow.");
+    unsafe { &*r.packets[r.read as usize] }
 }
 
 // NB: non-empty assertion commented out in original Snabb, but since we get a
 // bunch of nice safety invariants from the Rust compiler, let’s maintain them.
-// Box::from_raw will never alias because receive/transmit ensure any Packet is
-// either on a single Link, or on no Link at all.
-pub fn receive(r: &mut Link) -> Box<packet::Packet> {
+// PacketBox::from_raw will never alias because receive/transmit ensure any
+// Packet is either on a single Link, or on no Link at all.
+pub fn receive(r: &mut Link) -> packet::PacketBox {
     assert!(!empty(r), "Link underflow.");
-    let p = unsafe { Box::from_raw(r.packets[r.read as usize]) };
-    r.read = (r.read + 1) & (SIZE - 1);
+    let p = unsafe { packet::PacketBox::from_raw(r.packets[r.read as usize]) };
+    if let Some(histograms) = &mut r.histograms {
+        histograms.dequeue(r.read as usize, clock());
+    }
+    r.read = (r.read + 1) & r.mask;
     r.rxpackets += 1;
-    r.rxbytes += p.length as u64;
+    r.rxbytes += p.total_length() as u64;
     p
 }
 
+// Enqueue p, returns p if the link is full and refuses it (see DropPolicy).
 #[inline(always)]
-pub fn transmit(r: &mut Link, mut p: Box<packet::Packet>) {
+#[must_use]
+pub fn transmit(r: &mut Link, p: packet::PacketBox) -> Option<packet::PacketBox> {
     if full(r) {
-        r.txdrop += 1;
-        packet::free(p);
+        match r.policy {
+            DropPolicy::TailDrop => {
+                r.txdrop += 1;
+                packet::free(p);
+                return None;
+            }
+            DropPolicy::HeadDrop => drop_head(r),
+            DropPolicy::Refuse => return Some(p),
+        }
+    }
+    if let Some(tap) = &mut r.tap {
+        tap.capture(&p);
+    }
+    if let Some(histograms) = &mut r.histograms {
+        histograms.enqueued[r.write as usize] = clock();
+    }
+    r.txpackets += 1;
+    r.txbytes += p.total_length() as u64;
+    r.packets[r.write as usize] = packet::PacketBox::into_raw(p);
+    r.write = (r.write + 1) & r.mask;
+    None
+}
+
+// Drop the oldest packet on the link (not counted as received).
+fn drop_head(r: &mut Link) {
+    let p = unsafe { packet::PacketBox::from_raw(r.packets[r.read as usize]) };
+    r.read = (r.read + 1) & r.mask;
+    r.txdrop += 1;
+    packet::free(p);
+}
+
+// Dequeue up to max packets and append them to packets, return the number of
+// packets dequeued.
+// NB: batch operations update the link counters once per batch.
+pub fn receive_batch(r: &mut Link, packets: &mut Vec<packet::PacketBox>, max: usize) -> usize {
+    let n = cmp::min(max, nreadable(r));
+    let now = if r.histograms.is_some() { clock() } else { 0 };
+    let mut bytes = 0;
+    packets.reserve(n);
+    for _ in 0..n {
+        let p = unsafe { packet::PacketBox::from_raw(r.packets[r.read as usize]) };
+        if let Some(histograms) = &mut r.histograms {
+            histograms.dequeue(r.read as usize, now);
+        }
+        r.read = (r.read + 1) & r.mask;
+        bytes += p.total_length() as u64;
+        packets.push(p);
+    }
+    r.rxpackets += n as u64;
+    r.rxbytes += bytes;
+    n
+}
+
+// Enqueue packets. Like transmit, packets that do not fit onto the link are
+// handled according to the link’s DropPolicy: refused packets remain in
+// packets, otherwise the vector is left empty.
+pub fn transmit_batch(r: &mut Link, packets: &mut Vec<packet::PacketBox>) {
+    let n = match r.policy {
+        DropPolicy::HeadDrop => {
+            // only the last capacity packets can survive
+            let excess = packets.len().saturating_sub(capacity(r));
+            r.txdrop += excess as u64;
+            packets.drain(..excess);
+            for _ in nwritable(r)..packets.len() {
+                drop_head(r);
+            }
+            packets.len()
+        }
+        _ => cmp::min(packets.len(), nwritable(r)),
+    };
+    let now = if r.histograms.is_some() { clock() } else { 0 };
+    let mut bytes = 0;
+    for p in packets.drain(..n) {
+        if let Some(tap) = &mut r.tap {
+            tap.capture(&p);
+        }
+        if let Some(histograms) = &mut r.histograms {
+            histograms.enqueued[r.write as usize] = now;
+        }
+        bytes += p.total_length() as u64;
+        r.packets[r.write as usize] = packet::PacketBox::into_raw(p);
+        r.write = (r.write + 1) & r.mask;
+    }
+    r.txpackets += n as u64;
+    r.txbytes += bytes;
+    if r.policy == DropPolicy::TailDrop {
+        r.txdrop += packets.len() as u64;
+        packets.clear();
+    }
+}
+
+// Attach tap to the link (or detach the current tap if tap is None), return
+// the previously attached tap. Packets enqueued on the link are captured by
+// the tap.
+pub fn set_tap(r: &mut Link, tap: Option<tap::Tap>) -> Option<tap::Tap> {
+    mem::replace(&mut r.tap, tap.map(Box::new)).map(|tap| *tap)
+}
+
+pub fn tap(r: &Link) -> Option<&tap::Tap> {
+    r.tap.as_deref()
+}
+
+// Occupancy and dwell time histograms of a link.
+struct Histograms {
+    occupancy: Histogram, // number of packets on the link (sampled)
+    dwell: Histogram,     // time between enqueue and dequeue (nanoseconds)
+    enqueued: Vec<u64>,   // enqueue timestamps (parallel to packets)
+}
+
+impl Histograms {
+    #[inline(always)]
+    fn dequeue(&mut self, index: usize, now: u64) {
+        self.dwell.add(now.saturating_sub(self.enqueued[index]));
+    }
+}
+
+// Clock for dwell time measurements: nanoseconds since first use.
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+fn clock() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+// Enable (or disable) histograms for the link.
+// NB: enabling histograms resets them, packets already on the link are
+// accounted for as if they had been enqueued now.
+pub fn set_histograms(r: &mut Link, enabled: bool) {
+    r.histograms = if enabled {
+        Some(Box::new(Histograms {
+            occupancy: histogram::new(),
+            dwell: histogram::new(),
+            enqueued: vec![clock(); r.packets.len()],
+        }))
     } else {
-        r.txpackets += 1;
-        r.txbytes += p.length as u64;
-        r.packets[r.write as usize] = &mut *p;
-        std::mem::forget(p);
-        r.write = (r.write + 1) & (SIZE - 1);
+        None
+    };
+}
+
+// Record a sample of the link’s occupancy (e.g., once per breath).
+pub fn sample_occupancy(r: &mut Link) {
+    let n = nreadable(r) as u64;
+    if let Some(histograms) = &mut r.histograms {
+        histograms.occupancy.add(n);
     }
 }
 
+pub fn occupancy_histogram(r: &Link) -> Option<&Histogram> {
+    r.histograms
+        .as_ref()
+        .map(|histograms| &histograms.occupancy)
+}
+
+pub fn dwell_histogram(r: &Link) -> Option<&Histogram> {
+    r.histograms.as_ref().map(|histograms| &histograms.dwell)
+}
+
 // Ensure that Dropped Links are empty (otherwise Dropping a link would leak
 // its remaining enqueued packets).
-// NB: a non-empty Link going out of scope will trigger a panic.
+// NB: packets remaining on a Link going out of scope are freed.
 impl Drop for Link {
     fn drop(&mut self) {
         while !empty(self) {
@@ -112,12 +332,13 @@ mod selftest {
             p.length = n;
             p.data[(n - 1) as usize] = 42;
             // Why is &, &mut not automatically inferred?
-            transmit(&mut r, p);
+            assert!(transmit(&mut r, p).is_none());
             //p.data[0] = 13 // Would cause compiler error.
             //transmit(&mut r, p); // Would cause compile error
         }
         println!("Transmitted {} packets", to_transmit);
         assert!(full(&r), "Link should be full.");
+        assert_eq!(nreadable(&r), LINK_MAX_PACKETS);
         let mut n = 0;
         while !empty(&r) {
             n += 1;
@@ -134,6 +355,100 @@ mod selftest {
             "link: rxpackets={} rxbytes={} txpackets={} txbytes={} txdrop={}",
             r.rxpackets, r.rxbytes, r.txpackets, r.txbytes, r.txdrop
         );
-        // Failing to drain the link would cause panic
+        // Failing to drain the link would free the remaining packets on Drop
+    }
+
+    #[test]
+    fn batch() {
+        let mut r = new();
+        let mut packets: Vec<_> = (0..100).map(|_| packet::allocate()).collect();
+        for (n, p) in packets.iter_mut().enumerate() {
+            p.length = n as u16;
+        }
+        transmit_batch(&mut r, &mut packets);
+        assert!(packets.is_empty());
+        assert_eq!(nreadable(&r), 100);
+        assert_eq!(nwritable(&r), LINK_MAX_PACKETS - 100);
+        assert_eq!(receive_batch(&mut r, &mut packets, 10), 10);
+        assert_eq!(packets[9].length, 9);
+        assert_eq!(receive_batch(&mut r, &mut packets, 1000), 90);
+        assert_eq!(packets[99].length, 99);
+        assert!(empty(&r));
+        assert_eq!(r.rxpackets, 100);
+        assert_eq!(r.rxbytes, (0..100).sum::<u64>());
+        // overflowing the link drops the excess packets
+        packets.extend((0..LINK_MAX_PACKETS).map(|_| packet::allocate()));
+        transmit_batch(&mut r, &mut packets);
+        assert!(full(&r));
+        assert_eq!(r.txpackets, 100 + LINK_MAX_PACKETS as u64);
+        assert_eq!(r.txdrop, 100);
+    }
+
+    #[test]
+    fn tap() {
+        let mut r = new();
+        assert!(transmit(&mut r, packet::allocate()).is_none());
+        assert!(set_tap(&mut r, Some(tap::ring(10))).is_none());
+        assert!(transmit(&mut r, packet::allocate()).is_none());
+        let mut packets = vec![packet::allocate(), packet::allocate()];
+        transmit_batch(&mut r, &mut packets);
+        let tap = set_tap(&mut r, None).unwrap();
+        assert!(transmit(&mut r, packet::allocate()).is_none());
+        assert_eq!(tap.captured(), 3);
+        assert_eq!(r.txpackets, 5);
+    }
+
+    #[test]
+    fn histograms() {
+        let mut r = new();
+        assert!(occupancy_histogram(&r).is_none());
+        set_histograms(&mut r, true);
+        for _ in 0..3 {
+            assert!(transmit(&mut r, packet::allocate()).is_none());
+            sample_occupancy(&mut r);
+        }
+        let mut packets = Vec::new();
+        receive_batch(&mut r, &mut packets, 2);
+        packet::free(receive(&mut r));
+        sample_occupancy(&mut r);
+        let occupancy = occupancy_histogram(&r).unwrap();
+        assert_eq!(occupancy.count(), 4);
+        assert_eq!(occupancy.max(), 3);
+        assert_eq!(dwell_histogram(&r).unwrap().count(), 3);
+        set_histograms(&mut r, false);
+        assert!(dwell_histogram(&r).is_none());
+    }
+
+    #[test]
+    fn policies() {
+        let transmit_n = |r: &mut Link, n: u16| {
+            for length in 0..n {
+                let mut p = packet::allocate();
+                p.length = length;
+                if let Some(p) = transmit(r, p) {
+                    packet::free(p);
+                }
+            }
+        };
+        let mut r = with_options(8, DropPolicy::TailDrop);
+        assert_eq!(capacity(&r), 7);
+        transmit_n(&mut r, 10);
+        assert_eq!((nreadable(&r), r.txdrop), (7, 3));
+        assert_eq!(front(&r).length, 0);
+        let mut r = with_options(8, DropPolicy::HeadDrop);
+        transmit_n(&mut r, 10);
+        assert_eq!((nreadable(&r), r.txdrop), (7, 3));
+        assert_eq!(front(&r).length, 3);
+        let mut packets: Vec<_> = (0..10).map(|_| packet::allocate()).collect();
+        transmit_batch(&mut r, &mut packets);
+        assert_eq!((nreadable(&r), r.txdrop), (7, 13));
+        assert!(packets.is_empty());
+        let mut r = with_options(8, DropPolicy::Refuse);
+        assert!(transmit(&mut r, packet::allocate()).is_none());
+        let mut packets: Vec<_> = (0..10).map(|_| packet::allocate()).collect();
+        transmit_batch(&mut r, &mut packets);
+        assert_eq!((nreadable(&r), r.txdrop), (7, 0));
+        assert_eq!(packets.len(), 4, "Refused packets not returned");
+        assert!(transmit(&mut r, packets.pop().unwrap()).is_some());
     }
 }
@@ -4,6 +4,9 @@
 mod memory;
 mod packet;
 mod link;
+mod tap;
+mod histogram;
+mod pcap;
 mod engine;
 mod config;
 mod lib;
@@ -12,6 +15,11 @@ mod header;
 mod ethernet;
 mod ixy82599;
 mod ixy82599_app;
+mod pcap_app;
+mod raw_socket_app;
+mod tap_app;
+mod af_xdp;
+mod af_xdp_app;
 mod checksum;
 
 fn main() {
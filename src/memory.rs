@@ -9,6 +9,8 @@ use super::lib;
 use once_cell::unsync::Lazy;
 use regex::Regex;
 use std::ffi;
+use std::io;
+use std::ptr;
 
 // Serve small allocations from hugepage "chunks"
 
@@ -21,43 +23,75 @@ struct Chunk {
 }
 static mut CHUNKS: Lazy<Vec<Chunk>> = Lazy::new(Vec::new);
 
+// List of freed DMA allocations: {pointer, size, align}
+// Reused by allocations of the same size and alignment (e.g., the rings of a
+// device queue that is stopped and reopened).
+static mut FREED: Lazy<Vec<(u64, usize, usize)>> = Lazy::new(Vec::new);
+
 // Allocate DMA-friendly memory. Return virtual memory pointer.
+// NB: panics if no memory can be allocated, see try_dma_alloc.
 pub fn dma_alloc(bytes: usize, align: usize) -> *mut u8 {
+    try_dma_alloc(bytes, align)
+        .unwrap_or_else(|error| panic!("Failed to allocate DMA memory: {}", error))
+}
+
+// Allocate DMA-friendly memory, fail if no memory can be allocated (e.g., if
+// the IOMMU address space is exhausted or the IOMMU refuses to map memory).
+pub fn try_dma_alloc(bytes: usize, align: usize) -> io::Result<*mut u8> {
     assert!(bytes <= huge_page_size());
+    // Reuse freed memory if possible
+    let freed = unsafe { &mut *ptr::addr_of_mut!(FREED) };
+    if let Some(i) = freed.iter().position(|&(_, b, a)| b == bytes && a == align) {
+        return Ok(freed.swap_remove(i).0 as *mut u8);
+    }
     // Get current chunk of memory to allocate from
     if unsafe { CHUNKS.len() } == 0 {
-        allocate_next_chunk()
+        allocate_next_chunk()?;
     }
     let mut chunk = unsafe { CHUNKS.last_mut().unwrap() };
     // Skip allocation forward pointer to suit alignment
     chunk.used = lib::align(chunk.used, align);
     // Need a new chunk to service this allocation?
     if chunk.used + bytes > chunk.size {
-        allocate_next_chunk();
+        allocate_next_chunk()?;
         chunk = unsafe { CHUNKS.last_mut().unwrap() };
     }
     // Slice out the memory we need
     let offset = chunk.used;
     chunk.used += bytes;
-    (chunk.pointer + (offset as u64)) as *mut u8
+    Ok((chunk.pointer + (offset as u64)) as *mut u8)
+}
+
+// Free DMA memory allocated with dma_alloc(bytes, align).
+// NB: the memory is kept for reuse by dma_alloc (it is never unmapped), so it
+// must not be accessed by devices anymore.
+pub fn dma_free(ptr: *mut u8, bytes: usize, align: usize) {
+    unsafe { &mut *ptr::addr_of_mut!(FREED) }.push((ptr as u64, bytes, align));
 }
 
 // Add a new chunk.
-fn allocate_next_chunk() {
-    let ptr = allocate_hugetlb_chunk();
+fn allocate_next_chunk() -> io::Result<()> {
+    let ptr = allocate_hugetlb_chunk()?;
     let chunk = Chunk {
         pointer: ptr as u64,
         size: huge_page_size(),
         used: 0,
     };
+    if let Some(map) = unsafe { IOMMU_MAP } {
+        if let Err(error) = iommu_map(map, &chunk) {
+            unsafe { libc::munmap(ptr, chunk.size) };
+            return Err(error);
+        }
+    }
     unsafe {
         CHUNKS.push(chunk);
     }
+    Ok(())
 }
 
 // HugeTLB: Allocate contiguous memory in bulk from Linux
 
-fn allocate_hugetlb_chunk() -> *mut ffi::c_void {
+fn allocate_hugetlb_chunk() -> io::Result<*mut ffi::c_void> {
     std::panic::catch_unwind(|| allocate_huge_page(huge_page_size()))
         .expect("Failed to allocate a huge page for DMA")
 }
@@ -97,7 +131,8 @@ const TAG: u64 = 0x500000000000;
 
 // virtual_to_physical(ptr) -> u64
 //
-// Return the physical address of specially mapped DMA memory.
+// Return the physical address of specially mapped DMA memory (or its I/O
+// virtual address if DMA goes through the IOMMU, see use_iommu).
 pub fn virtual_to_physical(virt_addr: *const u8) -> u64 {
     let virt_addr = virt_addr as u64;
     assert!(
@@ -108,6 +143,99 @@ pub fn virtual_to_physical(virt_addr: *const u8) -> u64 {
     virt_addr ^ 0x500000000000
 }
 
+// IOMMU: DMA via I/O virtual addresses
+//
+// Devices behind an IOMMU (i.e., devices bound to vfio-pci) address memory
+// via I/O virtual addresses (IOVA) instead of physical addresses. Like
+// physical addresses, the IOVA of a chunk is its virtual address without the
+// tag bits, so virtual_to_physical works the same in both modes. Chunks
+// allocated before the IOMMU is used are mapped at their physical address,
+// chunks allocated in IOMMU mode are mapped at consecutive IOVAs starting at
+// IOVA_BASE (their physical addresses need not be resolved, which requires
+// root privileges). IOVAs must fit into the address width of the IOMMU (see
+// limit_iova). Allocating chunks still requires root privileges, as hugetlbfs
+// is mounted on demand.
+//
+// NB: devices that use physical addresses can not be used alongside devices
+// behind the IOMMU (virtual_to_physical returns IOVAs once the IOMMU is used).
+
+// use_iommu(map) - map DMA memory into the IOMMU from now on
+// limit_iova(address_width) - restrict IOVAs to address_width bits
+
+// Maps memory (address, IOVA, size) into the IOMMU.
+type IommuMap = fn(u64, u64, usize) -> io::Result<()>;
+
+static mut IOMMU_MAP: Option<IommuMap> = None;
+
+// First IOVA for chunks allocated in IOMMU mode (above the low 4 GiB, which
+// hold reserved regions such as the MSI window on x86).
+const IOVA_BASE: u64 = 1 << 32;
+
+// Next IOVA to try for chunks allocated in IOMMU mode.
+static mut NEXT_IOVA: u64 = IOVA_BASE;
+
+// IOVAs must be below IOVA_LIMIT (see limit_iova).
+static mut IOVA_LIMIT: u64 = u64::MAX;
+
+// End of the highest IOVA range mapped so far.
+static mut IOVA_END: u64 = 0;
+
+// use_iommu(map)
+//
+// Use the IOMMU for DMA. Map is called to map memory (address, IOVA, size)
+// into the IOMMU. Existing chunks are mapped immediately, fails if one of
+// them can not be mapped.
+pub fn use_iommu(map: IommuMap) -> io::Result<()> {
+    assert!(unsafe { IOMMU_MAP }.is_none(), "IOMMU is already in use");
+    unsafe {
+        for chunk in CHUNKS.iter() {
+            iommu_map(map, chunk)?;
+        }
+        IOMMU_MAP = Some(map);
+    }
+    Ok(())
+}
+
+// limit_iova(address_width)
+//
+// Restrict IOVAs to address_width bits (the address width of the IOMMU of a
+// device that uses it). Fails if memory is already mapped beyond the limit.
+pub fn limit_iova(address_width: u32) -> io::Result<()> {
+    let limit = 1u64.checked_shl(address_width).unwrap_or(u64::MAX);
+    unsafe {
+        if IOVA_END > limit {
+            return Err(io::Error::other(format!(
+                "DMA memory is mapped beyond the IOMMU address width ({} bits)",
+                address_width
+            )));
+        }
+        IOVA_LIMIT = IOVA_LIMIT.min(limit);
+    }
+    Ok(())
+}
+
+fn iommu_map(map: IommuMap, chunk: &Chunk) -> io::Result<()> {
+    let iova = chunk.pointer ^ TAG;
+    let end = iova + chunk.size as u64;
+    if end > unsafe { IOVA_LIMIT } {
+        return Err(io::Error::other(format!(
+            "DMA memory at 0x{:x} exceeds the IOMMU address width",
+            chunk.pointer
+        )));
+    }
+    map(chunk.pointer, iova, chunk.size).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!(
+                "Failed to map DMA memory at 0x{:x} into the IOMMU: {}",
+                chunk.pointer, error
+            ),
+        )
+    })?;
+    unsafe { IOVA_END = IOVA_END.max(end) };
+    Ok(())
+}
+
 // Map a new HugeTLB page to an appropriate virtual address.
 //
 // The page is allocated via the hugetlbfs filesystem
@@ -118,7 +246,7 @@ pub fn virtual_to_physical(virt_addr: *const u8) -> u64 {
 // Further reading:
 //   https://www.kernel.org/doc/Documentation/vm/hugetlbpage.txt
 //   http://stackoverflow.com/questions/27997934/mremap2-with-hugetlb-to-change-virtual-address
-fn allocate_huge_page(size: usize) -> *mut ffi::c_void {
+fn allocate_huge_page(size: usize) -> io::Result<*mut ffi::c_void> {
     ensure_hugetlbfs();
     unsafe {
         let tmpfile = cstr(&format!("/var/run/rush/hugetlbfs/alloc.{}", libc::getpid()));
@@ -135,20 +263,62 @@ fn allocate_huge_page(size: usize) -> *mut ffi::c_void {
         );
         assert!(tmpptr != libc::MAP_FAILED, "mmap hugetlb");
         assert!(libc::mlock(tmpptr, size) == 0, "mlock");
-        let phys = resolve_physical(tmpptr);
-        let virt = phys | TAG;
+        let ptr = match IOMMU_MAP {
+            None => {
+                let phys = resolve_physical(tmpptr);
+                let virt = phys | TAG;
+                Ok(libc::mmap(
+                    virt as *mut ffi::c_void,
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    fd,
+                    0,
+                ))
+            }
+            Some(_) => map_next_iova(fd, size),
+        };
+        libc::unlink(tmpfile.as_ptr());
+        libc::munmap(tmpptr, size);
+        libc::close(fd);
+        ptr
+    }
+}
+
+// Map the huge page fd at the (tagged) next free IOVA, fail if the IOMMU
+// address space is exhausted.
+unsafe fn map_next_iova(fd: libc::c_int, size: usize) -> io::Result<*mut ffi::c_void> {
+    loop {
+        let iova = NEXT_IOVA;
+        if iova + size as u64 > IOVA_LIMIT {
+            return Err(io::Error::other("IOMMU address space exhausted"));
+        }
+        NEXT_IOVA += size as u64;
+        let virt = iova | TAG;
+        // NB: MAP_FIXED_NOREPLACE skips addresses that are already mapped,
+        // e.g. by chunks allocated before the IOMMU was used.
         let ptr = libc::mmap(
             virt as *mut ffi::c_void,
             size,
             libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED | libc::MAP_FIXED,
+            libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE,
             fd,
             0,
         );
-        libc::unlink(tmpfile.as_ptr());
-        libc::munmap(tmpptr, size);
-        libc::close(fd);
-        ptr
+        if ptr as u64 == virt {
+            return Ok(ptr);
+        }
+        if ptr != libc::MAP_FAILED {
+            // mapped elsewhere (kernel ignores MAP_FIXED_NOREPLACE)
+            libc::munmap(ptr, size);
+            continue;
+        }
+        let error = io::Error::last_os_error();
+        assert!(
+            error.raw_os_error() == Some(libc::EEXIST),
+            "mmap hugetlb: {}",
+            error
+        );
     }
 }
 
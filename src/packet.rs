@@ -2,154 +2,788 @@ use super::engine;
 use super::lib;
 use super::memory;
 
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
 use std::cmp;
+use std::collections::HashMap;
+use std::io;
 use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
 
 // PACKET STRUCT AND FREELIST
 //
 // This module defines a struct to represent packets of network data, and
-// implements a global freelist from which packets can be allocated.
+// implements global freelists (packet pools) from which packets can be
+// allocated.
 //
-//   Packet - packet structure with length and data fields
-//   PAYLOAD_SIZE - size of packet’s data field
-//   init() - initializes the freelist with FREELIST_SIZE packets
-//   allocate() -> Box<Packet> - take a packet off the freelist for use
-//   free(Box<Packet>) - return a packet to the freelist
+//   Packet - packet structure with length, metadata, and data fields
+//   Metadata - per-packet metadata (timestamp, ingress, RSS hash, VLAN, flags)
+//   PacketBox - owning pointer to a packet, returns it to the freelist on Drop
+//   PacketBox::into_raw(PacketBox) -> *mut Packet - release ownership
+//   PacketBox::from_raw(*mut Packet) -> PacketBox - reclaim ownership
+//   PAYLOAD_SIZE - size of the data field of packets in the default pool
+//   Pool - handle for a packet pool (freelist with a fixed buffer size)
+//   DEFAULT_POOL - the pool used by allocate()
+//   new_pool(name:&str, buffer_size) -> Pool - create a named packet pool
+//   new_pool_at(name:&str, buffer_size, data, stride, npackets) -> Pool - create
+//     a fixed size packet pool in caller provided memory
+//   header_size() -> usize - offset of the data field within packets
+//   pool(name:&str) -> Option<Pool> - look up a packet pool by name
+//   pools() -> Vec<Pool> - list all packet pools
+//   pool_stats(Pool) -> PoolStats - get statistics of a packet pool
+//   init(Pool, preallocate, max_packets) - preallocate packets, cap pool size
+//   Packet.pool() -> Pool - pool the packet was allocated from
+//   Packet.next() -> Option<&Packet> - next segment of a multi-segment packet
+//   Packet.segments() -> Segments - iterate over the segments of a packet
+//   Packet.nsegments() -> usize - number of segments of a packet
+//   Packet.total_length() -> usize - sum of the lengths of all segments
+//   Packet.append(PacketBox) - chain segments to the end of a packet
+//   Packet.take_next() -> Option<PacketBox> - unchain the following segments
+//   null_mut() -> *mut Packet - null packet pointer (like std::ptr::null_mut)
+//   allocate() -> PacketBox - take a packet off the default freelist for use
+//   allocate_from(Pool) -> PacketBox - take a packet off a pool’s freelist
+//   try_allocate() -> Option<PacketBox> - like allocate(), None if exhausted
+//   try_allocate_from(Pool) -> Option<PacketBox> - ditto for allocate_from()
+//   free(PacketBox) - return a packet to its freelist (same as dropping it)
+//   clone(&Packet) -> PacketBox - copy a packet (including all segments)
+//   from_data(Pool, &[u8]) -> PacketBox - allocate a packet containing data
+//   linearize(PacketBox) -> PacketBox - copy all segments into a single one
+//   set_debug(bool) - enable/disable tagging of allocations (debug mode)
+//   set_allocation_tag(Option<&str>) - tag subsequent allocations (debug mode)
+//   outstanding_allocations() -> HashMap<String, usize> - live packets by tag
 
-// The maximum amount of payload in any given packet.
+// The amount of payload in packets allocated from the default pool.
 pub const PAYLOAD_SIZE: usize = 1024 * 10;
 
 // Packet of network data, with associated metadata.
-// XXX - should be #[repr(C, packed)], however that would require unsafe{} to
-// access members. Is the memory layout in repr(rust) equivalent?
+//
+// The size of the data field depends on the pool the packet was allocated
+// from, i.e. Packet is a dynamically sized type and data.len() is the buffer
+// size of its pool. Pointers to packets are fat pointers (address, buffer
+// size).
+//
+// Frames larger than a single buffer are represented as multi-segment packets:
+// a chain of packets linked via next. The head segment owns the chain (and
+// carries the metadata), length is the payload length of each segment.
+// NB: #[repr(C)] so that we can compute the layout of a packet for a given
+// buffer size (see packet_layout).
+#[repr(C)]
 pub struct Packet {
-    pub length: u16, // data payload length
-    pub data: [u8; PAYLOAD_SIZE],
+    pub length: u16, // data payload length (of this segment)
+    pub meta: Metadata,
+    pool: Pool,
+    next: Option<PacketBox>, // next segment
+    pub data: [u8],
+}
+
+impl Packet {
+    // Return the pool this packet belongs to.
+    pub fn pool(&self) -> Pool {
+        self.pool
+    }
+
+    // Return the next segment of this packet (if any).
+    pub fn next(&self) -> Option<&Packet> {
+        self.next.as_deref()
+    }
+
+    pub fn next_mut(&mut self) -> Option<&mut Packet> {
+        self.next.as_deref_mut()
+    }
+
+    // Iterate over the segments of this packet (starting with this segment).
+    pub fn segments(&self) -> Segments<'_> {
+        Segments(Some(self))
+    }
+
+    pub fn nsegments(&self) -> usize {
+        self.segments().count()
+    }
+
+    // Return the payload length of this packet summed over all segments.
+    pub fn total_length(&self) -> usize {
+        match self.next {
+            None => self.length as usize,
+            Some(_) => self.segments().map(|seg| seg.length as usize).sum(),
+        }
+    }
+
+    // Chain seg (and its following segments) to the end of this packet.
+    pub fn append(&mut self, seg: PacketBox) {
+        let mut next = &mut self.next;
+        while let Some(p) = next {
+            next = &mut p.next;
+        }
+        *next = Some(seg);
+    }
+
+    // Unchain and return the segments following this segment.
+    pub fn take_next(&mut self) -> Option<PacketBox> {
+        self.next.take()
+    }
+}
+
+// Iterator over the segments of a packet (see Packet.segments).
+pub struct Segments<'a>(Option<&'a Packet>);
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a Packet;
+    fn next(&mut self) -> Option<&'a Packet> {
+        let seg = self.0?;
+        self.0 = seg.next();
+        Some(seg)
+    }
+}
+
+// Return a null packet pointer (e.g., to initialize arrays of packet pointers).
+// NB: std::ptr::null_mut() only works for pointers to sized types.
+pub fn null_mut() -> *mut Packet {
+    ptr::slice_from_raw_parts_mut(ptr::null_mut::<u8>(), 0) as *mut Packet
 }
 
-// A packet may never go out of scope. It is either on the freelist, a link, or
-// in active use (in-scope).
-// XXX - Could free() packets automatically in Drop, and obsolete manual free.
-impl Drop for Packet {
+// Small, fixed-size metadata area carried alongside each packet.
+//
+// Cleared by allocate(), copied by clone(). Drivers fill in what the hardware
+// tells them (see IxgbeDevice::rx_batch), apps may use the remaining fields.
+// Fields are only meaningful if the corresponding flag is set.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    pub timestamp: u64, // RX timestamp (nanoseconds since the UNIX epoch)
+    pub port: u16,      // ingress port (app defined)
+    pub queue: u16,     // ingress hardware queue
+    pub rss_hash: u32,  // RSS hash computed by the NIC
+    pub vlan: u16,      // VLAN tag control information (TCI)
+    pub flags: u16,     // META_* flags
+}
+
+// Metadata flags
+pub const META_TIMESTAMP: u16 = 1 << 0; // timestamp is valid
+pub const META_PORT: u16 = 1 << 1; // port is valid
+pub const META_QUEUE: u16 = 1 << 2; // queue is valid
+pub const META_RSS_HASH: u16 = 1 << 3; // rss_hash is valid
+pub const META_VLAN: u16 = 1 << 4; // vlan is valid
+pub const META_IP_CSUM_OK: u16 = 1 << 5; // IPv4 header checksum verified
+pub const META_L4_CSUM_OK: u16 = 1 << 6; // TCP/UDP checksum verified
+pub const META_IP_CSUM_BAD: u16 = 1 << 7; // IPv4 header checksum error
+pub const META_L4_CSUM_BAD: u16 = 1 << 8; // TCP/UDP checksum error
+
+// Owning pointer to a packet taken off the freelist.
+//
+// A packet is either on the freelist, a link, a device ring, or in active use
+// (in-scope, owned by exactly one PacketBox). When a PacketBox goes out of
+// scope its packet is returned to the freelist automatically (see Drop below),
+// hence packets can not leak.
+//
+// Links and device rings hold packets as raw pointers: PacketBox::into_raw
+// hands over ownership without freeing the packet, and PacketBox::from_raw
+// takes it back.
+pub struct PacketBox(NonNull<Packet>);
+
+impl PacketBox {
+    // Release ownership of the packet and return a raw pointer to it.
+    // NB: the packet is not freed, the caller is responsible for eventually
+    // reclaiming it via from_raw.
+    pub fn into_raw(p: PacketBox) -> *mut Packet {
+        let ptr = p.0.as_ptr();
+        mem::forget(p);
+        ptr
+    }
+
+    // Reclaim ownership of a packet released via into_raw.
+    //
+    // Safety: ptr must have been obtained from into_raw, and must be reclaimed
+    // at most once (otherwise two PacketBoxes would alias the same packet).
+    pub unsafe fn from_raw(ptr: *mut Packet) -> PacketBox {
+        PacketBox(NonNull::new_unchecked(ptr))
+    }
+}
+
+impl Deref for PacketBox {
+    type Target = Packet;
+    fn deref(&self) -> &Packet {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl DerefMut for PacketBox {
+    fn deref_mut(&mut self) -> &mut Packet {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+// Return the packet (all of its segments) to FL, and account for it in the
+// engine statistics.
+impl Drop for PacketBox {
     fn drop(&mut self) {
-        panic!("Packet leaked");
+        let length = self.total_length() as u64;
+        engine::add_frees();
+        engine::add_freebytes(length);
+        // Calculate bits of physical capacity required for packet on 10GbE
+        // Account for minimum data size and overhead of Ethernet preamble, CRC,
+        // and inter-packet gap
+        // https://netoptimizer.blogspot.com/2014/05/the-calculations-10gbits-wirespeed.html
+        engine::add_freebits((12 + 8 + cmp::max(length, 60) + 4) * 8);
+        let next = self.take_next();
+        free_internal(self.0.as_ptr());
+        if let Some(next) = next {
+            release(next);
+        }
     }
 }
 
-// Allocate a packet struct on the heap (initialized all-zero).
-// NB: Box is how we heap-allocate in Rust.
-fn new_packet() -> Box<Packet> {
-    let base = memory::dma_alloc(mem::size_of::<Packet>(), mem::align_of::<Packet>());
-    let mut p = unsafe { Box::from_raw(base as *mut Packet) };
-    p.length = 0;
+// Handle for a packet pool (index into POOLS).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pool(u16);
+
+// The default pool (created on first use, with PAYLOAD_SIZE buffers).
+pub const DEFAULT_POOL: Pool = Pool(0);
+
+// Memory layout of the packet fields preceding data.
+fn header_layout() -> Layout {
+    let (layout, _) = Layout::new::<u16>()
+        .extend(Layout::new::<Metadata>())
+        .unwrap();
+    let (layout, _) = layout.extend(Layout::new::<Pool>()).unwrap();
+    let (layout, _) = layout.extend(Layout::new::<Option<PacketBox>>()).unwrap();
+    layout
+}
+
+// Memory layout of a packet with buffer_size bytes of payload.
+fn packet_layout(buffer_size: usize) -> Layout {
+    let (layout, _) = header_layout()
+        .extend(Layout::array::<u8>(buffer_size).unwrap())
+        .unwrap();
+    layout.pad_to_align()
+}
+
+// Offset of the data field within packets.
+pub fn header_size() -> usize {
+    header_layout().size()
+}
+
+// Initialize a packet struct for pool at base (with buffer_size bytes of
+// payload), and return a (fat) pointer to it.
+unsafe fn init_packet(base: *mut u8, pool: Pool, buffer_size: usize) -> *mut Packet {
+    let p = ptr::slice_from_raw_parts_mut(base, buffer_size) as *mut Packet;
+    (*p).length = 0;
+    (*p).meta = Default::default();
+    (*p).pool = pool;
+    ptr::write(&mut (*p).next, None);
     p
 }
-fn new_packet_noroot() -> Box<Packet> {
-    Box::new(Packet {
-        length: 0,
-        data: [0; PAYLOAD_SIZE],
-    })
+
+// Allocate a packet struct in DMA memory (initialized all-zero).
+fn new_packet(pool: Pool, buffer_size: usize) -> io::Result<*mut Packet> {
+    let layout = packet_layout(buffer_size);
+    let base = memory::try_dma_alloc(layout.size(), layout.align())?;
+    Ok(unsafe { init_packet(base, pool, buffer_size) })
+}
+// Allocate a packet struct on the heap (initialized all-zero).
+// NB: we intentionally leak heap allocated packets onto the freelist.
+fn new_packet_noroot(pool: Pool, buffer_size: usize) -> io::Result<*mut Packet> {
+    let base = unsafe { alloc::alloc_zeroed(packet_layout(buffer_size)) };
+    if base.is_null() {
+        return Err(io::ErrorKind::OutOfMemory.into());
+    }
+    Ok(unsafe { init_packet(base, pool, buffer_size) })
 }
 
-// Maximum number of packets on the freelist.
+// Default maximum number of packets on a freelist (see init).
 const MAX_PACKETS: usize = 1_000_000;
 
-// Freelist consists of an array of mutable raw pointers to Packet,
-// and a fill counter.
+// Freelist consists of a vector of mutable raw pointers to Packet (the free
+// packets), and allocation state.
 struct Freelist {
-    list: [*mut Packet; MAX_PACKETS],
-    nfree: usize,
+    name: String,
+    buffer_size: usize,
+    list: Vec<*mut Packet>,
+    allocated: usize,       // number of packets allocated for this freelist
+    allocation_step: usize, // number of packets to allocate in next step
+    max_packets: usize,     // maximum number of packets to allocate
+    high_water: usize,      // maximum number of packets in use at once
+    growths: usize,         // number of times the freelist was grown
+    failures: u64,          // number of failed allocations (pool exhausted)
+    fixed: bool,            // packets are in caller provided memory (see new_pool_at)
 }
 
-// FL: global freelist (initially empty, populated with null ptrs).
-static mut FL: Freelist = Freelist {
-    list: [std::ptr::null_mut(); MAX_PACKETS],
-    nfree: 0,
-};
+impl Freelist {
+    fn new(name: &str, buffer_size: usize) -> Self {
+        Freelist {
+            name: name.to_string(),
+            buffer_size,
+            list: Vec::new(),
+            allocated: 0,
+            allocation_step: 1000,
+            max_packets: MAX_PACKETS,
+            high_water: 0,
+            growths: 0,
+            failures: 0,
+            fixed: false,
+        }
+    }
+}
+
+// POOLS: freelists of this thread, indexed by Pool (initially only the empty
+// default pool). Packets (and Pool handles) must not be passed between
+// threads.
+// NB: POOLS is only ever borrowed for the duration of a
+// with_freelists/with_freelist call (new_pool may reallocate it), the
+// closures passed to these functions must not access POOLS themselves. POOLS
+// is leaked so that packets can still be freed while the thread exits (e.g.,
+// the packets on the links of its engine).
+thread_local! {
+    static POOLS: &'static RefCell<Vec<Freelist>> = Box::leak(Box::new(RefCell::new(vec![
+        Freelist::new("default", PAYLOAD_SIZE),
+    ])));
+}
+
+fn with_freelists<R>(f: impl FnOnce(&mut Vec<Freelist>) -> R) -> R {
+    POOLS.with(|pools| f(&mut pools.borrow_mut()))
+}
+
+fn with_freelist<R>(pool: Pool, f: impl FnOnce(&mut Freelist) -> R) -> R {
+    with_freelists(|freelists| f(&mut freelists[pool.0 as usize]))
+}
+
+fn buffer_size(pool: Pool) -> usize {
+    with_freelist(pool, |fl| fl.buffer_size)
+}
+
+// Create a new named packet pool with buffers of buffer_size bytes.
+//
+// Creating a pool with the name of an existing pool returns the existing pool
+// (buffer sizes must match).
+pub fn new_pool(name: &str, buffer_size: usize) -> Pool {
+    if let Some(pool) = self::pool(name) {
+        assert!(
+            self::buffer_size(pool) == buffer_size,
+            "Packet pool {} exists with different buffer size",
+            name
+        );
+        return pool;
+    }
+    assert!(buffer_size > 0, "Packet pool buffer size must be non-zero");
+    with_freelists(|freelists| {
+        assert!(
+            freelists.len() <= u16::MAX as usize,
+            "Too many packet pools"
+        );
+        freelists.push(Freelist::new(name, buffer_size));
+        Pool((freelists.len() - 1) as u16)
+    })
+}
 
-// Fill up FL with freshly allocated packets.
-// NB: using FL is unsafe because it is a mutable static (we have to ensure
-// thread safety).
+// Create a new named packet pool of npackets packets with buffers of
+// buffer_size bytes in caller provided memory (e.g., memory shared with the
+// kernel or a device). The data field of the i-th packet starts at
+// data + i * stride, and is preceded by the packet’s header (see header_size).
+// The pool never grows beyond npackets.
+//
+// Safety: the memory from data - header_size() to data + npackets * stride
+// must be valid (and not used otherwise) for the rest of the program.
+pub unsafe fn new_pool_at(
+    name: &str,
+    buffer_size: usize,
+    data: *mut u8,
+    stride: usize,
+    npackets: usize,
+) -> Pool {
+    assert!(self::pool(name).is_none(), "Packet pool {} exists", name);
+    let header = header_size();
+    let align = packet_layout(buffer_size).align();
+    assert!(
+        stride >= header + buffer_size,
+        "Packet pool stride is too small"
+    );
+    assert!(
+        (data as usize - header) & (align - 1) == 0 && stride & (align - 1) == 0,
+        "Packet pool memory is misaligned"
+    );
+    let pool = new_pool(name, buffer_size);
+    with_freelist(pool, |fl| {
+        fl.list.reserve(npackets);
+        for i in 0..npackets {
+            let base = data.add(i * stride).sub(header);
+            fl.list.push(init_packet(base, pool, buffer_size));
+        }
+        fl.allocated = npackets;
+        fl.max_packets = npackets;
+        fl.fixed = true;
+    });
+    pool
+}
+
+// Look up a packet pool by name.
+pub fn pool(name: &str) -> Option<Pool> {
+    with_freelists(|freelists| {
+        freelists
+            .iter()
+            .position(|fl| fl.name == name)
+            .map(|index| Pool(index as u16))
+    })
+}
+
+// List all packet pools (in order of creation).
+pub fn pools() -> Vec<Pool> {
+    (0..with_freelists(|freelists| freelists.len()))
+        .map(|index| Pool(index as u16))
+        .collect()
+}
+
+// Statistics of a packet pool.
+#[derive(Clone, Debug, Default)]
+pub struct PoolStats {
+    pub name: String,
+    pub buffer_size: usize, // size of the data field of packets in this pool
+    pub allocated: usize,   // packets allocated for this pool
+    pub in_use: usize,      // packets currently in use (allocated - free)
+    pub free: usize,        // packets currently on the freelist
+    pub max_packets: usize, // maximum number of packets for this pool
+    pub high_water: usize,  // maximum number of packets in use at once
+    pub growths: usize,     // number of times the freelist was grown
+    pub failures: u64,      // number of failed allocations (pool exhausted)
+}
+
+pub fn pool_stats(pool: Pool) -> PoolStats {
+    with_freelist(pool, |fl| PoolStats {
+        name: fl.name.clone(),
+        buffer_size: fl.buffer_size,
+        allocated: fl.allocated,
+        in_use: fl.allocated - fl.list.len(),
+        free: fl.list.len(),
+        max_packets: fl.max_packets,
+        high_water: fl.high_water,
+        growths: fl.growths,
+        failures: fl.failures,
+    })
+}
+
+// Initialize the freelist of pool.
+//
+// init(pool, preallocate, max_packets):
+//   preallocate is the number of packets to allocate up front. Allocating
+//     enough packets up front avoids growing the freelist on demand (which
+//     causes latency spikes in the data path).
+//   max_packets is the maximum number of packets ever allocated for pool.
+//     Once max_packets are in use further allocations fail (see try_allocate).
+//
+// Example: packet::init(packet::DEFAULT_POOL, 100_000, 100_000)
+pub fn init(pool: Pool, preallocate: usize, max_packets: usize) {
+    with_freelist(pool, |fl| {
+        assert!(!fl.fixed, "Packet pool {} has a fixed size", fl.name);
+        assert!(
+            preallocate <= max_packets,
+            "Can not preallocate more than max_packets"
+        );
+        assert!(
+            fl.allocated <= max_packets,
+            "Packet pool {} already exceeds {} packets",
+            fl.name,
+            max_packets
+        );
+        fl.max_packets = max_packets;
+        if preallocate > fl.allocated {
+            let n = preallocate - fl.allocated;
+            if let Err(error) = grow(pool, fl, n) {
+                panic!("Failed to allocate packets for pool {}: {}", fl.name, error);
+            }
+        }
+    })
+}
+
+// Fill up freelist with n freshly allocated packets, fails (after adding as
+// many packets as possible) if DMA (or heap) memory runs out.
 // NB: use DMA allocator if run as root, regular heap allocator otherwise.
-static mut PACKETS_ALLOCATED: usize = 0;
-static mut PACKET_ALLOCATION_STEP: usize = 1000;
-fn preallocate_step() {
+fn grow(pool: Pool, fl: &mut Freelist, n: usize) -> io::Result<()> {
     let new_packet = match unsafe { libc::getuid() } {
         0 => new_packet,
         _ => new_packet_noroot,
     };
-    unsafe {
-        assert!(
-            PACKETS_ALLOCATED + PACKET_ALLOCATION_STEP <= MAX_PACKETS,
-            "Packet allocation overflow"
-        );
-
-        for _ in 0..PACKET_ALLOCATION_STEP {
-            free_internal(new_packet());
-        }
-        PACKETS_ALLOCATED += PACKET_ALLOCATION_STEP;
-        PACKET_ALLOCATION_STEP *= 2;
+    fl.list.reserve(n);
+    for _ in 0..n {
+        fl.list.push(new_packet(pool, fl.buffer_size)?);
+        fl.allocated += 1;
     }
+    Ok(())
 }
 
-// Allocate an empty Boxed Packet from FL.
-// NB: we can use Box::from_raw safely on the packets "leaked" onto
-// the static FL. We can also be sure that the Box does not alias another
-// packet (see free).
+// Grow freelist on demand, by an exponentially increasing number of packets
+// (up to max_packets).
+fn preallocate_step(pool: Pool, fl: &mut Freelist) -> io::Result<()> {
+    let n = cmp::min(fl.allocation_step, fl.max_packets - fl.allocated);
+    fl.allocation_step *= 2;
+    fl.growths += 1;
+    grow(pool, fl, n)
+}
+
+// Allocate an empty PacketBox from the default pool.
 #[inline(always)]
-pub fn allocate() -> Box<Packet> {
-    if unsafe { FL.nfree == 0 } {
-        preallocate_step();
+pub fn allocate() -> PacketBox {
+    allocate_from(DEFAULT_POOL)
+}
+
+// Allocate an empty PacketBox from pool.
+// NB: panics if the pool is exhausted, use try_allocate_from in code paths
+// that can handle allocation failure (e.g., by dropping packets).
+#[inline(always)]
+pub fn allocate_from(pool: Pool) -> PacketBox {
+    match try_allocate_from(pool) {
+        Some(p) => p,
+        None => panic!("Packet pool {} exhausted", pool_stats(pool).name),
     }
-    unsafe {
-        FL.nfree -= 1;
+}
+
+// Allocate an empty PacketBox from the default pool, or return None if the
+// pool is exhausted.
+#[inline(always)]
+pub fn try_allocate() -> Option<PacketBox> {
+    try_allocate_from(DEFAULT_POOL)
+}
+
+// Allocate an empty PacketBox from pool, or return None if the pool is
+// exhausted (i.e., max_packets are in use, see init). Failed allocations are
+// counted (see PoolStats).
+// NB: we can use PacketBox::from_raw safely on the packets on the static
+// freelists. We can also be sure that the PacketBox does not alias another
+// packet (see free_internal).
+#[inline(always)]
+pub fn try_allocate_from(pool: Pool) -> Option<PacketBox> {
+    let p = with_freelist(pool, |fl| {
+        if fl.list.is_empty() {
+            if fl.allocated == fl.max_packets {
+                fl.failures += 1;
+                return None;
+            }
+            // NB: fails like an exhausted pool if memory runs out
+            if preallocate_step(pool, fl).is_err() && fl.list.is_empty() {
+                fl.failures += 1;
+                return None;
+            }
+        }
+        let p = fl.list.pop().unwrap();
+        fl.high_water = cmp::max(fl.high_water, fl.allocated - fl.list.len());
+        Some(p)
+    })?;
+    let mut p = unsafe { PacketBox::from_raw(p) };
+    if debug() {
+        tag(&p);
     }
-    unsafe { Box::from_raw(FL.list[FL.nfree]) }
+    p.meta = Default::default();
+    Some(p)
 }
 
-// Return Boxed Packet to FL.
-// NB: because p is mutable and Box does not implement the Copy trait free
-// effectively consumes the Box. Once a packet is freed it can no longer be
-// referenced, and hence can not me mutated once it has been returned to the
+// Put packet back onto the freelist of its pool.
+// NB: only ever called with packets released by a Dropped PacketBox, which
+// are not owned by anyone else. Once a packet is freed it can no longer be
+// referenced, and hence can not be mutated once it has been returned to the
 // freelist.
-// NB: we can cast a mutable reference of the boxed packet (&mut *p) to a raw
-// pointer.
-// NB: we std::mem::forget the Box p to inhibit Dropping of the packet once it
-// is on the freelist. (I.e., we intentionally leak up to MAX_PACKETS packets
-// onto the static FL.) If a packet goes out of scope without being freed, the
-// attempt to Drop it will trigger a panic (see Packet). Hence we ensure that
-// all allocated packets are eventually freed.
-fn free_internal(mut p: Box<Packet>) {
-    assert!(
-        unsafe { FL.nfree } != MAX_PACKETS,
-        "Packet freelist overflow"
-    );
+fn free_internal(p: *mut Packet) {
+    if debug() {
+        untag(p);
+    }
 
-    p.length = 0;
     unsafe {
-        FL.list[FL.nfree] = &mut *p;
+        (*p).length = 0;
     }
-    mem::forget(p);
-    unsafe {
-        FL.nfree += 1;
+    with_freelist(unsafe { (*p).pool }, |fl| {
+        assert!(fl.list.len() != fl.allocated, "Packet freelist overflow");
+        fl.list.push(p);
+    });
+}
+
+// Return PacketBox to its freelist.
+// NB: PacketBox does not implement the Copy trait so free consumes p, which
+// is then Dropped (see PacketBox). Calling free is equivalent to letting p go
+// out of scope, but documents intent.
+pub fn free(p: PacketBox) {
+    drop(p);
+}
+
+// Return the segments of p to their freelists without accounting for them in
+// the engine statistics (e.g., segments merged by linearize, or the tail of a
+// packet that is being freed).
+fn release(p: PacketBox) {
+    let mut next = Some(p);
+    while let Some(mut seg) = next {
+        next = seg.take_next();
+        free_internal(PacketBox::into_raw(seg));
+    }
+}
+
+// Debug mode: tag each allocation (e.g., with the name of the allocating app,
+// see engine::breathe) so that the whereabouts of packets that are in use can
+// be determined (see engine::report_packets). Like the pools, debug mode and
+// tags are per thread (and TAGS is leaked, see POOLS).
+struct Tags {
+    current: Option<Rc<str>>,
+    live: HashMap<*const u8, Rc<str>>,
+}
+
+thread_local! {
+    static DEBUG: Cell<bool> = const { Cell::new(false) };
+    static TAGS: &'static RefCell<Tags> = Box::leak(Box::new(RefCell::new(Tags {
+        current: None,
+        live: HashMap::new(),
+    })));
+}
+
+fn with_tags<R>(f: impl FnOnce(&mut Tags) -> R) -> R {
+    TAGS.with(|tags| f(&mut tags.borrow_mut()))
+}
+
+// Enable/disable debug mode.
+// NB: disabling debug mode forgets all tags.
+pub fn set_debug(enabled: bool) {
+    DEBUG.with(|debug| debug.set(enabled));
+    if !enabled {
+        with_tags(|tags| tags.live.clear());
+    }
+}
+
+pub fn debug() -> bool {
+    DEBUG.with(Cell::get)
+}
+
+// Tag subsequent allocations with tag (or leave them untagged if tag is None).
+// Has no effect unless debug mode is enabled.
+pub fn set_allocation_tag(tag: Option<&str>) {
+    if debug() {
+        with_tags(|tags| tags.current = tag.map(Rc::from));
     }
 }
-pub fn free(p: Box<Packet>) {
-    engine::add_frees();
-    engine::add_freebytes(p.length as u64);
-    // Calculate bits of physical capacity required for packet on 10GbE
-    // Account for minimum data size and overhead of Ethernet preamble, CRC,
-    // and inter-packet gap
-    // https://netoptimizer.blogspot.com/2014/05/the-calculations-10gbits-wirespeed.html
-    engine::add_freebits((12 + 8 + cmp::max(p.length as u64, 60) + 4) * 8);
-    free_internal(p);
+
+fn tag(p: &Packet) {
+    with_tags(|tags| {
+        let tag = match &tags.current {
+            Some(tag) => tag.clone(),
+            None => Rc::from("(untagged)"),
+        };
+        tags.live.insert(p.data.as_ptr(), tag);
+    })
 }
 
-// Clone a packet
-pub fn clone(p: &Packet) -> Box<Packet> {
-    let mut copy = allocate();
-    lib::copy(&mut copy.data, &p.data, p.length as usize);
-    copy.length = p.length;
+fn untag(p: *const Packet) {
+    with_tags(|tags| tags.live.remove(&unsafe { (*p).data.as_ptr() }));
+}
+
+// Return the number of packets currently in use by allocation tag.
+// NB: only packets allocated while debug mode was enabled are accounted for.
+pub fn outstanding_allocations() -> HashMap<String, usize> {
+    let mut outstanding = HashMap::new();
+    with_tags(|tags| {
+        for tag in tags.live.values() {
+            *outstanding.entry(tag.to_string()).or_insert(0) += 1;
+        }
+    });
+    outstanding
+}
+
+// Clone a packet (each segment of the copy is allocated from the same pool as
+// the corresponding segment of p)
+// NB: panics if a pool is exhausted, see try_clone.
+pub fn clone(p: &Packet) -> PacketBox {
+    match try_clone(p) {
+        Some(copy) => copy,
+        None => panic!("Packet pool {} exhausted", pool_stats(p.pool).name),
+    }
+}
+
+// Clone a packet, or return None if a pool is exhausted.
+pub fn try_clone(p: &Packet) -> Option<PacketBox> {
+    let mut copy = clone_segment(p)?;
+    copy.meta = p.meta;
+    for seg in p.segments().skip(1) {
+        match clone_segment(seg) {
+            Some(seg) => copy.append(seg),
+            None => {
+                release(copy);
+                return None;
+            }
+        }
+    }
+    Some(copy)
+}
+
+// Allocate a packet from pool and copy data into it. Data that does not fit
+// into a single buffer is spread across multiple segments.
+// NB: panics if the pool is exhausted, see try_from_data.
+pub fn from_data(pool: Pool, data: &[u8]) -> PacketBox {
+    match try_from_data(pool, data) {
+        Some(p) => p,
+        None => panic!("Packet pool {} exhausted", pool_stats(pool).name),
+    }
+}
+
+// Like from_data, but return None if the pool is exhausted.
+pub fn try_from_data(pool: Pool, data: &[u8]) -> Option<PacketBox> {
+    let buffer_size = cmp::min(buffer_size(pool), u16::MAX as usize);
+    let mut chunks = data.chunks(buffer_size);
+    let mut p = try_allocate_from(pool)?;
+    if let Some(chunk) = chunks.next() {
+        lib::copy(&mut p.data, chunk, chunk.len());
+        p.length = chunk.len() as u16;
+    }
+    for chunk in chunks {
+        let mut seg = match try_allocate_from(pool) {
+            Some(seg) => seg,
+            None => {
+                release(p);
+                return None;
+            }
+        };
+        lib::copy(&mut seg.data, chunk, chunk.len());
+        seg.length = chunk.len() as u16;
+        p.append(seg);
+    }
+    Some(p)
+}
+
+fn clone_segment(seg: &Packet) -> Option<PacketBox> {
+    let mut copy = try_allocate_from(seg.pool)?;
+    lib::copy(&mut copy.data, &seg.data, seg.length as usize);
+    copy.length = seg.length;
+    Some(copy)
+}
+
+// Copy the payload of a multi-segment packet into a single segment.
+//
+// The head segment is reused if its buffer is large enough, otherwise the
+// packet is copied to a packet allocated from the pool with the smallest
+// buffers that fit. Panics if there is no such pool.
+pub fn linearize(mut p: PacketBox) -> PacketBox {
+    let length = p.total_length();
+    let next = match p.take_next() {
+        Some(next) => next,
+        None => return p,
+    };
+    assert!(length <= u16::MAX as usize, "Packet too long to linearize");
+    let mut offset = p.length as usize;
+    if p.data.len() >= length {
+        for seg in next.segments() {
+            lib::copy(&mut p.data[offset..], &seg.data, seg.length as usize);
+            offset += seg.length as usize;
+        }
+        p.length = length as u16;
+        release(next);
+        return p;
+    }
+    let pool = pools()
+        .into_iter()
+        .filter(|&pool| buffer_size(pool) >= length)
+        .min_by_key(|&pool| buffer_size(pool))
+        .expect("No packet pool with buffers large enough to linearize packet");
+    let mut copy = allocate_from(pool);
+    lib::copy(&mut copy.data, &p.data, offset);
+    for seg in next.segments() {
+        lib::copy(&mut copy.data[offset..], &seg.data, seg.length as usize);
+        offset += seg.length as usize;
+    }
+    copy.length = length as u16;
+    copy.meta = p.meta;
+    release(next);
+    release(p);
     copy
 }
 
@@ -177,8 +811,202 @@ mod selftest {
             p.length, p.data[0]
         );
         let len = p.length;
-        free(p); // Not freeing would cause panic
+        free(p); // Not freeing would free p when it goes out of scope
         println!("Freed a packet of length {}", len);
         //p.length = 2; // Would cause compile error
     }
+
+    #[test]
+    fn drop_frees() {
+        // NB: use a private pool, other tests allocate concurrently
+        let pool = new_pool("selftest_drop", 64);
+        free(allocate_from(pool)); // Make sure the freelist is populated
+        let nfree = pool_stats(pool).free;
+        {
+            let mut p = allocate_from(pool);
+            p.length = 42;
+        }
+        assert_eq!(pool_stats(pool).free, nfree, "Dropped packet not freed");
+        let ptr = PacketBox::into_raw(allocate_from(pool));
+        assert_eq!(pool_stats(pool).free, nfree - 1, "Raw packet freed");
+        free(unsafe { PacketBox::from_raw(ptr) });
+        assert_eq!(pool_stats(pool).free, nfree, "Reclaimed packet not freed");
+    }
+
+    #[test]
+    fn pool_alloc() {
+        let small = new_pool("selftest_small", 2048);
+        assert_eq!(new_pool("selftest_small", 2048), small);
+        assert_eq!(pool("selftest_small"), Some(small));
+        assert_eq!(pool("selftest_nonexistent"), None);
+        let mut p = allocate_from(small);
+        assert_eq!(p.data.len(), 2048);
+        assert_eq!(p.pool(), small);
+        p.length = 2048;
+        p.data[2047] = 42;
+        let c = clone(&p);
+        assert_eq!(c.pool(), small);
+        assert_eq!(c.data[2047], 42);
+        let d = allocate();
+        assert_eq!(d.data.len(), PAYLOAD_SIZE);
+        assert_eq!(d.pool(), DEFAULT_POOL);
+        let stats = pool_stats(small);
+        assert_eq!(stats.free, stats.allocated - 2);
+        free(p);
+        free(c);
+        free(d);
+        let stats = pool_stats(small);
+        assert_eq!(stats.free, stats.allocated);
+        assert_eq!(stats.in_use, 0);
+        assert!(stats.high_water >= 2);
+        assert!(stats.growths >= 1);
+        println!(
+            "pool {}: buffer_size={} allocated={} free={}",
+            stats.name, stats.buffer_size, stats.allocated, stats.free
+        );
+    }
+
+    #[test]
+    fn preallocate() {
+        let pool = new_pool("selftest_capped", 1024);
+        init(pool, 10, 10);
+        let stats = pool_stats(pool);
+        assert_eq!(stats.allocated, 10);
+        assert_eq!(stats.growths, 0);
+        let mut packets: Vec<_> = (0..9).map(|_| allocate_from(pool)).collect();
+        // multi-segment allocations fail as a whole
+        assert!(try_from_data(pool, &[0; 2048]).is_none());
+        assert_eq!(pool_stats(pool).free, 1);
+        packets.push(allocate_from(pool));
+        assert!(
+            try_allocate_from(pool).is_none(),
+            "Pool should be exhausted"
+        );
+        assert!(try_clone(&packets[0]).is_none());
+        assert_eq!(pool_stats(pool).failures, 3);
+        drop(packets);
+        init(pool, 10, 100);
+        let packets: Vec<_> = (0..100).map(|_| allocate_from(pool)).collect();
+        let stats = pool_stats(pool);
+        assert_eq!(stats.allocated, 100);
+        assert!(stats.growths > 0);
+        assert!(
+            try_allocate_from(pool).is_none(),
+            "Pool should be exhausted"
+        );
+        drop(packets);
+    }
+
+    #[test]
+    fn pool_at() {
+        let stride = header_size() + 256;
+        let memory: &'static mut [u64] =
+            Box::leak(vec![0u64; stride * 4 / 8 + 1].into_boxed_slice());
+        let data = unsafe { (memory.as_mut_ptr() as *mut u8).add(header_size()) };
+        let pool = unsafe { new_pool_at("selftest_at", 200, data, stride, 4) };
+        let packets: Vec<_> = (0..4).map(|_| allocate_from(pool)).collect();
+        assert!(
+            try_allocate_from(pool).is_none(),
+            "Pool should be exhausted"
+        );
+        for p in &packets {
+            let offset = p.data.as_ptr() as usize - data as usize;
+            assert_eq!(offset % stride, 0);
+            assert!(offset / stride < 4);
+            assert_eq!(p.data.len(), 200);
+        }
+        drop(packets);
+        let stats = pool_stats(pool);
+        assert_eq!((stats.allocated, stats.free, stats.growths), (4, 4, 0));
+    }
+
+    #[test]
+    fn segments() {
+        let small = new_pool("selftest_segments", 64);
+        let mut p = allocate_from(small);
+        p.meta.vlan = 42;
+        for n in 0..4u8 {
+            let mut seg = allocate_from(small);
+            seg.length = 64;
+            lib::fill(&mut seg.data, 64, n);
+            p.append(seg);
+        }
+        p.length = 10;
+        lib::fill(&mut p.data, 10, 0xff);
+        assert_eq!(p.nsegments(), 5);
+        assert_eq!(p.total_length(), 10 + 4 * 64);
+        assert_eq!(p.next().unwrap().data[0], 0);
+        let c = clone(&p);
+        assert_eq!(c.nsegments(), 5);
+        assert_eq!(c.meta, p.meta);
+        assert_eq!(c.segments().last().unwrap().data[63], 3);
+        free(c);
+        // does not fit a single 64 byte buffer, copied to a larger pool
+        let l = linearize(p);
+        assert_eq!(l.nsegments(), 1);
+        assert!(l.data.len() >= 10 + 4 * 64);
+        assert_eq!(l.length as usize, 10 + 4 * 64);
+        assert_eq!(l.meta.vlan, 42);
+        assert_eq!(l.data[9], 0xff);
+        assert_eq!(l.data[10 + 2 * 64], 2);
+        free(l);
+        // fits into the head segment
+        let mut p = allocate();
+        p.length = 1;
+        let mut seg = allocate_from(small);
+        seg.length = 2;
+        seg.data[1] = 7;
+        p.append(seg);
+        let l = linearize(p);
+        assert_eq!(l.pool(), DEFAULT_POOL);
+        assert_eq!(l.length, 3);
+        assert_eq!(l.data[2], 7);
+        free(l);
+        let stats = pool_stats(small);
+        assert_eq!(stats.free, stats.allocated, "Segments not freed");
+        let data: Vec<u8> = (0..150).collect();
+        let p = from_data(small, &data);
+        assert_eq!(p.nsegments(), 3);
+        assert_eq!(p.total_length(), 150);
+        assert_eq!(p.segments().last().unwrap().data[149 - 128], 149);
+        let p = linearize(p);
+        assert_eq!(&p.data[..150], &data[..]);
+    }
+
+    #[test]
+    fn allocation_tags() {
+        set_debug(true);
+        set_allocation_tag(Some("selftest"));
+        let p = allocate();
+        let q = allocate();
+        set_allocation_tag(None);
+        let r = allocate();
+        let outstanding = outstanding_allocations();
+        assert_eq!(outstanding.get("selftest"), Some(&2));
+        assert_eq!(outstanding.get("(untagged)"), Some(&1));
+        free(p);
+        free(r);
+        let outstanding = outstanding_allocations();
+        assert_eq!(outstanding.get("selftest"), Some(&1));
+        assert_eq!(outstanding.get("(untagged)"), None);
+        free(q);
+        set_debug(false);
+        assert!(outstanding_allocations().is_empty());
+    }
+
+    #[test]
+    fn metadata() {
+        let mut p = allocate();
+        assert_eq!(p.meta, Default::default(), "Metadata not cleared");
+        p.meta.rss_hash = 0xdeadbeef;
+        p.meta.vlan = 42;
+        p.meta.flags = META_RSS_HASH | META_VLAN;
+        let c = clone(&p);
+        assert_eq!(c.meta, p.meta, "Metadata not copied");
+        free(c);
+        free(p);
+        let p = allocate();
+        assert_eq!(p.meta, Default::default(), "Metadata not cleared");
+        free(p);
+    }
 }
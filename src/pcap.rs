@@ -0,0 +1,328 @@
+// PCAP FILE FORMAT
+//
+// This module implements reading and writing of packet captures in the
+// classic libpcap file format
+// (https://wiki.wireshark.org/Development/LibpcapFileFormat), and writing of
+// captures in the pcapng format (https://github.com/pcapng/pcapng).
+//
+//   LINKTYPE_ETHERNET - link type of Ethernet captures
+//   write_file_header(&mut Write, snaplen) - write a pcap file header
+//   write_record(&mut Write, timestamp, data:&[u8], orig_len) - write a record
+//   FileHeader - parsed pcap file header
+//   Record - parsed pcap record header (timestamp, original length)
+//   read_file_header(&mut Read) -> FileHeader - read a pcap file header
+//   read_record(&mut Read, &FileHeader, &mut Vec<u8>) -> Option<Record> - read
+//     the next record (None at the end of the file, InvalidData if the record
+//     is larger than the snaplen of the file or MAX_SNAPLEN)
+//   write_pcapng_header(&mut Write, snaplen) -> usize - write pcapng section
+//     header and interface description blocks
+//   write_pcapng_record(&mut Write, timestamp, data:&[u8], orig_len) -> usize
+//     - write a pcapng enhanced packet block
+//   record_size(data_len) -> usize - size of a pcap record
+//   copy_data(&mut Vec<u8>, &Packet, snaplen) - copy the data to capture of a
+//     packet
+//   pcapng_record_size(data_len) -> usize - size of a pcapng packet block
+
+use super::packet;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NativeEndian, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+// Magic numbers of pcap files with microsecond and nanosecond resolution
+// timestamps.
+const MAGIC_MICROSECONDS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOSECONDS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+// Largest record accepted by read_record (the maximum snaplen of libpcap).
+pub const MAX_SNAPLEN: usize = 262_144;
+
+// Write a pcap file header for Ethernet captures of up to snaplen bytes per
+// packet (timestamps have nanosecond resolution, native byte order).
+pub fn write_file_header(w: &mut dyn Write, snaplen: u32) -> io::Result<()> {
+    w.write_u32::<NativeEndian>(MAGIC_NANOSECONDS)?;
+    w.write_u16::<NativeEndian>(VERSION_MAJOR)?;
+    w.write_u16::<NativeEndian>(VERSION_MINOR)?;
+    w.write_i32::<NativeEndian>(0)?; // thiszone: GMT
+    w.write_u32::<NativeEndian>(0)?; // sigfigs
+    w.write_u32::<NativeEndian>(snaplen)?;
+    w.write_u32::<NativeEndian>(LINKTYPE_ETHERNET)
+}
+
+// Write a packet record.
+//
+// write_record(w, timestamp, data, orig_len):
+//   timestamp is the capture time in nanoseconds since the UNIX epoch.
+//   data is the captured (possibly truncated) packet data.
+//   orig_len is the length of the packet on the wire.
+pub fn write_record(
+    w: &mut dyn Write,
+    timestamp: u64,
+    data: &[u8],
+    orig_len: usize,
+) -> io::Result<()> {
+    w.write_u32::<NativeEndian>((timestamp / 1_000_000_000) as u32)?;
+    w.write_u32::<NativeEndian>((timestamp % 1_000_000_000) as u32)?;
+    w.write_u32::<NativeEndian>(data.len() as u32)?;
+    w.write_u32::<NativeEndian>(orig_len as u32)?;
+    w.write_all(data)
+}
+
+// Size of the file header and record headers in bytes.
+pub const FILE_HEADER_SIZE: usize = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub big_endian: bool,  // byte order of the file
+    pub nanoseconds: bool, // timestamp resolution (microseconds otherwise)
+    pub snaplen: u32,
+    pub linktype: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub timestamp: u64, // nanoseconds since the UNIX epoch
+    pub orig_len: usize,
+}
+
+// Read a pcap file header (in either byte order, with either timestamp
+// resolution). Fails with InvalidData if r does not start with a pcap header.
+pub fn read_file_header(r: &mut dyn Read) -> io::Result<FileHeader> {
+    let mut buf = [0; FILE_HEADER_SIZE];
+    r.read_exact(&mut buf)?;
+    let (big_endian, nanoseconds) = match (
+        LittleEndian::read_u32(&buf[0..4]),
+        BigEndian::read_u32(&buf[0..4]),
+    ) {
+        (MAGIC_MICROSECONDS, _) => (false, false),
+        (MAGIC_NANOSECONDS, _) => (false, true),
+        (_, MAGIC_MICROSECONDS) => (true, false),
+        (_, MAGIC_NANOSECONDS) => (true, true),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pcap file (bad magic number)",
+            ))
+        }
+    };
+    Ok(FileHeader {
+        big_endian,
+        nanoseconds,
+        snaplen: read_u32(&buf[16..20], big_endian),
+        linktype: read_u32(&buf[20..24], big_endian),
+    })
+}
+
+// Read the next record of a pcap file into data, return its header (or None
+// at the end of the file). Fails with InvalidData if the record is larger
+// than the snaplen of the file (or MAX_SNAPLEN).
+// NB: a truncated last record is treated like the end of the file.
+pub fn read_record(
+    r: &mut dyn Read,
+    header: &FileHeader,
+    data: &mut Vec<u8>,
+) -> io::Result<Option<Record>> {
+    let mut buf = [0; RECORD_HEADER_SIZE];
+    match r.read_exact(&mut buf) {
+        Ok(()) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let seconds = read_u32(&buf[0..4], header.big_endian) as u64;
+    let fraction = read_u32(&buf[4..8], header.big_endian) as u64;
+    let incl_len = read_u32(&buf[8..12], header.big_endian) as usize;
+    let orig_len = read_u32(&buf[12..16], header.big_endian) as usize;
+    if incl_len > header.snaplen as usize || incl_len > MAX_SNAPLEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record of {} bytes exceeds snaplen", incl_len),
+        ));
+    }
+    data.resize(incl_len, 0);
+    match r.read_exact(data) {
+        Ok(()) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let fraction = if header.nanoseconds {
+        fraction
+    } else {
+        fraction * 1000
+    };
+    Ok(Some(Record {
+        timestamp: seconds * 1_000_000_000 + fraction,
+        orig_len,
+    }))
+}
+
+// Size of a record with data_len bytes of data.
+pub fn record_size(data_len: usize) -> usize {
+    RECORD_HEADER_SIZE + data_len
+}
+
+// Copy up to snaplen bytes of p’s payload (from all segments) to data.
+pub fn copy_data(data: &mut Vec<u8>, p: &packet::Packet, snaplen: usize) {
+    data.clear();
+    for seg in p.segments() {
+        let n = (snaplen - data.len()).min(seg.length as usize);
+        data.extend_from_slice(&seg.data[..n]);
+    }
+}
+
+// pcapng block types and options
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPT_ENDOFOPT: u16 = 0;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+
+const PCAPNG_SECTION_HEADER_SIZE: usize = 28;
+const PCAPNG_INTERFACE_DESCRIPTION_SIZE: usize = 32;
+const PCAPNG_ENHANCED_PACKET_SIZE: usize = 32; // without data
+
+// Write a pcapng section header block, followed by the description of a
+// single Ethernet interface with nanosecond resolution timestamps. Return the
+// number of bytes written.
+pub fn write_pcapng_header(w: &mut dyn Write, snaplen: u32) -> io::Result<usize> {
+    w.write_u32::<NativeEndian>(PCAPNG_SECTION_HEADER)?;
+    w.write_u32::<NativeEndian>(PCAPNG_SECTION_HEADER_SIZE as u32)?;
+    w.write_u32::<NativeEndian>(PCAPNG_BYTE_ORDER_MAGIC)?;
+    w.write_u16::<NativeEndian>(1)?; // major version
+    w.write_u16::<NativeEndian>(0)?; // minor version
+    w.write_i64::<NativeEndian>(-1)?; // section length: unspecified
+    w.write_u32::<NativeEndian>(PCAPNG_SECTION_HEADER_SIZE as u32)?;
+
+    w.write_u32::<NativeEndian>(PCAPNG_INTERFACE_DESCRIPTION)?;
+    w.write_u32::<NativeEndian>(PCAPNG_INTERFACE_DESCRIPTION_SIZE as u32)?;
+    w.write_u16::<NativeEndian>(LINKTYPE_ETHERNET as u16)?;
+    w.write_u16::<NativeEndian>(0)?; // reserved
+    w.write_u32::<NativeEndian>(snaplen)?;
+    w.write_u16::<NativeEndian>(PCAPNG_OPT_IF_TSRESOL)?;
+    w.write_u16::<NativeEndian>(1)?;
+    w.write_all(&[9, 0, 0, 0])?; // 10^-9 seconds (padded)
+    w.write_u16::<NativeEndian>(PCAPNG_OPT_ENDOFOPT)?;
+    w.write_u16::<NativeEndian>(0)?;
+    w.write_u32::<NativeEndian>(PCAPNG_INTERFACE_DESCRIPTION_SIZE as u32)?;
+
+    Ok(PCAPNG_SECTION_HEADER_SIZE + PCAPNG_INTERFACE_DESCRIPTION_SIZE)
+}
+
+// Size of an enhanced packet block with data_len bytes of data.
+pub fn pcapng_record_size(data_len: usize) -> usize {
+    PCAPNG_ENHANCED_PACKET_SIZE + ((data_len + 3) & !3)
+}
+
+// Write a pcapng enhanced packet block (for the interface described by
+// write_pcapng_header), return the number of bytes written.
+pub fn write_pcapng_record(
+    w: &mut dyn Write,
+    timestamp: u64,
+    data: &[u8],
+    orig_len: usize,
+) -> io::Result<usize> {
+    let size = pcapng_record_size(data.len());
+    w.write_u32::<NativeEndian>(PCAPNG_ENHANCED_PACKET)?;
+    w.write_u32::<NativeEndian>(size as u32)?;
+    w.write_u32::<NativeEndian>(0)?; // interface id
+    w.write_u32::<NativeEndian>((timestamp >> 32) as u32)?;
+    w.write_u32::<NativeEndian>(timestamp as u32)?;
+    w.write_u32::<NativeEndian>(data.len() as u32)?;
+    w.write_u32::<NativeEndian>(orig_len as u32)?;
+    w.write_all(data)?;
+    w.write_all(&[0; 3][..size - PCAPNG_ENHANCED_PACKET_SIZE - data.len()])?;
+    w.write_u32::<NativeEndian>(size as u32)?;
+    Ok(size)
+}
+
+fn read_u32(buf: &[u8], big_endian: bool) -> u32 {
+    if big_endian {
+        BigEndian::read_u32(buf)
+    } else {
+        LittleEndian::read_u32(buf)
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+
+    #[test]
+    fn write() {
+        let mut buf = Vec::new();
+        write_file_header(&mut buf, 128).unwrap();
+        assert_eq!(buf.len(), 24);
+        assert_eq!(&buf[0..4], &MAGIC_NANOSECONDS.to_ne_bytes());
+        write_record(&mut buf, 1_500_000_042, &[1, 2, 3], 60).unwrap();
+        assert_eq!(buf.len(), 24 + 16 + 3);
+        assert_eq!(&buf[24..28], &1u32.to_ne_bytes());
+        assert_eq!(&buf[28..32], &500_000_042u32.to_ne_bytes());
+        assert_eq!(&buf[32..36], &3u32.to_ne_bytes());
+        assert_eq!(&buf[36..40], &60u32.to_ne_bytes());
+        assert_eq!(&buf[40..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn read() {
+        let mut buf = Vec::new();
+        write_file_header(&mut buf, 128).unwrap();
+        write_record(&mut buf, 1_500_000_042, &[1, 2, 3], 60).unwrap();
+        write_record(&mut buf, 2_000_000_000, &[4], 1).unwrap();
+        buf.extend_from_slice(&[0, 1]); // truncated record
+        let mut r = &buf[..];
+        let header = read_file_header(&mut r).unwrap();
+        assert!(header.nanoseconds);
+        assert_eq!((header.snaplen, header.linktype), (128, LINKTYPE_ETHERNET));
+        let mut data = Vec::new();
+        let record = read_record(&mut r, &header, &mut data).unwrap().unwrap();
+        assert_eq!(record.timestamp, 1_500_000_042);
+        assert_eq!((record.orig_len, &data[..]), (60, &[1, 2, 3][..]));
+        let record = read_record(&mut r, &header, &mut data).unwrap().unwrap();
+        assert_eq!(record.timestamp, 2_000_000_000);
+        assert_eq!(&data[..], &[4]);
+        assert_eq!(read_record(&mut r, &header, &mut data).unwrap(), None);
+        // big endian, microsecond resolution
+        let mut buf = vec![0xa1, 0xb2, 0xc3, 0xd4, 0, 2, 0, 4];
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&[0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+        buf.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 64, 42]);
+        let mut r = &buf[..];
+        let header = read_file_header(&mut r).unwrap();
+        assert!(header.big_endian && !header.nanoseconds);
+        assert_eq!(header.snaplen, 65535);
+        let record = read_record(&mut r, &header, &mut data).unwrap().unwrap();
+        assert_eq!(record.timestamp, 1_000_002_000);
+        assert_eq!((record.orig_len, &data[..]), (64, &[42][..]));
+        let mut r = &[0u8; 24][..];
+        assert!(read_file_header(&mut r).is_err());
+        // records larger than the snaplen are rejected
+        let mut buf = Vec::new();
+        write_file_header(&mut buf, 2).unwrap();
+        write_record(&mut buf, 0, &[1, 2, 3], 3).unwrap();
+        let mut r = &buf[..];
+        let header = read_file_header(&mut r).unwrap();
+        let error = read_record(&mut r, &header, &mut data).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn write_pcapng() {
+        let mut buf = Vec::new();
+        assert_eq!(write_pcapng_header(&mut buf, 128).unwrap(), buf.len());
+        assert_eq!(&buf[0..4], &PCAPNG_SECTION_HEADER.to_ne_bytes());
+        assert_eq!(&buf[28..32], &PCAPNG_INTERFACE_DESCRIPTION.to_ne_bytes());
+        let size = write_pcapng_record(&mut buf, 1 << 32 | 7, &[1, 2, 3, 4, 5], 60).unwrap();
+        assert_eq!(size, 32 + 8);
+        let block = &buf[60..];
+        assert_eq!(block.len(), size);
+        assert_eq!(&block[4..8], &(size as u32).to_ne_bytes());
+        assert_eq!(&block[12..16], &1u32.to_ne_bytes());
+        assert_eq!(&block[16..20], &7u32.to_ne_bytes());
+        assert_eq!(&block[28..33], &[1, 2, 3, 4, 5]);
+        assert_eq!(&block[size - 4..], &(size as u32).to_ne_bytes());
+    }
+}
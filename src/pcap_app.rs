@@ -0,0 +1,530 @@
+use super::engine;
+use super::lib;
+use super::link;
+use super::packet;
+use super::pcap;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// PcapReader app: replay packets from a pcap file on output
+//
+//   path: pcap file to read (Ethernet captures in either byte order, µs or ns
+//     timestamps)
+//   looping: start over at the end of the file
+//   limit: maximum number of packets to send (unlimited if None)
+//   realtime: replay at the original inter-packet timing, relative to the
+//     engine time (at full speed otherwise)
+//
+// Packets carry their capture timestamp in their metadata.
+
+#[derive(Clone, Debug)]
+pub struct PcapReader {
+    pub path: String,
+    pub looping: bool,
+    pub limit: Option<u64>,
+    pub realtime: bool,
+}
+impl engine::AppConfig for PcapReader {
+    fn new(&self) -> Box<dyn engine::App> {
+        let file = File::open(&self.path)
+            .unwrap_or_else(|error| panic!("Failed to open {}: {}", self.path, error));
+        let mut file = BufReader::new(file);
+        let header = pcap::read_file_header(&mut file)
+            .unwrap_or_else(|error| panic!("Failed to read {}: {}", self.path, error));
+        if header.linktype != pcap::LINKTYPE_ETHERNET {
+            panic!(
+                "{} is not an Ethernet capture (link type {})",
+                self.path, header.linktype
+            );
+        }
+        Box::new(PcapReaderApp {
+            conf: self.clone(),
+            state: RefCell::new(ReaderState {
+                file,
+                header,
+                data: Vec::new(),
+                next: None,
+                pass_records: 0,
+                sent: 0,
+                start: None,
+                done: false,
+            }),
+        })
+    }
+}
+pub struct PcapReaderApp {
+    conf: PcapReader,
+    state: RefCell<ReaderState>,
+}
+struct ReaderState {
+    file: BufReader<File>,
+    header: pcap::FileHeader,
+    data: Vec<u8>,                 // data of the next record
+    next: Option<pcap::Record>,    // next record (read, but not yet sent)
+    pass_records: u64,             // records read in the current pass
+    sent: u64,                     // packets sent
+    start: Option<(Instant, u64)>, // replay start (time, timestamp)
+    done: bool,
+}
+impl ReaderState {
+    // Read the next record (unless it has already been read), starting over at
+    // the end of the file if looping. Return None when done.
+    fn next_record(&mut self, looping: bool) -> Option<pcap::Record> {
+        while self.next.is_none() && !self.done {
+            match pcap::read_record(&mut self.file, &self.header, &mut self.data) {
+                Ok(Some(record)) => {
+                    self.next = Some(record);
+                    self.pass_records += 1;
+                }
+                Ok(None) if looping && self.pass_records > 0 => {
+                    let start = SeekFrom::Start(pcap::FILE_HEADER_SIZE as u64);
+                    if let Err(error) = self.file.seek(start) {
+                        println!("PcapReader failed to rewind: {}", error);
+                        self.done = true;
+                    }
+                    self.pass_records = 0;
+                    self.start = None;
+                }
+                Ok(None) => self.done = true,
+                Err(error) => {
+                    println!("PcapReader failed to read record: {}", error);
+                    self.done = true;
+                }
+            }
+        }
+        self.next
+    }
+
+    // Return true if record is due to be sent at now (always true at full
+    // speed).
+    fn due(&mut self, record: &pcap::Record, realtime: bool, now: Instant) -> bool {
+        if !realtime {
+            return true;
+        }
+        match self.start {
+            Some((start, first)) => {
+                let offset = Duration::from_nanos(record.timestamp.saturating_sub(first));
+                now >= start + offset
+            }
+            None => {
+                self.start = Some((now, record.timestamp));
+                true
+            }
+        }
+    }
+}
+impl engine::App for PcapReaderApp {
+    fn has_pull(&self) -> bool {
+        true
+    }
+    fn pull(&self, app: &engine::AppState) {
+        if let Some(output) = app.output.get("output") {
+            let mut output = output.borrow_mut();
+            let mut state = self.state.borrow_mut();
+            let now = engine::now();
+            for _ in 0..engine::pull_npackets(&output) {
+                if let Some(limit) = self.conf.limit {
+                    if state.sent >= limit {
+                        break;
+                    }
+                }
+                let record = match state.next_record(self.conf.looping) {
+                    Some(record) => record,
+                    None => break,
+                };
+                if !state.due(&record, self.conf.realtime, now) {
+                    break;
+                }
+                // send the record on the next pull if the pool is exhausted
+                let mut p = match packet::try_from_data(packet::DEFAULT_POOL, &state.data) {
+                    Some(p) => p,
+                    None => break,
+                };
+                p.meta.timestamp = record.timestamp;
+                p.meta.flags = packet::META_TIMESTAMP;
+                if let Some(p) = link::transmit(&mut output, p) {
+                    // refused, send the record again on the next pull
+                    packet::free(p);
+                    break;
+                }
+                state.next = None;
+                state.sent += 1;
+            }
+        }
+    }
+}
+
+// PcapWriter app: write packets received on input to a pcap file
+//
+//   path: file to write to
+//   format: PcapFormat::Pcap (libpcap) or PcapFormat::Pcapng
+//   snaplen: maximum number of bytes written per packet
+//   timestamps: Timestamps::Engine (engine time of the breath the packet is
+//     written in) or Timestamps::Metadata (packet metadata, if valid, engine
+//     time otherwise)
+//   rotate_size: start a new file once the current file would exceed this
+//     number of bytes (if Some)
+//   rotate_interval: start a new file after this interval (if Some)
+//
+// When rotating, the n-th file after the first is named <path>.<n>.
+// Packets are freed after they have been written (like basic_apps::Sink).
+// After an I/O error (printed once), the app stops writing and counts the
+// packets it fails to write as errors in its report.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcapFormat {
+    Pcap,
+    Pcapng,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timestamps {
+    Engine,
+    Metadata,
+}
+
+#[derive(Clone, Debug)]
+pub struct PcapWriter {
+    pub path: String,
+    pub format: PcapFormat,
+    pub snaplen: usize,
+    pub timestamps: Timestamps,
+    pub rotate_size: Option<u64>,
+    pub rotate_interval: Option<Duration>,
+}
+impl engine::AppConfig for PcapWriter {
+    fn new(&self) -> Box<dyn engine::App> {
+        let app = PcapWriterApp::new(self)
+            .unwrap_or_else(|error| panic!("Failed to create {}: {}", self.path, error));
+        Box::new(app)
+    }
+}
+pub struct PcapWriterApp {
+    conf: PcapWriter,
+    state: RefCell<WriterState>,
+}
+impl PcapWriterApp {
+    fn new(conf: &PcapWriter) -> io::Result<PcapWriterApp> {
+        let now = engine::now();
+        let mut state = WriterState {
+            file: None,
+            nfiles: 0,
+            size: 0,
+            header_size: 0,
+            opened: now,
+            data: Vec::new(),
+            epoch: (SystemTime::now(), now),
+            errors: 0,
+        };
+        state.open(conf, now)?;
+        Ok(PcapWriterApp {
+            conf: conf.clone(),
+            state: RefCell::new(state),
+        })
+    }
+}
+struct WriterState {
+    file: Option<BufWriter<File>>, // None after an I/O error
+    nfiles: u64,                   // number of files opened
+    size: u64,                     // bytes written to the current file
+    header_size: u64,              // size of the file header
+    opened: Instant,               // time the current file was opened
+    data: Vec<u8>,                 // captured data of the current packet
+    epoch: (SystemTime, Instant),  // wall clock time at instant
+    errors: u64,                   // packets not written since last report
+}
+impl WriterState {
+    // Open the next file (at now) and write its header.
+    fn open(&mut self, conf: &PcapWriter, now: Instant) -> io::Result<()> {
+        let path = match self.nfiles {
+            0 => conf.path.clone(),
+            n => format!("{}.{}", conf.path, n),
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+        self.header_size = match conf.format {
+            PcapFormat::Pcap => {
+                pcap::write_file_header(&mut file, conf.snaplen as u32)?;
+                pcap::FILE_HEADER_SIZE as u64
+            }
+            PcapFormat::Pcapng => pcap::write_pcapng_header(&mut file, conf.snaplen as u32)? as u64,
+        };
+        self.size = self.header_size;
+        self.file = Some(file);
+        self.nfiles += 1;
+        self.opened = now;
+        Ok(())
+    }
+
+    // Return true if the current file should be rotated before writing a
+    // record of size bytes at now.
+    fn rotate(&self, conf: &PcapWriter, size: u64, now: Instant) -> bool {
+        let empty = self.size == self.header_size;
+        let full = match conf.rotate_size {
+            Some(limit) => self.size + size > limit,
+            None => false,
+        };
+        let expired = match conf.rotate_interval {
+            Some(interval) => now >= self.opened + interval,
+            None => false,
+        };
+        // NB: a file always holds at least one record
+        (full && !empty) || expired
+    }
+
+    // Wall clock time (nanoseconds since the UNIX epoch) at now.
+    fn wall_clock(&self, now: Instant) -> u64 {
+        let (wall, instant) = self.epoch;
+        let now = wall + now.saturating_duration_since(instant);
+        now.duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+
+    fn write(&mut self, conf: &PcapWriter, p: &packet::Packet, now: Instant) -> io::Result<()> {
+        let timestamp = match conf.timestamps {
+            Timestamps::Metadata if p.meta.flags & packet::META_TIMESTAMP != 0 => p.meta.timestamp,
+            _ => self.wall_clock(now),
+        };
+        pcap::copy_data(&mut self.data, p, conf.snaplen);
+        let size = match conf.format {
+            PcapFormat::Pcap => pcap::record_size(self.data.len()),
+            PcapFormat::Pcapng => pcap::pcapng_record_size(self.data.len()),
+        } as u64;
+        if self.rotate(conf, size, now) {
+            if let Some(mut file) = self.file.take() {
+                file.flush()?;
+            }
+            self.open(conf, now)?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        match conf.format {
+            PcapFormat::Pcap => {
+                pcap::write_record(file, timestamp, &self.data, p.total_length())?;
+            }
+            PcapFormat::Pcapng => {
+                pcap::write_pcapng_record(file, timestamp, &self.data, p.total_length())?;
+            }
+        }
+        self.size += size;
+        Ok(())
+    }
+}
+impl engine::App for PcapWriterApp {
+    fn has_push(&self) -> bool {
+        true
+    }
+    fn push(&self, app: &engine::AppState) {
+        if let Some(input) = app.input.get("input") {
+            let mut input = input.borrow_mut();
+            let mut state = self.state.borrow_mut();
+            let now = engine::now();
+            while !link::empty(&input) {
+                let p = link::receive(&mut input);
+                if state.file.is_none() {
+                    state.errors += 1;
+                } else if let Err(error) = state.write(&self.conf, &p, now) {
+                    // NB: stop writing, but keep consuming packets
+                    println!("PcapWriter failed to write {}: {}", self.conf.path, error);
+                    state.file = None;
+                    state.errors += 1;
+                }
+                packet::free(p);
+            }
+        }
+    }
+    fn has_report(&self) -> bool {
+        true
+    }
+    fn report(&self) {
+        let mut state = self.state.borrow_mut();
+        println!(
+            "  PcapWriter stats for {} since last report:",
+            self.conf.path
+        );
+        println!("     errors:\t{:10}", lib::comma_value(state.errors));
+        state.errors = 0;
+    }
+    fn has_stop(&self) -> bool {
+        true
+    }
+    fn stop(&self) {
+        if let Some(file) = &mut self.state.borrow_mut().file {
+            if let Err(error) = file.flush() {
+                println!("PcapWriter failed to write {}: {}", self.conf.path, error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::basic_apps;
+    use crate::config;
+
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn write_pcap(path: &str, npackets: u64) {
+        let mut file = File::create(path).unwrap();
+        pcap::write_file_header(&mut file, 65535).unwrap();
+        for n in 0..npackets {
+            let data = vec![n as u8; 60];
+            pcap::write_record(&mut file, n * 1_000_000_000, &data, 60).unwrap();
+        }
+    }
+
+    fn replay(name: &str, reader: PcapReader) -> u64 {
+        let mut c = config::new();
+        config::app(&mut c, name, &reader);
+        config::app(&mut c, "pcap_sink", &basic_apps::Sink {});
+        let spec = format!("{}.output -> pcap_sink.input", name);
+        config::link(&mut c, &spec);
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            done: Some(Box::new(|| true)),
+            report_links: true,
+            ..Default::default()
+        }));
+        let txpackets = engine::state().link_table[&spec].borrow().txpackets;
+        engine::configure(&config::new());
+        txpackets
+    }
+
+    #[test]
+    fn pcap_reader() {
+        let path = std::env::temp_dir().join(format!("rush-reader-{}.pcap", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        write_pcap(&path, 3);
+        let reader = PcapReader {
+            path: path.clone(),
+            looping: false,
+            limit: None,
+            realtime: false,
+        };
+        assert_eq!(replay("pcap_once", reader.clone()), 3);
+        let looping = PcapReader {
+            looping: true,
+            limit: Some(7),
+            ..reader.clone()
+        };
+        assert_eq!(replay("pcap_loop", looping), 7);
+        // packets are one second apart, only the first one is due
+        let realtime = PcapReader {
+            realtime: true,
+            ..reader
+        };
+        assert_eq!(replay("pcap_realtime", realtime), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Replay npackets from a pcap file through writer, return the sizes of the
+    // files written.
+    fn write(name: &str, npackets: u64, writer: PcapWriter) -> Vec<u64> {
+        let input = format!("{}.input.pcap", writer.path);
+        write_pcap(&input, npackets);
+        let mut c = config::new();
+        let reader = PcapReader {
+            path: input.clone(),
+            looping: false,
+            limit: None,
+            realtime: false,
+        };
+        config::app(&mut c, "pcap_source", &reader);
+        config::app(&mut c, name, &writer);
+        config::link(&mut c, &format!("pcap_source.output -> {}.input", name));
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            done: Some(Box::new(|| true)),
+            ..Default::default()
+        }));
+        engine::configure(&config::new());
+        std::fs::remove_file(&input).unwrap();
+        let mut sizes = Vec::new();
+        for n in 0.. {
+            let path = match n {
+                0 => writer.path.clone(),
+                n => format!("{}.{}", writer.path, n),
+            };
+            match std::fs::metadata(&path) {
+                Ok(metadata) => sizes.push(metadata.len()),
+                Err(_) => break,
+            }
+            if n == 0 && writer.format == PcapFormat::Pcap {
+                let mut file = BufReader::new(File::open(&path).unwrap());
+                let header = pcap::read_file_header(&mut file).unwrap();
+                let mut data = Vec::new();
+                let record = pcap::read_record(&mut file, &header, &mut data).unwrap();
+                assert_eq!(record.unwrap().timestamp, 0, "Timestamp not preserved");
+                assert_eq!(data.len(), writer.snaplen.min(60));
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+        sizes
+    }
+
+    #[test]
+    fn pcap_writer() {
+        let path = std::env::temp_dir().join(format!("rush-writer-{}.pcap", std::process::id()));
+        let writer = PcapWriter {
+            path: path.to_str().unwrap().to_string(),
+            format: PcapFormat::Pcap,
+            snaplen: 65535,
+            timestamps: Timestamps::Metadata,
+            rotate_size: None,
+            rotate_interval: None,
+        };
+        assert_eq!(write("pcap_writer", 3, writer.clone()), vec![24 + 3 * 76]);
+        let snapped = PcapWriter {
+            snaplen: 20,
+            ..writer.clone()
+        };
+        assert_eq!(write("pcap_snapped", 3, snapped), vec![24 + 3 * 36]);
+        let rotated = PcapWriter {
+            rotate_size: Some(24 + 2 * 76),
+            ..writer.clone()
+        };
+        let sizes = write("pcap_rotated", 5, rotated);
+        assert_eq!(sizes, vec![24 + 2 * 76, 24 + 2 * 76, 24 + 76]);
+        let pcapng = PcapWriter {
+            format: PcapFormat::Pcapng,
+            ..writer
+        };
+        assert_eq!(write("pcap_ng", 2, pcapng), vec![60 + 2 * (32 + 60)]);
+    }
+
+    #[test]
+    fn pcap_writer_error() {
+        let writer = PcapWriter {
+            path: "/dev/full".to_string(),
+            format: PcapFormat::Pcap,
+            snaplen: 65535,
+            timestamps: Timestamps::Engine,
+            rotate_size: None,
+            rotate_interval: None,
+        };
+        let app = PcapWriterApp::new(&writer).unwrap();
+        let input = Rc::new(RefCell::new(link::new()));
+        let state = engine::AppState {
+            app: Box::new(basic_apps::SinkApp {}),
+            conf: Box::new(writer),
+            input: HashMap::from([("input".to_string(), input.clone())]),
+            output: HashMap::new(),
+        };
+        // more than fits into the write buffer
+        for _ in 0..1000 {
+            let p = packet::from_data(packet::DEFAULT_POOL, &[0; 60]);
+            assert!(link::transmit(&mut input.borrow_mut(), p).is_none());
+        }
+        engine::App::push(&app, &state);
+        assert!(link::empty(&input.borrow()));
+        let errors = app.state.borrow().errors;
+        assert!(app.state.borrow().file.is_none(), "Still writing");
+        assert!(errors > 0 && errors < 1000, "Errors: {}", errors);
+    }
+}
@@ -0,0 +1,488 @@
+use super::engine;
+use super::lib;
+use super::link;
+use super::packet;
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+
+// RawSocket app: send and receive packets on a kernel network interface
+//
+//   ifname: name of the interface to bind to (e.g., "eth0" or "veth0")
+//
+// Packets received on the interface are sent on output, packets received on
+// input are transmitted on the interface. The app uses an AF_PACKET socket
+// with memory mapped TPACKET_V3 rings, so packets are exchanged with the
+// kernel in batches without a system call per packet.
+//
+// Received packets carry their kernel timestamp and (stripped) VLAN tag in
+// their metadata. Packets that exceed TX_FRAME_SIZE are dropped on transmit.
+
+#[derive(Clone, Debug)]
+pub struct RawSocket {
+    pub ifname: String,
+}
+impl engine::AppConfig for RawSocket {
+    fn new(&self) -> Box<dyn engine::App> {
+        let socket = open(&self.ifname)
+            .unwrap_or_else(|error| panic!("Failed to open {}: {}", self.ifname, error));
+        Box::new(RawSocketApp {
+            ifname: self.ifname.clone(),
+            socket: RefCell::new(socket),
+            rx_packets: RefCell::new(Vec::with_capacity(engine::PULL_NPACKETS)),
+        })
+    }
+}
+pub struct RawSocketApp {
+    ifname: String,
+    socket: RefCell<Socket>,
+    rx_packets: RefCell<Vec<packet::PacketBox>>,
+}
+impl engine::App for RawSocketApp {
+    fn has_pull(&self) -> bool {
+        true
+    }
+    fn pull(&self, app: &engine::AppState) {
+        if let Some(output) = app.output.get("output") {
+            let mut output = output.borrow_mut();
+            let mut packets = self.rx_packets.borrow_mut();
+            // leave packets in the receive ring if there is no room downstream
+            let npackets = engine::pull_npackets(&output);
+            self.socket.borrow_mut().receive(&mut packets, npackets);
+            link::transmit_batch(&mut output, &mut packets);
+        }
+    }
+    fn has_push(&self) -> bool {
+        true
+    }
+    fn push(&self, app: &engine::AppState) {
+        if let Some(input) = app.input.get("input") {
+            let mut input = input.borrow_mut();
+            self.socket.borrow_mut().transmit(&mut input);
+        }
+    }
+    fn has_report(&self) -> bool {
+        true
+    }
+    fn report(&self) {
+        let mut socket = self.socket.borrow_mut();
+        let (packets, drops) = socket.kernel_stats();
+        println!("  Socket stats for {} since last report:", self.ifname);
+        println!("     rxpackets:\t{:10}", lib::comma_value(packets));
+        println!(
+            "     rxdrops:\t{:10}",
+            lib::comma_value(drops + socket.rxnobufs)
+        );
+        println!("     txpackets:\t{:10}", lib::comma_value(socket.txpackets));
+        println!("     txdrops:\t{:10}", lib::comma_value(socket.txdrops));
+        socket.rxnobufs = 0;
+        socket.txpackets = 0;
+        socket.txdrops = 0;
+    }
+}
+
+// Ring geometry. The RX ring is made of RX_BLOCK_NR blocks that the kernel
+// fills with variable sized frames and hands to us as a whole (once full, or
+// after RX_BLOCK_TIMEOUT milliseconds). The TX ring is made of fixed size
+// frames.
+const RX_BLOCK_SIZE: u32 = 1 << 20;
+const RX_BLOCK_NR: u32 = 8;
+const RX_FRAME_SIZE: u32 = 1 << 11;
+const RX_BLOCK_TIMEOUT: u32 = 1;
+const TX_BLOCK_SIZE: u32 = 1 << 16;
+const TX_BLOCK_NR: u32 = 16;
+const TX_FRAME_SIZE: u32 = 1 << 11;
+const TX_FRAME_NR: u32 = TX_BLOCK_SIZE / TX_FRAME_SIZE * TX_BLOCK_NR;
+
+// Definitions from <linux/if_packet.h> (not provided by the libc crate)
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_STATISTICS: libc::c_int = 6;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1 << 0;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1 << 0;
+const TP_STATUS_WRONG_FORMAT: u32 = 1 << 2;
+const PACKET_OUTGOING: u8 = 4;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+#[repr(C)]
+struct TpacketStatsV3 {
+    tp_packets: u32,
+    tp_drops: u32,
+    tp_freeze_q_cnt: u32,
+}
+
+// Block descriptor (struct tpacket_block_desc with struct tpacket_hdr_v1)
+#[repr(C)]
+struct TpacketBlockDesc {
+    version: u32,
+    offset_to_priv: u32,
+    block_status: u32,
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+    blk_len: u32,
+    seq_num: u64,
+    ts_first_pkt: [u32; 2],
+    ts_last_pkt: [u32; 2],
+}
+
+// Frame header (struct tpacket3_hdr)
+#[repr(C)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_rxhash: u32,
+    tp_vlan_tci: u32,
+    tp_vlan_tpid: u16,
+    tp_padding: [u8; 10],
+}
+
+// Frame headers are followed by a struct sockaddr_ll (RX), TX frame data
+// starts right after the header.
+const TPACKET3_HDRLEN: usize = mem::size_of::<Tpacket3Hdr>();
+
+struct Socket {
+    fd: libc::c_int,
+    ring: *mut u8,
+    ring_size: usize,
+    rx_block: u32,    // current RX block
+    rx_packet: u32,   // next packet in current RX block
+    rx_offset: usize, // offset of next packet in current RX block
+    tx_frame: u32,    // next TX frame
+    rxnobufs: u64,    // packets dropped on receive, pool exhausted (since last report)
+    txpackets: u64,   // packets transmitted (since last report)
+    txdrops: u64,     // packets dropped on transmit (since last report)
+}
+
+// Open an AF_PACKET socket bound to ifname and map its RX and TX rings.
+fn open(ifname: &str) -> io::Result<Socket> {
+    let name = CString::new(ifname).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // NB: the socket does not receive packets until it is bound (protocol 0),
+    // so no packets are queued before the rings are set up.
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut socket = Socket {
+        fd,
+        ring: ptr::null_mut(),
+        ring_size: 0,
+        rx_block: 0,
+        rx_packet: 0,
+        rx_offset: 0,
+        tx_frame: 0,
+        rxnobufs: 0,
+        txpackets: 0,
+        txdrops: 0,
+    };
+    setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+    let rx = TpacketReq3 {
+        tp_block_size: RX_BLOCK_SIZE,
+        tp_block_nr: RX_BLOCK_NR,
+        tp_frame_size: RX_FRAME_SIZE,
+        tp_frame_nr: RX_BLOCK_SIZE / RX_FRAME_SIZE * RX_BLOCK_NR,
+        tp_retire_blk_tov: RX_BLOCK_TIMEOUT,
+        tp_sizeof_priv: 0,
+        tp_feature_req_word: 0,
+    };
+    setsockopt(fd, PACKET_RX_RING, &rx)?;
+    let tx = TpacketReq3 {
+        tp_block_size: TX_BLOCK_SIZE,
+        tp_block_nr: TX_BLOCK_NR,
+        tp_frame_size: TX_FRAME_SIZE,
+        tp_frame_nr: TX_FRAME_NR,
+        tp_retire_blk_tov: 0,
+        tp_sizeof_priv: 0,
+        tp_feature_req_word: 0,
+    };
+    setsockopt(fd, PACKET_TX_RING, &tx)?;
+    // the RX ring is mapped first, followed by the TX ring
+    let ring_size = (RX_BLOCK_SIZE * RX_BLOCK_NR + TX_BLOCK_SIZE * TX_BLOCK_NR) as usize;
+    let ring = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            ring_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ring == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    socket.ring = ring as *mut u8;
+    socket.ring_size = ring_size;
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    addr.sll_ifindex = ifindex as i32;
+    let res = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as u32,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn setsockopt<T>(fd: libc::c_int, option: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as u32,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl Socket {
+    fn rx_block(&self, block: u32) -> *mut TpacketBlockDesc {
+        unsafe { self.ring.add((block * RX_BLOCK_SIZE) as usize) as *mut TpacketBlockDesc }
+    }
+
+    fn tx_frame(&self, frame: u32) -> *mut Tpacket3Hdr {
+        let offset = RX_BLOCK_SIZE * RX_BLOCK_NR + frame * TX_FRAME_SIZE;
+        unsafe { self.ring.add(offset as usize) as *mut Tpacket3Hdr }
+    }
+
+    // Copy up to npackets packets from the RX ring to packets. Blocks are
+    // returned to the kernel once all of their packets have been received.
+    fn receive(&mut self, packets: &mut Vec<packet::PacketBox>, mut npackets: usize) {
+        while npackets > 0 {
+            let block = self.rx_block(self.rx_block);
+            let status = unsafe { ptr::read_volatile(&(*block).block_status) };
+            if status & TP_STATUS_USER == 0 {
+                break;
+            }
+            fence(Ordering::Acquire);
+            let num_pkts = unsafe { (*block).num_pkts };
+            if self.rx_packet == 0 {
+                self.rx_offset = unsafe { (*block).offset_to_first_pkt } as usize;
+            }
+            while self.rx_packet < num_pkts && npackets > 0 {
+                let frame = unsafe { (block as *mut u8).add(self.rx_offset) };
+                let hdr = unsafe { &*(frame as *const Tpacket3Hdr) };
+                let addr = unsafe { &*(frame.add(TPACKET3_HDRLEN) as *const libc::sockaddr_ll) };
+                // NB: AF_PACKET sockets also see the packets we transmit.
+                if addr.sll_pkttype != PACKET_OUTGOING {
+                    let data = unsafe {
+                        std::slice::from_raw_parts(
+                            frame.add(hdr.tp_mac as usize),
+                            hdr.tp_snaplen as usize,
+                        )
+                    };
+                    let mut p = match packet::try_from_data(packet::DEFAULT_POOL, data) {
+                        Some(p) => p,
+                        None => {
+                            self.rxnobufs += 1;
+                            self.rx_offset += hdr.tp_next_offset as usize;
+                            self.rx_packet += 1;
+                            continue;
+                        }
+                    };
+                    p.meta.timestamp = hdr.tp_sec as u64 * 1_000_000_000 + hdr.tp_nsec as u64;
+                    p.meta.flags = packet::META_TIMESTAMP;
+                    if hdr.tp_status & TP_STATUS_VLAN_VALID != 0 {
+                        p.meta.vlan = hdr.tp_vlan_tci as u16;
+                        p.meta.flags |= packet::META_VLAN;
+                    }
+                    packets.push(p);
+                    npackets -= 1;
+                }
+                self.rx_offset += hdr.tp_next_offset as usize;
+                self.rx_packet += 1;
+            }
+            if self.rx_packet < num_pkts {
+                break;
+            }
+            // return the block to the kernel
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(&mut (*block).block_status, TP_STATUS_KERNEL) };
+            self.rx_block = (self.rx_block + 1) % RX_BLOCK_NR;
+            self.rx_packet = 0;
+        }
+    }
+
+    // Copy packets from input to free TX frames and ask the kernel to send
+    // them. Packets are left on input when the TX ring is full.
+    fn transmit(&mut self, input: &mut link::Link) {
+        let mut queued = 0;
+        while !link::empty(input) {
+            let hdr = self.tx_frame(self.tx_frame);
+            let status = unsafe { ptr::read_volatile(&(*hdr).tp_status) };
+            if status != TP_STATUS_AVAILABLE && status != TP_STATUS_WRONG_FORMAT {
+                break;
+            }
+            fence(Ordering::Acquire);
+            let p = link::receive(input);
+            let length = p.total_length();
+            if length > TX_FRAME_SIZE as usize - TPACKET3_HDRLEN {
+                self.txdrops += 1;
+                continue;
+            }
+            let mut offset = TPACKET3_HDRLEN;
+            for seg in p.segments() {
+                unsafe {
+                    let dst = (hdr as *mut u8).add(offset);
+                    ptr::copy_nonoverlapping(seg.data.as_ptr(), dst, seg.length as usize);
+                }
+                offset += seg.length as usize;
+            }
+            unsafe {
+                (*hdr).tp_next_offset = 0;
+                (*hdr).tp_len = length as u32;
+                (*hdr).tp_snaplen = length as u32;
+            }
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(&mut (*hdr).tp_status, TP_STATUS_SEND_REQUEST) };
+            self.tx_frame = (self.tx_frame + 1) % TX_FRAME_NR;
+            queued += 1;
+        }
+        if queued > 0 {
+            // NB: errors (e.g., ENOBUFS) leave frames in the ring, the kernel
+            // picks them up with the next send.
+            unsafe { libc::send(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT) };
+            self.txpackets += queued;
+        }
+    }
+
+    // Return the number of packets received and dropped by the kernel since
+    // the last call.
+    fn kernel_stats(&self) -> (u64, u64) {
+        let mut stats: TpacketStatsV3 = unsafe { mem::zeroed() };
+        let mut size = mem::size_of::<TpacketStatsV3>() as u32;
+        let res = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                PACKET_STATISTICS,
+                &mut stats as *mut TpacketStatsV3 as *mut libc::c_void,
+                &mut size,
+            )
+        };
+        match res {
+            0 => (stats.tp_packets as u64, stats.tp_drops as u64),
+            _ => (0, 0),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                libc::munmap(self.ring as *mut libc::c_void, self.ring_size);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::basic_apps;
+    use crate::config;
+
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    #[test]
+    fn raw_socket() {
+        if unsafe { libc::getuid() } != 0 {
+            println!("Skipping test (need to be root)");
+            return;
+        }
+        // NB: network namespaces are per thread, the veth pair (and the
+        // sockets bound to it) only exist in this test’s namespace.
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            println!("Skipping test (failed to create network namespace)");
+            return;
+        }
+        if !(ip(&[
+            "link", "add", "veth0", "type", "veth", "peer", "name", "veth1",
+        ]) && ip(&["link", "set", "veth0", "up"])
+            && ip(&["link", "set", "veth1", "up"]))
+        {
+            println!("Skipping test (failed to create veth pair)");
+            return;
+        }
+        let mut c = config::new();
+        config::app(&mut c, "veth_source", &basic_apps::Source { size: 60 });
+        config::app(
+            &mut c,
+            "veth0",
+            &RawSocket {
+                ifname: "veth0".to_string(),
+            },
+        );
+        config::app(
+            &mut c,
+            "veth1",
+            &RawSocket {
+                ifname: "veth1".to_string(),
+            },
+        );
+        config::app(&mut c, "veth_sink", &basic_apps::Sink {});
+        config::link(&mut c, "veth_source.output -> veth0.input");
+        config::link(&mut c, "veth1.output -> veth_sink.input");
+        engine::configure(&c);
+        engine::main(Some(engine::Options {
+            duration: Some(Duration::from_millis(100)),
+            report_links: true,
+            report_apps: true,
+            ..Default::default()
+        }));
+        let rxpackets = engine::state().link_table["veth1.output -> veth_sink.input"]
+            .borrow()
+            .txpackets;
+        engine::configure(&config::new());
+        assert!(rxpackets > 0, "No packets received");
+    }
+}
@@ -0,0 +1,231 @@
+// LINK TAPS
+//
+// This module implements taps that capture copies of the packets transmitted
+// on a link (e.g., for debugging). Taps are attached to links at runtime (see
+// engine::tap), links without a tap are not affected.
+//
+//   Tap - packet capture attached to a link
+//   Capture - captured packet (timestamp, original length, captured data)
+//   pcap(path:&str) -> Tap - create a tap that writes to a pcap file
+//   ring(npackets) -> Tap - create a tap that keeps the last npackets captures
+//   Tap.filter(Fn(&Packet)->bool) -> Tap - only capture matching packets
+//   Tap.snaplen(usize) -> Tap - capture at most snaplen bytes per packet
+//   Tap.captured() -> u64 - number of packets captured
+//   Tap.failed() -> u64 - number of packets that failed to be captured (write
+//     errors)
+//   Tap.captures() -> &VecDeque<Capture> - captured packets (ring taps)
+//   Tap.capture(&Packet) - capture a packet (called by link::transmit)
+
+use super::packet;
+use super::pcap;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Default number of bytes captured per packet.
+const DEFAULT_SNAPLEN: usize = 65535;
+
+// Capture filter: predicate on packets
+type Filter = dyn Fn(&packet::Packet) -> bool;
+
+pub struct Tap {
+    target: Target,
+    filter: Option<Box<Filter>>,
+    snaplen: usize,
+    captured: u64,
+    failed: u64,
+}
+
+enum Target {
+    Pcap(BufWriter<File>),          // file
+    Ring(VecDeque<Capture>, usize), // captures, maximum number of captures
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    pub timestamp: u64, // capture time (nanoseconds since the UNIX epoch)
+    pub length: usize,  // original packet length
+    pub data: Vec<u8>,  // captured data (at most snaplen bytes)
+}
+
+// Create a tap that writes captured packets to the pcap file at path.
+// NB: the file header is written right away, so that the file is valid even if
+// no packets are captured.
+pub fn pcap(path: &str) -> io::Result<Tap> {
+    let mut file = BufWriter::new(File::create(path)?);
+    pcap::write_file_header(&mut file, DEFAULT_SNAPLEN as u32)?;
+    file.flush()?;
+    Ok(new(Target::Pcap(file)))
+}
+
+// Create a tap that keeps the last npackets captured packets in memory.
+pub fn ring(npackets: usize) -> Tap {
+    assert!(npackets > 0, "Tap ring must hold at least one packet");
+    new(Target::Ring(VecDeque::with_capacity(npackets), npackets))
+}
+
+fn new(target: Target) -> Tap {
+    Tap {
+        target,
+        filter: None,
+        snaplen: DEFAULT_SNAPLEN,
+        captured: 0,
+        failed: 0,
+    }
+}
+
+impl Tap {
+    // Only capture packets for which filter returns true.
+    pub fn filter(mut self, filter: impl Fn(&packet::Packet) -> bool + 'static) -> Tap {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    // Capture at most snaplen bytes of each packet.
+    pub fn snaplen(mut self, snaplen: usize) -> Tap {
+        self.snaplen = snaplen;
+        if let Target::Pcap(file) = &mut self.target {
+            if let Err(error) = rewrite_header(file, snaplen) {
+                println!("Tap failed to write pcap header: {}", error);
+            }
+        }
+        self
+    }
+
+    pub fn captured(&self) -> u64 {
+        self.captured
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    // Return the captured packets of a ring tap (oldest first).
+    pub fn captures(&self) -> &VecDeque<Capture> {
+        match &self.target {
+            Target::Ring(captures, _) => captures,
+            Target::Pcap(..) => panic!("Not a ring tap"),
+        }
+    }
+
+    // Capture a copy of p (unless it is rejected by the filter).
+    // NB: never inlined, to keep the tap out of link::transmit’s fast path.
+    #[inline(never)]
+    pub fn capture(&mut self, p: &packet::Packet) {
+        if let Some(filter) = &self.filter {
+            if !filter(p) {
+                return;
+            }
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        match &mut self.target {
+            Target::Pcap(file) => {
+                let mut data = Vec::new();
+                pcap::copy_data(&mut data, p, self.snaplen);
+                // NB: taps are a debugging aid, failing to capture must not
+                // interrupt packet processing (failures are reported when the
+                // tap is detached).
+                if pcap::write_record(file, timestamp, &data, p.total_length()).is_err() {
+                    self.failed += 1;
+                    return;
+                }
+            }
+            Target::Ring(captures, npackets) => {
+                // reuse the oldest capture’s buffer once the ring is full
+                let mut capture = if captures.len() == *npackets {
+                    captures.pop_front().unwrap()
+                } else {
+                    Default::default()
+                };
+                capture.timestamp = timestamp;
+                capture.length = p.total_length();
+                pcap::copy_data(&mut capture.data, p, self.snaplen);
+                captures.push_back(capture);
+            }
+        }
+        self.captured += 1;
+    }
+}
+
+// Overwrite the pcap file header (with snaplen), and continue writing at the
+// end of the file.
+fn rewrite_header(file: &mut BufWriter<File>, snaplen: usize) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    pcap::write_file_header(file, snaplen as u32)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+// Flush buffered captures when the tap is detached, and report failed
+// captures.
+impl Drop for Tap {
+    fn drop(&mut self) {
+        if let Target::Pcap(file) = &mut self.target {
+            if let Err(error) = file.flush() {
+                println!("Tap failed to flush captures: {}", error);
+            }
+        }
+        if self.failed > 0 {
+            println!("Tap failed to write {} captures", self.failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+
+    #[test]
+    fn ring_tap() {
+        let mut tap = ring(2).snaplen(4).filter(|p| p.length > 1);
+        for length in 0..5 {
+            let mut p = packet::allocate();
+            p.length = length;
+            p.data[0] = length as u8;
+            tap.capture(&p);
+        }
+        assert_eq!(tap.captured(), 3);
+        let captures = tap.captures();
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[0].length, 3);
+        assert_eq!((captures[0].data.len(), captures[0].data[0]), (3, 3));
+        assert_eq!(captures[1].length, 4);
+        assert_eq!(captures[1].data.len(), 4);
+    }
+
+    #[test]
+    fn pcap_tap() {
+        let path = std::env::temp_dir().join(format!("rush-tap-{}.pcap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut tap = pcap(path).unwrap().snaplen(10);
+        // the file header is written even if nothing is captured
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 24);
+        let mut p = packet::allocate();
+        p.length = 60;
+        tap.capture(&p);
+        tap.capture(&p);
+        drop(tap);
+        let contents = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(contents.len(), 24 + 2 * (16 + 10));
+        assert_eq!(contents[16..20], 10u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn failed_captures() {
+        let file = BufWriter::new(File::create("/dev/full").unwrap());
+        let mut tap = new(Target::Pcap(file));
+        let mut p = packet::allocate();
+        p.length = 60;
+        // more than fits into the write buffer
+        for _ in 0..1000 {
+            tap.capture(&p);
+        }
+        assert!(tap.failed() > 0, "No failed captures");
+        assert_eq!(tap.captured() + tap.failed(), 1000);
+    }
+}
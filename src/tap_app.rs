@@ -0,0 +1,303 @@
+use super::engine;
+use super::lib;
+use super::link;
+use super::packet;
+
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+// Tap app: exchange packets with the kernel via a Linux TAP interface
+//
+//   name: name of the interface to create or attach to (the kernel picks a
+//     name, e.g. "tap0", if empty)
+//   mtu: MTU to set on the interface (left unchanged if None)
+//   persist: make the interface persistent, i.e. keep it after the app is
+//     stopped (existing persistent interfaces are kept regardless)
+//
+// Frames read from the interface are sent on output, packets received on
+// input are written to the interface. The interface is not brought up by the
+// app (e.g., use "ip link set <name> up").
+
+#[derive(Clone, Debug)]
+pub struct Tap {
+    pub name: String,
+    pub mtu: Option<u16>,
+    pub persist: bool,
+}
+impl engine::AppConfig for Tap {
+    fn new(&self) -> Box<dyn engine::App> {
+        let (file, name) = open(self)
+            .unwrap_or_else(|error| panic!("Failed to open TAP {}: {}", self.name, error));
+        Box::new(TapApp {
+            name,
+            file: RefCell::new(file),
+            buffer: RefCell::new(vec![0; MAX_FRAME_SIZE]),
+            rxdrops: Cell::new(0),
+            rxerrors: Cell::new(0),
+            txerrors: Cell::new(0),
+        })
+    }
+}
+pub struct TapApp {
+    name: String,             // interface name
+    file: RefCell<File>,      // /dev/net/tun file attached to the interface
+    buffer: RefCell<Vec<u8>>, // receive buffer
+    rxdrops: Cell<u64>,       // frames dropped, pool exhausted (since last report)
+    rxerrors: Cell<u64>,      // failed reads (since last report)
+    txerrors: Cell<u64>,      // packets dropped, write failed (since last report)
+}
+impl engine::App for TapApp {
+    fn has_pull(&self) -> bool {
+        true
+    }
+    fn pull(&self, app: &engine::AppState) {
+        if let Some(output) = app.output.get("output") {
+            let mut output = output.borrow_mut();
+            let mut file = self.file.borrow_mut();
+            let mut buffer = self.buffer.borrow_mut();
+            for _ in 0..engine::pull_npackets(&output) {
+                match file.read(&mut buffer) {
+                    Ok(length) => {
+                        let p = match packet::try_from_data(packet::DEFAULT_POOL, &buffer[..length])
+                        {
+                            Some(p) => p,
+                            None => {
+                                self.rxdrops.set(self.rxdrops.get() + 1);
+                                continue;
+                            }
+                        };
+                        if let Some(p) = link::transmit(&mut output, p) {
+                            // NB: the frame has been read, it can not be refused
+                            output.txdrop += 1;
+                            packet::free(p);
+                        }
+                    }
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        self.rxerrors.set(self.rxerrors.get() + 1);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    fn has_push(&self) -> bool {
+        true
+    }
+    fn push(&self, app: &engine::AppState) {
+        if let Some(input) = app.input.get("input") {
+            let mut input = input.borrow_mut();
+            let mut file = self.file.borrow_mut();
+            while !link::empty(&input) {
+                let p = link::front(&input);
+                let segments: Vec<_> = p
+                    .segments()
+                    .map(|seg| IoSlice::new(&seg.data[..seg.length as usize]))
+                    .collect();
+                match file.write_vectored(&segments) {
+                    Ok(_) => (),
+                    // leave the packet on input until the kernel accepts it
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => self.txerrors.set(self.txerrors.get() + 1),
+                }
+                link::receive(&mut input);
+            }
+        }
+    }
+    fn has_report(&self) -> bool {
+        true
+    }
+    fn report(&self) {
+        println!("  TAP stats for {} since last report:", self.name);
+        println!(
+            "     rxdrops:\t{:10}",
+            lib::comma_value(self.rxdrops.replace(0))
+        );
+        println!(
+            "     rxerrors:\t{:10}",
+            lib::comma_value(self.rxerrors.replace(0))
+        );
+        println!(
+            "     txerrors:\t{:10}",
+            lib::comma_value(self.txerrors.replace(0))
+        );
+    }
+}
+
+// Largest frame read from the interface.
+const MAX_FRAME_SIZE: usize = 65535;
+
+// Definitions from <linux/if_tun.h> (not provided by the libc crate)
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETPERSIST: libc::c_ulong = 0x4004_54cb;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const SIOCSIFMTU: libc::c_ulong = 0x8922;
+
+// struct ifreq (with the ifr_flags and ifr_mtu members of its union)
+#[repr(C)]
+struct IfReq {
+    ifr_name: [u8; libc::IFNAMSIZ],
+    ifr_ifru: IfReqData,
+}
+#[repr(C)]
+#[derive(Clone, Copy)]
+union IfReqData {
+    ifr_flags: libc::c_short,
+    ifr_mtu: libc::c_int,
+    ifr_pad: [u8; 24],
+}
+
+fn ifreq(name: &str) -> io::Result<IfReq> {
+    if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let mut req: IfReq = unsafe { mem::zeroed() };
+    req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(req)
+}
+
+fn ioctl(fd: libc::c_int, request: libc::c_ulong, arg: usize) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, request, arg) } {
+        res if res < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// Create (or attach to) the TAP interface described by conf, return the
+// non-blocking /dev/net/tun file attached to it and its name.
+fn open(conf: &Tap) -> io::Result<(File, String)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/net/tun")?;
+    let mut req = ifreq(&conf.name)?;
+    req.ifr_ifru.ifr_flags = IFF_TAP | IFF_NO_PI;
+    ioctl(file.as_raw_fd(), TUNSETIFF, &mut req as *mut IfReq as usize)?;
+    let length = req
+        .ifr_name
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(libc::IFNAMSIZ);
+    let name = String::from_utf8_lossy(&req.ifr_name[..length]).into_owned();
+    // NB: interfaces created by TUNSETIFF are not persistent, and we never
+    // clear the persistence of an existing interface.
+    if conf.persist {
+        ioctl(file.as_raw_fd(), TUNSETPERSIST, 1)?;
+    }
+    if let Some(mtu) = conf.mtu {
+        // NB: the MTU is set via an (arbitrary) socket, not the TAP file.
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut req = ifreq(&name)?;
+        req.ifr_ifru.ifr_mtu = mtu as libc::c_int;
+        let res = ioctl(socket, SIOCSIFMTU, &mut req as *mut IfReq as usize);
+        unsafe { libc::close(socket) };
+        res?;
+    }
+    Ok((file, name))
+}
+
+#[cfg(test)]
+mod selftest {
+    use super::*;
+    use crate::basic_apps;
+    use crate::config;
+    use crate::pcap;
+    use crate::pcap_app;
+    use crate::tap;
+
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn ip(args: &[&str]) -> bool {
+        Command::new("ip")
+            .args(args)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    // ARP request for 10.0.0.1 from 02:00:00:00:00:01 (10.0.0.2)
+    const ARP_REQUEST: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 10, 0, 0, 2,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 10, 0, 0, 1,
+    ];
+
+    #[test]
+    fn tap_arp() {
+        if unsafe { libc::getuid() } != 0 {
+            println!("Skipping test (need to be root)");
+            return;
+        }
+        // NB: network namespaces are per thread, the TAP interface only
+        // exists in this test’s namespace (and is not visible in /sys).
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+            println!("Skipping test (failed to create network namespace)");
+            return;
+        }
+        // attach to an existing (persistent) interface that is up, so that
+        // the kernel answers our first request
+        if !(ip(&["tuntap", "add", "dev", "rushtap0", "mode", "tap"])
+            && ip(&["addr", "add", "10.0.0.1/24", "dev", "rushtap0"])
+            && ip(&["link", "set", "rushtap0", "up"]))
+        {
+            println!("Skipping test (failed to create TAP interface)");
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("rush-tap-arp-{}.pcap", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut file = File::create(&path).unwrap();
+        pcap::write_file_header(&mut file, 65535).unwrap();
+        pcap::write_record(&mut file, 0, &ARP_REQUEST, ARP_REQUEST.len()).unwrap();
+        drop(file);
+        let mut c = config::new();
+        let reader = pcap_app::PcapReader {
+            path: path.clone(),
+            looping: false,
+            limit: None,
+            realtime: false,
+        };
+        config::app(&mut c, "arp_request", &reader);
+        let conf = Tap {
+            name: "rushtap0".to_string(),
+            mtu: Some(9000),
+            persist: false,
+        };
+        config::app(&mut c, "tap", &conf);
+        config::app(&mut c, "tap_sink", &basic_apps::Sink {});
+        config::link(&mut c, "arp_request.output -> tap.input");
+        config::link(&mut c, "tap.output -> tap_sink.input");
+        engine::configure(&c);
+        let show = Command::new("ip")
+            .args(["link", "show", "rushtap0"])
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&show.stdout).contains("mtu 9000"));
+        engine::tap("tap.output -> tap_sink.input", tap::ring(64));
+        engine::main(Some(engine::Options {
+            duration: Some(Duration::from_millis(100)),
+            ..Default::default()
+        }));
+        let tap = engine::untap("tap.output -> tap_sink.input").unwrap();
+        engine::configure(&config::new());
+        std::fs::remove_file(&path).unwrap();
+        // look for the kernel’s ARP reply
+        let reply = tap.captures().iter().any(|capture| {
+            capture.data.len() >= 42
+                && capture.data[12..14] == [0x08, 0x06]
+                && capture.data[21] == 2
+        });
+        assert!(reply, "No ARP reply");
+        // the existing interface is still persistent, and kept once detached
+        assert!(ip(&["link", "show", "rushtap0"]));
+        assert!(ip(&["tuntap", "del", "dev", "rushtap0", "mode", "tap"]));
+    }
+}